@@ -0,0 +1,60 @@
+//! Using the store from synchronous code, with the server on its own thread.
+//!
+//! Run with `cargo run --example blocking --features blocking`.
+
+use std::thread;
+
+use anyhow::{ensure, Result};
+use tokio::sync::oneshot;
+
+use kv_store::client::blocking::BlockingClient;
+use kv_store::{MiniDatabase, StorageBackend};
+
+fn main() -> Result<()> {
+    // The server still needs a runtime; the client below brings its own
+    let server_runtime = tokio::runtime::Runtime::new()?;
+    let server = server_runtime.block_on(
+        MiniDatabase::new(String::new())
+            .with_backend(StorageBackend::Memory)
+            .bind("127.0.0.1:0"),
+    )?;
+    let addr = server.local_addr().to_string();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let serving = server_runtime.spawn(server.serve_with_shutdown(shutdown_rx));
+
+    let client = BlockingClient::new(&addr)?;
+    client.set("greeting", "héllo")?;
+    ensure!(client.get("greeting")?.as_deref() == Some("héllo"), "read back the wrong value");
+    ensure!(client.strlen("greeting")? == 6, "strlen counts bytes");
+    for _ in 0..10 {
+        client.incr("counter")?;
+    }
+    ensure!(client.get("counter")?.as_deref() == Some("10"), "lost an increment");
+    // Any async method without a wrapper can still be run to completion
+    let (_, deleted) = client.run(|c| c.delete_detailed("greeting"))?;
+    ensure!(deleted, "the key was not deleted");
+    println!("{} keys after the blocking calls", client.len()?);
+
+    // Clients on other threads each get their own connection and runtime
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            let addr = addr.clone();
+            thread::spawn(move || -> Result<()> {
+                let client = BlockingClient::new(&addr)?;
+                client.rpush("log", &[&format!("worker {}", worker)])?;
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().expect("worker panicked")?;
+    }
+    ensure!(client.llen("log")? == 4, "a worker's push is missing");
+    println!("4 threads pushed to the log");
+
+    // Shuts the client's runtime down and closes its connection
+    drop(client);
+    let _ = shutdown_tx.send(());
+    server_runtime.block_on(serving)??;
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Following every change to an in-process server, e.g. to invalidate a cache.
+//!
+//! Run with `cargo run --example mutations`.
+
+use std::collections::HashMap;
+
+use anyhow::{ensure, Result};
+use tokio::sync::oneshot;
+
+use kv_store::client::DatabaseClient;
+use kv_store::{MiniDatabase, MutationEvent, StorageBackend};
+
+const WRITERS: usize = 4;
+const KEYS_PER_WRITER: usize = 25;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let db = MiniDatabase::new(String::new()).with_backend(StorageBackend::Memory);
+    // Subscribed before serving, so no write is missed
+    let mut events = db.subscribe_mutations();
+    let server = db.bind("127.0.0.1:0").await?;
+    let addr = server.local_addr().to_string();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let serving = tokio::spawn(server.serve_with_shutdown(shutdown_rx));
+
+    let mut writers = Vec::new();
+    for writer in 0..WRITERS {
+        let client = DatabaseClient::new(&addr).await?;
+        writers.push(tokio::spawn(async move {
+            for i in 0..KEYS_PER_WRITER {
+                client.set(&format!("writer{}:key{}", writer, i), &i.to_string()).await?;
+            }
+            // Deleting a key nobody set changes nothing, so it sends no event
+            client.delete("missing").await?;
+            client.delete(&format!("writer{}:key0", writer)).await?;
+            Ok::<_, anyhow::Error>(())
+        }));
+    }
+    for writer in writers {
+        writer.await??;
+    }
+    DatabaseClient::new(&addr).await?.clear().await?;
+
+    // Mirror the store from the events alone
+    let mut mirror = HashMap::new();
+    let (mut sets, mut deletes) = (0, 0);
+    loop {
+        match events.recv().await? {
+            MutationEvent::Set { key, value, .. } => {
+                sets += 1;
+                mirror.insert(key, value);
+            }
+            MutationEvent::Delete { key, .. } => {
+                deletes += 1;
+                mirror.remove(&key);
+            }
+            MutationEvent::Clear { db } => {
+                println!("{} sets and {} deletes before database {} was cleared", sets, deletes, db);
+                println!("the mirror held {} keys", mirror.len());
+                break;
+            }
+        }
+    }
+    ensure!(sets == WRITERS * KEYS_PER_WRITER, "expected {} sets", WRITERS * KEYS_PER_WRITER);
+    ensure!(deletes == WRITERS, "expected {} deletes", WRITERS);
+    ensure!(mirror.len() == WRITERS * (KEYS_PER_WRITER - 1), "the mirror is out of step");
+
+    let _ = shutdown_tx.send(());
+    serving.await??;
+    Ok(())
+}
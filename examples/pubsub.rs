@@ -0,0 +1,48 @@
+//! One publisher and two subscribers on an in-process server.
+//!
+//! Run with `cargo run --example pubsub`.
+
+use anyhow::Result;
+use tokio::sync::oneshot;
+
+use kv_store::client::DatabaseClient;
+use kv_store::MiniDatabase;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let storage = std::env::temp_dir().join("kv-store-pubsub-example.json");
+    let server = MiniDatabase::new(storage.display().to_string())
+        .bind("127.0.0.1:0")
+        .await?;
+    let addr = server.local_addr().to_string();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let serving = tokio::spawn(server.serve_with_shutdown(shutdown_rx));
+
+    let mut subscribers = Vec::new();
+    for name in ["first", "second"] {
+        let mut subscription = DatabaseClient::new(&addr).await?.subscribe("news").await?;
+        subscribers.push(tokio::spawn(async move {
+            for _ in 0..3 {
+                match subscription.next_message().await? {
+                    Some(message) => println!("{} got {}: {}", name, message.channel, message.payload),
+                    None => break,
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        }));
+    }
+
+    let publisher = DatabaseClient::new(&addr).await?;
+    for payload in ["hello", "world", "bye"] {
+        let receivers = publisher.publish("news", payload).await?;
+        println!("published {} to {} subscribers", payload, receivers);
+    }
+
+    for subscriber in subscribers {
+        subscriber.await??;
+    }
+    let _ = shutdown_tx.send(());
+    serving.await??;
+    std::fs::remove_file(&storage).ok();
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! A primary and a read-only replica following it, both in-process.
+//!
+//! Run with `cargo run --example replication`.
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Result};
+use tokio::sync::oneshot;
+
+use kv_store::client::DatabaseClient;
+use kv_store::{MiniDatabase, StorageBackend};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let primary = MiniDatabase::new(String::new())
+        .with_backend(StorageBackend::Memory)
+        .bind("127.0.0.1:0")
+        .await?;
+    let primary_addr = primary.local_addr().to_string();
+    let (primary_tx, primary_rx) = oneshot::channel();
+    let primary_serving = tokio::spawn(primary.serve_with_shutdown(primary_rx));
+
+    // Written before the replica exists, so it arrives with the initial snapshot
+    let writer = DatabaseClient::new(&primary_addr).await?;
+    writer.set("before", "snapshot").await?;
+
+    let replica = MiniDatabase::new(String::new())
+        .with_backend(StorageBackend::Memory)
+        .with_replica_of(&primary_addr)
+        .bind("127.0.0.1:0")
+        .await?;
+    let replica_addr = replica.local_addr().to_string();
+    let (replica_tx, replica_rx) = oneshot::channel();
+    let replica_serving = tokio::spawn(replica.serve_with_shutdown(replica_rx));
+
+    let reader = DatabaseClient::new(&replica_addr).await?;
+    wait_for(&reader, "before", Some("snapshot")).await?;
+    println!("replica synced the snapshot");
+
+    // Streamed to the replica as the primary applies them
+    writer.set("after", "stream").await?;
+    writer.incr("counter").await?;
+    writer.incr("counter").await?;
+    writer.delete("before").await?;
+    wait_for(&reader, "counter", Some("2")).await?;
+    wait_for(&reader, "before", None).await?;
+    ensure!(reader.get("after").await?.as_deref() == Some("stream"), "replica missed a write");
+    println!("replica applied the streamed writes");
+
+    let refused = reader.set("direct", "write").await;
+    ensure!(refused.is_err(), "the replica accepted a write");
+    println!("replica refused a direct write: {}", refused.unwrap_err());
+
+    drop(reader);
+    drop(writer);
+    let _ = replica_tx.send(());
+    replica_serving.await??;
+    let _ = primary_tx.send(());
+    primary_serving.await??;
+    Ok(())
+}
+
+/// Poll the replica until `key` holds `expected`, giving up after a few seconds
+async fn wait_for(client: &DatabaseClient, key: &str, expected: Option<&str>) -> Result<()> {
+    for _ in 0..50 {
+        if client.get(key).await?.as_deref() == expected {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    bail!("replica never saw {} = {:?}", key, expected)
+}
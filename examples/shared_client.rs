@@ -0,0 +1,53 @@
+//! Many tasks sharing one client connection on an in-process server.
+//!
+//! Run with `cargo run --example shared_client`.
+
+use anyhow::{ensure, Result};
+use tokio::sync::oneshot;
+
+use kv_store::client::DatabaseClient;
+use kv_store::MiniDatabase;
+
+const TASKS: usize = 50;
+const KEYS_PER_TASK: usize = 20;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let storage = std::env::temp_dir().join("kv-store-shared-client-example.json");
+    let server = MiniDatabase::new(storage.display().to_string())
+        .bind("127.0.0.1:0")
+        .await?;
+    let addr = server.local_addr().to_string();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let serving = tokio::spawn(server.serve_with_shutdown(shutdown_rx));
+
+    let client = DatabaseClient::new(&addr).await?;
+    let mut tasks = Vec::new();
+    for task in 0..TASKS {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            for i in 0..KEYS_PER_TASK {
+                let key = format!("task{}:key{}", task, i);
+                let value = format!("{}-{}", task, i);
+                client.set(&key, &value).await?;
+                // Each caller gets the reply to its own command, not a neighbour's
+                let read = client.get(&key).await?;
+                ensure!(read.as_deref() == Some(value.as_str()), "{} read back {:?}", key, read);
+            }
+            Ok(())
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+
+    let keys = client.len().await?;
+    println!("{} tasks wrote {} keys over one connection", TASKS, keys);
+    ensure!(keys == TASKS * KEYS_PER_TASK, "expected {} keys", TASKS * KEYS_PER_TASK);
+
+    drop(client);
+    let _ = shutdown_tx.send(());
+    serving.await??;
+    std::fs::remove_file(&storage).ok();
+    Ok(())
+}
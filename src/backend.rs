@@ -0,0 +1,185 @@
+//! Storage backends: where a server loads its databases from and saves them to
+
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+
+use crate::error::ServerError;
+use crate::lockfile::StorageLock;
+use crate::store::{Databases, PendingSave, StorageBackend, StorageOptions};
+
+/// What a server needs from the place its databases are kept between runs
+pub(crate) trait Storage {
+    fn kind(&self) -> StorageBackend;
+
+    /// Whether writes ever reach disk, so there is anything to save or log
+    fn is_persistent(&self) -> bool;
+
+    /// The saved databases, or `None` when nothing is kept between runs
+    fn load(&self) -> Result<Option<Databases>>;
+
+    /// Start saving `store`, or `None` when nothing is kept
+    fn begin_save(&self, store: &Databases, options: StorageOptions) -> Result<Option<PendingSave>>;
+
+    /// Lock the storage for this server, if there is any to lock
+    fn lock(&self, force: bool) -> Result<Option<StorageLock>, ServerError>;
+}
+
+/// Keeps the databases in a storage file, loaded on start and saved as they change
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file's databases; empty ones when the file does not exist yet
+    pub fn load(&self) -> Result<Databases> {
+        Databases::load_from_file(&self.path)
+    }
+
+    /// Take the file's lock so a second server cannot save over it
+    pub fn lock(&self, force: bool) -> Result<StorageLock, ServerError> {
+        StorageLock::acquire(&self.path, force)
+    }
+}
+
+impl Storage for FileStore {
+    fn kind(&self) -> StorageBackend {
+        StorageBackend::File
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn load(&self) -> Result<Option<Databases>> {
+        FileStore::load(self).map(Some)
+    }
+
+    /// Write `store` into the temp file, to be committed once any lock on it is released
+    fn begin_save(&self, store: &Databases, options: StorageOptions) -> Result<Option<PendingSave>> {
+        store.begin_save(&self.path, options).map(Some)
+    }
+
+    fn lock(&self, force: bool) -> Result<Option<StorageLock>, ServerError> {
+        FileStore::lock(self, force).map(Some)
+    }
+}
+
+/// Keeps the databases in memory only, for ephemeral caches: nothing is loaded,
+/// saved, logged or locked, and everything is lost when the server stops
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStore;
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Storage for MemoryStore {
+    fn kind(&self) -> StorageBackend {
+        StorageBackend::Memory
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    /// `None`, so the server keeps the empty databases it started with
+    fn load(&self) -> Result<Option<Databases>> {
+        Ok(None)
+    }
+
+    fn begin_save(&self, _store: &Databases, _options: StorageOptions) -> Result<Option<PendingSave>> {
+        Ok(None)
+    }
+
+    fn lock(&self, _force: bool) -> Result<Option<StorageLock>, ServerError> {
+        Ok(None)
+    }
+}
+
+/// The backend a server runs with, chosen with [`StorageBackend`]
+#[derive(Debug, Clone)]
+pub(crate) enum Backend {
+    File(FileStore),
+    Memory(MemoryStore),
+}
+
+impl Backend {
+    pub(crate) fn new(kind: StorageBackend, storage_path: &str) -> Self {
+        match kind {
+            StorageBackend::File => Self::File(FileStore::new(storage_path)),
+            StorageBackend::Memory => Self::Memory(MemoryStore::new()),
+        }
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        match self {
+            Self::File(file) => file,
+            Self::Memory(memory) => memory,
+        }
+    }
+
+    pub(crate) fn kind(&self) -> StorageBackend {
+        self.storage().kind()
+    }
+
+    pub(crate) fn is_persistent(&self) -> bool {
+        self.storage().is_persistent()
+    }
+
+    pub(crate) fn load(&self) -> Result<Option<Databases>> {
+        self.storage().load()
+    }
+
+    pub(crate) fn begin_save(&self, store: &Databases, options: StorageOptions) -> Result<Option<PendingSave>> {
+        self.storage().begin_save(store, options)
+    }
+
+    pub(crate) fn lock(&self, force: bool) -> Result<Option<StorageLock>, ServerError> {
+        self.storage().lock(force)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn memory_backend_keeps_nothing_between_runs() {
+        let backend = Backend::new(StorageBackend::Memory, "unused.json");
+        assert_eq!(backend.kind(), StorageBackend::Memory);
+        assert!(!backend.is_persistent());
+        assert!(backend.load().unwrap().is_none());
+        assert!(backend.begin_save(&Databases::new(), StorageOptions::default()).unwrap().is_none());
+        assert!(backend.lock(false).unwrap().is_none());
+        assert!(!std::path::Path::new("unused.json").exists());
+    }
+
+    #[test]
+    fn file_backend_saves_and_locks_its_file() {
+        let dir = TempDir::new();
+        let path = dir.join("db.json");
+        let backend = Backend::new(StorageBackend::File, path.to_str().unwrap());
+        assert!(backend.is_persistent());
+        assert_eq!(backend.load().unwrap().unwrap().get(0).len(), 0);
+
+        let lock = backend.lock(false).unwrap().unwrap();
+        assert!(lock.path().exists());
+        let mut databases = Databases::new();
+        databases.get_mut(0).set("name".to_string(), "Ada".to_string());
+        backend.begin_save(&databases, StorageOptions::default()).unwrap().unwrap().commit().unwrap();
+        assert_eq!(backend.load().unwrap().unwrap().get(0).get_string("name").unwrap().as_deref(), Some("Ada"));
+    }
+}
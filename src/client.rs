@@ -1,142 +1,1327 @@
 //! Database client implementation
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod connection;
+
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, timeout, Instant, MissedTickBehavior};
 use tracing::info;
-use anyhow::Result;
 use serde_json;
 
-use crate::protocol::{DatabaseCommand, DatabaseResponse};
+use connection::{agreed_protocol, call, closed, frame, Connector, Request};
+pub use crate::error::ClientError;
+use crate::protocol::{DatabaseCommand, DatabaseResponse, HealthStatus, PersistenceStatus, ReplicationCommand, SetOptions, SetOutcome, PROTOCOL_VERSION};
+use crate::store::Databases;
+use crate::protocol::framing::read_frame;
+use crate::slowlog::SlowLogEntry;
+use crate::stats::StatsSnapshot;
+
+/// How long to wait for a response unless configured otherwise
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Result<T> = std::result::Result<T, ClientError>;
+
+/// Outcome of [`DatabaseClient::get_detailed`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Found(String),
+    /// No live key by that name, as opposed to one holding an empty string
+    Missing,
+}
+
+impl Lookup {
+    pub fn is_found(&self) -> bool {
+        matches!(self, Lookup::Found(_))
+    }
+
+    pub fn into_option(self) -> Option<String> {
+        match self {
+            Lookup::Found(value) => Some(value),
+            Lookup::Missing => None,
+        }
+    }
+}
+
+/// Database client for connecting to the server.
+///
+/// Generic over the underlying stream so the same client can talk over TCP or any
+/// other `AsyncRead + AsyncWrite` transport, such as an in-memory `tokio::io::duplex`.
+///
+/// # Concurrency
+///
+/// The stream is owned by a background task, started when the client is created,
+/// that takes requests from a queue. The client is a cheap handle onto that queue:
+/// clone it to share one connection between tasks. Commands queued back to back are
+/// written together and their responses read in the same order, so concurrent
+/// callers are pipelined on the connection while each gets its own reply. Pipelines
+/// and transactions run as a unit, never interleaved with other commands. Dropping
+/// every clone, and any [`KeepAlive`] started from one, stops the task and closes
+/// the connection.
+///
+/// The connection's state is shared too: `select` and `auth` on one clone apply to
+/// all of them. Read timeouts are per handle.
+pub struct DatabaseClient<S = TcpStream> {
+    requests: mpsc::Sender<Request<S>>,
+    read_timeout: Option<Duration>,
+    /// Protocol version agreed with the server, also updated by the connection task
+    /// when a reconnecting client redials
+    protocol: Arc<AtomicU32>,
+}
 
-/// Database client for connecting to the server
-pub struct DatabaseClient {
-    stream: TcpStream,
+impl<S> Clone for DatabaseClient<S> {
+    fn clone(&self) -> Self {
+        Self {
+            requests: self.requests.clone(),
+            read_timeout: self.read_timeout,
+            protocol: self.protocol.clone(),
+        }
+    }
 }
 
 impl DatabaseClient {
     pub async fn new(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
+        let stream = TcpStream::connect(addr).await.map_err(ClientError::ConnectionFailed)?;
         info!("Connected to database server at {}", addr);
-        
-        Ok(Self { stream })
+
+        let client = Self::from_stream(stream);
+        client.hello(PROTOCOL_VERSION).await?;
+        Ok(client)
+    }
+
+    /// Connect, giving up with [`ClientError::ConnectTimeout`] after `limit`
+    pub async fn connect_with_timeout(addr: &str, limit: Duration) -> Result<Self> {
+        let stream = timeout(limit, TcpStream::connect(addr))
+            .await
+            .map_err(|_| ClientError::ConnectTimeout(limit))?
+            .map_err(ClientError::ConnectionFailed)?;
+        info!("Connected to database server at {}", addr);
+
+        let client = Self::from_stream(stream);
+        client.hello(PROTOCOL_VERSION).await?;
+        Ok(client)
+    }
+
+    /// Connect in reconnecting mode.
+    ///
+    /// If the connection breaks during a command the client redials with capped
+    /// exponential backoff and retries that command once. A command whose response
+    /// was lost may therefore run twice, and MULTI state does not survive a reconnect.
+    pub async fn new_reconnecting(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await.map_err(ClientError::ConnectionFailed)?;
+        info!("Connected to database server at {}", addr);
+
+        let addr = addr.to_string();
+        let connector: Connector<TcpStream> = Box::new(move || {
+            let addr = addr.clone();
+            Box::pin(async move { TcpStream::connect(addr).await })
+        });
+        let client = Self::spawn(stream, Some(connector));
+        client.hello(PROTOCOL_VERSION).await?;
+        Ok(client)
+    }
+}
+
+#[cfg(unix)]
+impl DatabaseClient<UnixStream> {
+    /// Connect to a server listening on a Unix domain socket
+    pub async fn new_unix<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path).await.map_err(ClientError::ConnectionFailed)?;
+        info!("Connected to database server at {}", path.display());
+
+        let client = Self::from_stream(stream);
+        client.hello(PROTOCOL_VERSION).await?;
+        Ok(client)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> DatabaseClient<S> {
+    /// Wrap an already connected stream; must be called within a Tokio runtime
+    pub fn from_stream(stream: S) -> Self {
+        Self::spawn(stream, None)
+    }
+
+    fn spawn(stream: S, connector: Option<Connector<S>>) -> Self {
+        let protocol = Arc::new(AtomicU32::new(PROTOCOL_VERSION));
+        Self {
+            requests: connection::spawn(stream, connector, protocol.clone()),
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            protocol,
+        }
+    }
+
+    /// Ask to speak `protocol` on this connection and return the version the server
+    /// agreed to. Constructors that connect do this with [`PROTOCOL_VERSION`]; streams
+    /// wrapped with [`DatabaseClient::from_stream`] assume the latest until asked.
+    ///
+    /// Servers from before the handshake answer with an error and are taken to speak
+    /// version 1.
+    pub async fn hello(&self, protocol: u32) -> Result<u32> {
+        let agreed = agreed_protocol(self.send_command(DatabaseCommand::Hello { protocol }).await?);
+        self.protocol.store(agreed, Ordering::Relaxed);
+        Ok(agreed)
+    }
+
+    /// Protocol version agreed with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    /// Set how long this handle waits for each response; `None` waits forever.
+    ///
+    /// After a [`ClientError::ReadTimeout`] a late response may still arrive, so the
+    /// connection is not used again: a reconnecting client opens a new one for the next
+    /// command, from any clone, and any other client fails every later command.
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
     }
 
-    async fn send_command(&mut self, command: DatabaseCommand) -> Result<DatabaseResponse> {
-        let command_data = serde_json::to_vec(&command)?;
-        self.stream.write_all(&command_data).await?;
+    /// Send `Ping` every `interval` on this connection until one fails.
+    ///
+    /// Pings go through the same queue as the client's own commands, so they keep an
+    /// idle connection from being dropped by NATs and firewalls without getting in the
+    /// way of a pipeline or transaction. The first failure, e.g. a dead connection or
+    /// a timeout, is reported by [`KeepAlive::failed`] and stops the pings. Dropping
+    /// the [`KeepAlive`] stops them too; it does not keep the connection open once the
+    /// client itself is dropped.
+    pub fn start_keepalive(&self, interval: Duration) -> KeepAlive {
+        let requests = self.requests.downgrade();
+        let read_timeout = self.read_timeout;
+        let (report, failure) = oneshot::channel();
 
-        let mut buffer = vec![0; 1024];
-        let n = self.stream.read(&mut buffer).await?;
-        let response: DatabaseResponse = serde_json::from_slice(&buffer[..n])?;
+        let task = tokio::spawn(async move {
+            let mut ticks = interval_at(Instant::now() + interval, interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let ping = match frame(&DatabaseCommand::Ping) {
+                Ok(ping) => ping,
+                Err(e) => {
+                    let _ = report.send(e);
+                    return;
+                }
+            };
+            loop {
+                ticks.tick().await;
+                // Once the client is gone there is no connection left to keep alive
+                let Some(requests) = requests.upgrade() else {
+                    return;
+                };
+                let frame = ping.clone();
+                let result = call(&requests, |reply| Request::Command { frame, read_timeout, reply }).await;
+                let error = match result {
+                    Ok(DatabaseResponse::Pong) => continue,
+                    Ok(DatabaseResponse::Error { message }) => ClientError::Server { message },
+                    Ok(_) => ClientError::unexpected_response(),
+                    Err(e) => e,
+                };
+                let _ = report.send(error);
+                return;
+            }
+        });
 
-        Ok(response)
+        KeepAlive { failure, task }
     }
 
-    pub async fn get(&mut self, key: &str) -> Result<Option<String>> {
+    async fn send_command(&self, command: DatabaseCommand) -> Result<DatabaseResponse> {
+        let frame = frame(&command)?;
+        let read_timeout = self.read_timeout;
+        call(&self.requests, |reply| Request::Command { frame, read_timeout, reply }).await
+    }
+
+    /// Send every command without waiting, then collect one response per command.
+    ///
+    /// The server handles a connection's commands one at a time, so responses come
+    /// back in the same order as `commands`. Responses are read while commands are
+    /// still being written so a large batch cannot fill both socket buffers and stall.
+    /// Pipelines are never retried by a reconnecting client.
+    pub async fn pipeline(&self, commands: Vec<DatabaseCommand>) -> Result<Vec<DatabaseResponse>> {
+        let frames = commands.iter().map(frame).collect::<Result<Vec<_>>>()?;
+        let read_timeout = self.read_timeout;
+        call(&self.requests, |reply| Request::Pipeline { frames, read_timeout, reply }).await
+    }
+
+    /// Stop the connection task and take back the stream, e.g. for a subscription
+    async fn into_stream(self) -> Result<S> {
+        let (reply, stream) = oneshot::channel();
+        self.requests.send(Request::Detach { reply }).await.map_err(|_| closed())?;
+        stream.await.map_err(|_| closed())
+    }
+
+    /// The value at `key`, or `None` if it is missing; an empty value is `Some("")`
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
         let response = self.send_command(DatabaseCommand::Get { key: key.to_string() }).await?;
         
         match response {
             DatabaseResponse::Ok { value } => Ok(value),
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
         }
     }
 
-    pub async fn set(&mut self, key: &str, value: &str) -> Result<Option<String>> {
+    /// Like [`DatabaseClient::get`], but spelling out that the key was missing, e.g.
+    /// to cache negative lookups. An empty value is `Found("")`.
+    pub async fn get_detailed(&self, key: &str) -> Result<Lookup> {
+        Ok(match self.get(key).await? {
+            Some(value) => Lookup::Found(value),
+            None => Lookup::Missing,
+        })
+    }
+
+    /// Store binary data, which need not be valid UTF-8
+    pub async fn set_bytes(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.expect_ok(DatabaseCommand::SetBytes {
+            key: key.to_string(),
+            value: value.to_vec(),
+        }).await
+    }
+
+    /// Read a value as bytes; strings come back as their UTF-8 encoding
+    pub async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.send_command(DatabaseCommand::GetBytes { key: key.to_string() }).await?;
+
+        match response {
+            DatabaseResponse::Bytes { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<Option<String>> {
         let response = self.send_command(DatabaseCommand::Set { 
+            key: key.to_string(), 
+            value: value.to_string(),
+            opts: None,
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Set subject to NX/XX-style conditions; returns whether the key was created,
+    /// updated or skipped, and the previous value if `opts.get_old` is set
+    pub async fn set_with_options(&self, key: &str, value: &str, opts: SetOptions) -> Result<(SetOutcome, Option<String>)> {
+        let response = self.send_command(DatabaseCommand::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+            opts: Some(opts),
+        }).await?;
+
+        match response {
+            DatabaseResponse::SetResult { outcome, old_value } => Ok((outcome, old_value)),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Set `key` to expire after `ttl`, rounded up to whole seconds; returns the
+    /// previous value
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use kv_store::client::DatabaseClient;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), kv_store::ClientError> {
+    /// let client = DatabaseClient::new("127.0.0.1:6379").await?;
+    /// client.set_ex("session", "token", Duration::from_secs(1)).await?;
+    /// assert_eq!(client.get("session").await?.as_deref(), Some("token"));
+    ///
+    /// tokio::time::sleep(Duration::from_secs(2)).await;
+    /// assert_eq!(client.get("session").await?, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_ex(&self, key: &str, value: &str, ttl: Duration) -> Result<Option<String>> {
+        let response = self.send_command(DatabaseCommand::SetEx {
+            key: key.to_string(),
+            value: value.to_string(),
+            seconds: ceil_secs(ttl),
+        }).await?;
+
+        match response {
+            DatabaseResponse::Ok { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Expire an existing key after `ttl`, rounded up to whole seconds; returns false
+    /// if the key does not exist
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use kv_store::client::DatabaseClient;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), kv_store::ClientError> {
+    /// let client = DatabaseClient::new("127.0.0.1:6379").await?;
+    /// client.set("name", "Ada").await?;
+    /// assert!(client.expire("name", Duration::from_millis(500)).await?);
+    /// assert!(!client.expire("missing", Duration::from_secs(1)).await?);
+    ///
+    /// tokio::time::sleep(Duration::from_secs(2)).await;
+    /// assert!(!client.exists("name").await?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::Expire {
+            key: key.to_string(),
+            seconds: ceil_secs(ttl),
+        }).await?;
+
+        match response {
+            DatabaseResponse::Bool { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Time left before `key` expires, `None` if it never does, and
+    /// [`ClientError::KeyNotFound`] if it does not exist
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use kv_store::client::DatabaseClient;
+    /// # use kv_store::ClientError;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), ClientError> {
+    /// let client = DatabaseClient::new("127.0.0.1:6379").await?;
+    /// client.set("forever", "x").await?;
+    /// assert_eq!(client.ttl("forever").await?, None);
+    ///
+    /// client.set_ex("session", "token", Duration::from_secs(1)).await?;
+    /// assert!(client.ttl("session").await?.is_some());
+    /// tokio::time::sleep(Duration::from_secs(2)).await;
+    /// assert!(matches!(client.ttl("session").await, Err(ClientError::KeyNotFound { .. })));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let response = self.send_command(DatabaseCommand::Ttl { key: key.to_string() }).await?;
+
+        match response {
+            DatabaseResponse::Ttl { seconds: -2 } => Err(ClientError::KeyNotFound { key: key.to_string() }),
+            DatabaseResponse::Ttl { seconds } => Ok(u64::try_from(seconds).ok().map(Duration::from_secs)),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn get_set(&self, key: &str, value: &str) -> Result<Option<String>> {
+        let response = self.send_command(DatabaseCommand::GetSet { 
+            key: key.to_string(), 
+            value: value.to_string() 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn set_nx(&self, key: &str, value: &str) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::SetNx { 
+            key: key.to_string(), 
+            value: value.to_string() 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value: Some(v) } => Ok(v == "1"),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Set `new` only if the current value equals `expected`; `None` requires the key to be absent
+    pub async fn cas(&self, key: &str, expected: Option<&str>, new: &str) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::Cas { 
+            key: key.to_string(), 
+            expected: expected.map(str::to_string), 
+            new: new.to_string() 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Bool { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn mset(&self, pairs: &[(&str, &str)]) -> Result<()> {
+        let pairs = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let response = self.send_command(DatabaseCommand::MSet { pairs }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { .. } => Ok(()),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn mget(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        let response = self.send_command(DatabaseCommand::MGet { keys }).await?;
+        
+        match response {
+            DatabaseResponse::Values { values } => Ok(values),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn append(&self, key: &str, value: &str) -> Result<usize> {
+        let response = self.send_command(DatabaseCommand::Append { 
             key: key.to_string(), 
             value: value.to_string() 
         }).await?;
         
+        match response {
+            DatabaseResponse::Ok { value: Some(v) } => Ok(v.parse()?),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Bytes `start..=end` of the string at `key`, with negative offsets counting back
+    /// from the end; `None` if the key is missing. See [`KeyValueStore::get_range`].
+    ///
+    /// [`KeyValueStore::get_range`]: crate::store::KeyValueStore::get_range
+    pub async fn get_range(&self, key: &str, start: i64, end: i64) -> Result<Option<String>> {
+        let response = self.send_command(DatabaseCommand::GetRange { key: key.to_string(), start, end }).await?;
+
         match response {
             DatabaseResponse::Ok { value } => Ok(value),
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Length of the value at `key` in bytes, not characters, without fetching it;
+    /// 0 if the key is missing
+    pub async fn strlen(&self, key: &str) -> Result<usize> {
+        let response = self.send_command(DatabaseCommand::StrLen { key: key.to_string() }).await?;
+
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Overwrite the string at `key` from byte `offset`, returning the new length
+    pub async fn set_range(&self, key: &str, offset: usize, value: &str) -> Result<usize> {
+        let response = self.send_command(DatabaseCommand::SetRange {
+            key: key.to_string(),
+            offset,
+            value: value.to_string(),
+        }).await?;
+
+        match response {
+            DatabaseResponse::Ok { value: Some(v) } => Ok(v.parse()?),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn incr(&self, key: &str) -> Result<i64> {
+        self.incr_by(key, 1).await
+    }
+
+    pub async fn decr(&self, key: &str) -> Result<i64> {
+        self.incr_by(key, -1).await
+    }
+
+    pub async fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        let response = self.send_command(DatabaseCommand::IncrBy { 
+            key: key.to_string(), 
+            delta 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value: Some(v) } => Ok(v.parse()?),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
         }
     }
 
-    pub async fn delete(&mut self, key: &str) -> Result<Option<String>> {
-        let response = self.send_command(DatabaseCommand::Delete { key: key.to_string() }).await?;
+    pub async fn lpush(&self, key: &str, values: &[&str]) -> Result<usize> {
+        self.push(DatabaseCommand::LPush { 
+            key: key.to_string(), 
+            values: values.iter().map(|v| v.to_string()).collect() 
+        }).await
+    }
+
+    pub async fn rpush(&self, key: &str, values: &[&str]) -> Result<usize> {
+        self.push(DatabaseCommand::RPush { 
+            key: key.to_string(), 
+            values: values.iter().map(|v| v.to_string()).collect() 
+        }).await
+    }
+
+    async fn push(&self, command: DatabaseCommand) -> Result<usize> {
+        let response = self.send_command(command).await?;
+        
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn lpop(&self, key: &str) -> Result<Option<String>> {
+        self.pop(DatabaseCommand::LPop { key: key.to_string() }).await
+    }
+
+    pub async fn rpop(&self, key: &str) -> Result<Option<String>> {
+        self.pop(DatabaseCommand::RPop { key: key.to_string() }).await
+    }
+
+    async fn pop(&self, command: DatabaseCommand) -> Result<Option<String>> {
+        let response = self.send_command(command).await?;
         
         match response {
             DatabaseResponse::Ok { value } => Ok(value),
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn llen(&self, key: &str) -> Result<usize> {
+        let response = self.send_command(DatabaseCommand::LLen { key: key.to_string() }).await?;
+        
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
         }
     }
 
-    pub async fn exists(&mut self, key: &str) -> Result<bool> {
+    pub async fn hset(&self, key: &str, field: &str, value: &str) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::HSet { 
+            key: key.to_string(), 
+            field: field.to_string(), 
+            value: value.to_string() 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Bool { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        let response = self.send_command(DatabaseCommand::HGet { 
+            key: key.to_string(), 
+            field: field.to_string() 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn hdel(&self, key: &str, field: &str) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::HDel { 
+            key: key.to_string(), 
+            field: field.to_string() 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Bool { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>> {
+        let response = self.send_command(DatabaseCommand::HGetAll { key: key.to_string() }).await?;
+        
+        match response {
+            DatabaseResponse::Pairs { pairs } => Ok(pairs),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Move `from` to `to`, replacing any value at `to`
+    pub async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.expect_ok(DatabaseCommand::Rename { 
+            from: from.to_string(), 
+            to: to.to_string() 
+        }).await
+    }
+
+    /// Move `from` to `to` only if `to` does not exist, returning whether it moved
+    pub async fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::RenameNx { 
+            from: from.to_string(), 
+            to: to.to_string() 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Bool { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Copy `from` to `to`, returning false if `to` exists and `replace` is false
+    pub async fn copy(&self, from: &str, to: &str, replace: bool) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::Copy { 
+            from: from.to_string(), 
+            to: to.to_string(), 
+            replace 
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Bool { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Delete `key`, returning its previous string value; `None` if it was missing or
+    /// held a collection
+    pub async fn delete(&self, key: &str) -> Result<Option<String>> {
+        let response = self.send_command(DatabaseCommand::Delete { key: key.to_string(), detailed: false }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Delete `key`, returning its previous string value and whether a live key was
+    /// removed, so deleting a missing key is told apart from deleting a list or hash
+    pub async fn delete_detailed(&self, key: &str) -> Result<(Option<String>, bool)> {
+        let response = self.send_command(DatabaseCommand::Delete { key: key.to_string(), detailed: true }).await?;
+
+        match response {
+            DatabaseResponse::Deleted { value, deleted } => Ok((value, deleted)),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Delete several keys in one round-trip, returning how many existed
+    pub async fn delete_many(&self, keys: &[&str]) -> Result<usize> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        let response = self.send_command(DatabaseCommand::DeleteMany { keys }).await?;
+        
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool> {
         let response = self.send_command(DatabaseCommand::Exists { key: key.to_string() }).await?;
         
         match response {
-            DatabaseResponse::Ok { value } => {
-                match value {
-                    Some(v) => Ok(v.parse().unwrap_or(false)),
-                    None => Ok(false),
-                }
-            },
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Bool { value } => Ok(value),
+            // Older servers send the flag as a "true"/"false" string
+            DatabaseResponse::Ok { value: Some(v) } => v
+                .parse()
+                .map_err(|_| ClientError::Protocol(format!("Invalid boolean in response: {}", v))),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
         }
     }
 
-    pub async fn keys(&mut self) -> Result<Vec<String>> {
+    /// How many of `keys` exist, counting repeated keys each time
+    pub async fn exists_many(&self, keys: &[&str]) -> Result<usize> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        let response = self.send_command(DatabaseCommand::ExistsMany { keys }).await?;
+        
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Mark `keys` as just used so LRU eviction keeps them longest, without reading
+    /// their values; returns how many exist
+    pub async fn touch(&self, keys: &[&str]) -> Result<usize> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        let response = self.send_command(DatabaseCommand::Touch { keys }).await?;
+
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn type_of(&self, key: &str) -> Result<String> {
+        let response = self.send_command(DatabaseCommand::Type { key: key.to_string() }).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value: Some(v) } => Ok(v),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn keys(&self) -> Result<Vec<String>> {
         let response = self.send_command(DatabaseCommand::Keys).await?;
         
         match response {
             DatabaseResponse::Keys { keys } => Ok(keys),
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// A random existing key, or `None` if the database is empty
+    pub async fn random_key(&self) -> Result<Option<String>> {
+        let response = self.send_command(DatabaseCommand::RandomKey).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn scan(&self, prefix: &str) -> Result<Vec<String>> {
+        let response = self.send_command(DatabaseCommand::Scan { prefix: prefix.to_string() }).await?;
+        
+        match response {
+            DatabaseResponse::Keys { keys } => Ok(keys),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// One page of keys from `cursor` and the cursor for the next page, 0 when done
+    pub async fn scan_cursor(&self, cursor: u64, count: usize) -> Result<(u64, Vec<String>)> {
+        let response = self.send_command(DatabaseCommand::ScanCursor { cursor, count }).await?;
+
+        match response {
+            DatabaseResponse::ScanPage { cursor, keys } => Ok((cursor, keys)),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Every key, fetched `count` at a time with `ScanCursor` so no single response
+    /// has to hold the whole keyspace
+    pub async fn scan_all(&self, count: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, page) = self.scan_cursor(cursor, count).await?;
+            keys.extend(page);
+            if next == 0 {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+
+    pub async fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let response = self.send_command(DatabaseCommand::KeysMatching { pattern: pattern.to_string() }).await?;
+        
+        match response {
+            DatabaseResponse::Keys { keys } => Ok(keys),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
         }
     }
 
-    pub async fn len(&mut self) -> Result<usize> {
+    pub async fn len(&self) -> Result<usize> {
         let response = self.send_command(DatabaseCommand::Len).await?;
         
         match response {
             DatabaseResponse::Len { count } => Ok(count),
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Seconds since the value at `key` last changed
+    pub async fn key_age(&self, key: &str) -> Result<u64> {
+        let response = self.send_command(DatabaseCommand::KeyAge { key: key.to_string() }).await?;
+
+        match response {
+            DatabaseResponse::Age { seconds } => Ok(seconds),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Whether the selected database has no live keys
+    pub async fn is_empty(&self) -> Result<bool> {
+        let response = self.send_command(DatabaseCommand::IsEmpty).await?;
+
+        match response {
+            DatabaseResponse::Bool { value } => Ok(value),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
         }
     }
 
-    pub async fn clear(&mut self) -> Result<()> {
+    /// Total bytes of keys and values in the selected database
+    pub async fn byte_size(&self) -> Result<usize> {
+        let response = self.send_command(DatabaseCommand::ByteSize).await?;
+        
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    pub async fn clear(&self) -> Result<()> {
         let response = self.send_command(DatabaseCommand::Clear).await?;
         
         match response {
             DatabaseResponse::Ok { .. } => Ok(()),
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
         }
     }
 
-    pub async fn ping(&mut self) -> Result<()> {
+    /// The selected database as a blob for [`DatabaseClient::restore`]
+    pub async fn dump(&self) -> Result<String> {
+        let response = self.send_command(DatabaseCommand::Dump).await?;
+        
+        match response {
+            DatabaseResponse::Ok { value: Some(blob) } => Ok(blob),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Replace the selected database with a blob from [`DatabaseClient::dump`]
+    pub async fn restore(&self, data: &str) -> Result<()> {
+        self.expect_ok(DatabaseCommand::Restore { data: data.to_string() }).await
+    }
+
+    /// Empty the selected database; with `sync`, return only once that is saved to disk
+    pub async fn flush_db(&self, sync: bool) -> Result<()> {
+        self.expect_ok(DatabaseCommand::FlushDb { sync }).await
+    }
+
+    /// Apply `commands` atomically with MULTI/EXEC, returning one response per command
+    pub async fn transaction(&self, commands: Vec<DatabaseCommand>) -> Result<Vec<DatabaseResponse>> {
+        let frames = commands.iter().map(frame).collect::<Result<Vec<_>>>()?;
+        let read_timeout = self.read_timeout;
+        call(&self.requests, |reply| Request::Transaction { frames, read_timeout, reply }).await
+    }
+
+    async fn expect_ok(&self, command: DatabaseCommand) -> Result<()> {
+        connection::expect_ok(self.send_command(command).await?)
+    }
+
+    pub async fn auth(&self, password: &str) -> Result<()> {
+        self.expect_ok(DatabaseCommand::Auth { password: password.to_string() }).await
+    }
+
+    pub async fn stats(&self) -> Result<StatsSnapshot> {
+        let response = self.send_command(DatabaseCommand::Stats).await?;
+        
+        match response {
+            DatabaseResponse::Stats { stats } => Ok(stats),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Whether the server has finished loading, and how many keys it holds
+    pub async fn health(&self) -> Result<HealthStatus> {
+        let response = self.send_command(DatabaseCommand::Health).await?;
+
+        match response {
+            DatabaseResponse::Health { health } => Ok(health),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Storage path and format, last save time, and whether a save is pending
+    pub async fn persistence(&self) -> Result<PersistenceStatus> {
+        let response = self.send_command(DatabaseCommand::Persistence).await?;
+
+        match response {
+            DatabaseResponse::Persistence { persistence } => Ok(persistence),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// The server's version, protocol version and supported command names, for
+    /// checking whether a command is available before sending it
+    pub async fn command_info(&self) -> Result<(String, u32, Vec<String>)> {
+        let response = self.send_command(DatabaseCommand::Command).await?;
+
+        match response {
+            DatabaseResponse::CommandInfo { version, protocol_version, commands } => {
+                Ok((version, protocol_version, commands))
+            }
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Recent slow commands, newest first
+    pub async fn slow_log(&self) -> Result<Vec<SlowLogEntry>> {
+        let response = self.send_command(DatabaseCommand::SlowLog).await?;
+        
+        match response {
+            DatabaseResponse::SlowLog { entries } => Ok(entries),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Make the server replace its data with its storage file's contents; returns the
+    /// new key count. The server refuses while writes are unsaved unless `force` is
+    /// set, which discards them.
+    pub async fn reload(&self, force: bool) -> Result<usize> {
+        let response = self.send_command(DatabaseCommand::Reload { force }).await?;
+
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Switch this connection to database `index`, for every clone of the client
+    pub async fn select(&self, index: usize) -> Result<()> {
+        self.expect_ok(DatabaseCommand::Select { index }).await
+    }
+
+    pub async fn ping(&self) -> Result<()> {
         let response = self.send_command(DatabaseCommand::Ping).await?;
         
         match response {
             DatabaseResponse::Pong => Ok(()),
-            DatabaseResponse::Error { message } => Err(anyhow::anyhow!("Server error: {}", message)),
-            _ => Err(anyhow::anyhow!("Unexpected response type")),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Send a message to `channel`; returns how many subscribers received it
+    pub async fn publish(&self, channel: &str, message: &str) -> Result<usize> {
+        let response = self.send_command(DatabaseCommand::Publish {
+            channel: channel.to_string(),
+            message: message.to_string(),
+        }).await?;
+        
+        match response {
+            DatabaseResponse::Len { count } => Ok(count),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+
+    /// Subscribe to `channel`, turning this connection into a message stream.
+    ///
+    /// The connection can no longer run normal commands, so the client is consumed
+    /// and any clones of it fail with a closed connection; open another client to
+    /// publish or read data.
+    pub async fn subscribe(self, channel: &str) -> Result<Subscription<S>> {
+        self.expect_ok(DatabaseCommand::Subscribe { channel: channel.to_string() }).await?;
+        Ok(Subscription { stream: self.into_stream().await? })
+    }
+
+    /// Watch `key` in the selected database, turning this connection into a stream of
+    /// changes to it. Like [`DatabaseClient::subscribe`], this consumes the client.
+    pub async fn watch(self, key: &str) -> Result<KeyWatch<S>> {
+        self.expect_ok(DatabaseCommand::Watch { key: key.to_string() }).await?;
+        Ok(KeyWatch { stream: self.into_stream().await? })
+    }
+
+    /// Follow every write on the server, as a replica does: returns a snapshot of all
+    /// databases and the stream of mutations applied after it. Like
+    /// [`DatabaseClient::subscribe`], this consumes the client.
+    pub async fn replicate(self) -> Result<(Databases, Replication<S>)> {
+        let snapshot = match self.send_command(DatabaseCommand::Replicate).await? {
+            DatabaseResponse::Snapshot { data } => serde_json::from_str(&data)?,
+            DatabaseResponse::Error { message } => return Err(ClientError::Server { message }),
+            _ => return Err(ClientError::unexpected_response()),
+        };
+        Ok((snapshot, Replication { stream: self.into_stream().await? }))
+    }
+}
+
+/// A message received on a subscribed channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: String,
+    pub payload: String,
+}
+
+/// A connection streaming messages from a channel, created by [`DatabaseClient::subscribe`]
+pub struct Subscription<S = TcpStream> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Subscription<S> {
+    /// Wait for the next message; `None` once the server closes the connection
+    pub async fn next_message(&mut self) -> Result<Option<Message>> {
+        match read_notification(&mut self.stream).await? {
+            Some(DatabaseResponse::Message { channel, payload }) => Ok(Some(Message { channel, payload })),
+            Some(_) => Err(ClientError::unexpected_response()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A change to a watched key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub key: String,
+    /// The new value, or `None` if the key was deleted or expired
+    pub value: Option<String>,
+}
+
+/// A connection streaming changes to one key, created by [`DatabaseClient::watch`]
+pub struct KeyWatch<S = TcpStream> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> KeyWatch<S> {
+    /// Wait for the next change; `None` once the server closes the connection
+    pub async fn next_change(&mut self) -> Result<Option<KeyChange>> {
+        match read_notification(&mut self.stream).await? {
+            Some(DatabaseResponse::KeyChanged { key, value }) => Ok(Some(KeyChange { key, value })),
+            Some(_) => Err(ClientError::unexpected_response()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A connection streaming a primary's mutations, created by [`DatabaseClient::replicate`]
+pub struct Replication<S = TcpStream> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Replication<S> {
+    /// Wait for the next mutation; `None` once the server closes the connection.
+    ///
+    /// An error from the server means the stream can not continue, e.g. because this
+    /// replica fell behind, and a new snapshot is needed.
+    pub async fn next_command(&mut self) -> Result<Option<ReplicationCommand>> {
+        match read_notification(&mut self.stream).await? {
+            Some(DatabaseResponse::Replicated { command }) => Ok(Some(command)),
+            Some(_) => Err(ClientError::unexpected_response()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Periodic pings on a client's connection, started by [`DatabaseClient::start_keepalive`].
+///
+/// Dropping this stops the pings.
+pub struct KeepAlive {
+    failure: oneshot::Receiver<ClientError>,
+    task: JoinHandle<()>,
+}
+
+impl KeepAlive {
+    /// Wait for a ping to fail; never returns while the connection stays healthy.
+    /// Returns `None` if the pings stopped because the client was dropped.
+    pub async fn failed(&mut self) -> Option<ClientError> {
+        (&mut self.failure).await.ok()
+    }
+
+    /// The error that stopped the pings, if one has failed yet
+    pub fn try_failed(&mut self) -> Option<ClientError> {
+        self.failure.try_recv().ok()
+    }
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Read one pushed frame with no timeout, turning server errors into `Err`
+async fn read_notification<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<DatabaseResponse>> {
+    let Some(data) = read_frame(stream).await? else {
+        return Ok(None);
+    };
+    match serde_json::from_slice(&data)? {
+        DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+        response => Ok(Some(response)),
+    }
+}
+
+/// TTLs are whole seconds on the wire; round up so a short TTL never means "expire now"
+fn ceil_secs(ttl: Duration) -> u64 {
+    ttl.as_secs().saturating_add(u64::from(ttl.subsec_nanos() > 0))
+}
+
+/// How [`run_client_command`] prints what the server sends back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Plain,
+    /// Each response as one line of JSON in its wire form, for piping into tools like
+    /// `jq`; error responses go to stderr
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "json" => Ok(Self::Json),
+            other => Err(format!("unknown output format '{}' (expected plain or json)", other)),
         }
     }
 }
 
 /// Run a single client command
-pub async fn run_client_command(addr: &str, command: DatabaseCommand) -> Result<()> {
-    let mut client = DatabaseClient::new(addr).await?;
+pub async fn run_client_command(addr: &str, password: Option<&str>, db: usize, command: DatabaseCommand, output: OutputFormat) -> Result<()> {
+    let client = DatabaseClient::new(addr).await?;
+    run_with_client(client, password, db, command, output).await
+}
+
+/// Run a single client command over a Unix domain socket
+#[cfg(unix)]
+pub async fn run_unix_client_command(path: &str, password: Option<&str>, db: usize, command: DatabaseCommand, output: OutputFormat) -> Result<()> {
+    let client = DatabaseClient::new_unix(path).await?;
+    run_with_client(client, password, db, command, output).await
+}
+
+async fn run_with_client<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    client: DatabaseClient<S>,
+    password: Option<&str>,
+    db: usize,
+    command: DatabaseCommand,
+    output: OutputFormat,
+) -> Result<()> {
+    if let Some(password) = password {
+        client.auth(password).await?;
+    }
+    if db != 0 {
+        client.select(db).await?;
+    }
+    if let DatabaseCommand::Subscribe { channel } = command {
+        let mut subscription = client.subscribe(&channel).await?;
+        while let Some(message) = subscription.next_message().await? {
+            match output {
+                OutputFormat::Plain => println!("{}: {}", message.channel, message.payload),
+                OutputFormat::Json => print_json(&DatabaseResponse::message(message.channel, message.payload))?,
+            }
+        }
+        return Ok(());
+    }
+    if let DatabaseCommand::Watch { key } = command {
+        let mut watch = client.watch(&key).await?;
+        while let Some(change) = watch.next_change().await? {
+            match output {
+                OutputFormat::Plain => println!("{}: {}", change.key, change.value.as_deref().unwrap_or("(deleted)")),
+                OutputFormat::Json => print_json(&DatabaseResponse::key_changed(change.key, change.value))?,
+            }
+        }
+        return Ok(());
+    }
     
     let response = client.send_command(command).await?;
     
+    match output {
+        OutputFormat::Plain => print_response(response),
+        OutputFormat::Json => print_json(&response)?,
+    }
+
+    Ok(())
+}
+
+/// Print `response` as one line of JSON, on stderr if it is an error
+fn print_json(response: &DatabaseResponse) -> Result<()> {
+    let json = serde_json::to_string(response)?;
+    match response {
+        DatabaseResponse::Error { .. } => eprintln!("{}", json),
+        _ => println!("{}", json),
+    }
+    Ok(())
+}
+
+fn print_response(response: DatabaseResponse) {
     match response {
         DatabaseResponse::Ok { value } => {
             match value {
+                // Quoted, as redis-cli does, so it can't be mistaken for a missing key
+                Some(v) if v.is_empty() => println!("\"\""),
                 Some(v) => println!("{}", v),
                 None => println!("(null)"),
             }
         }
+        DatabaseResponse::SetResult { outcome, old_value } => {
+            let outcome = match outcome {
+                SetOutcome::Created => "created",
+                SetOutcome::Updated => "updated",
+                SetOutcome::Skipped => "skipped",
+            };
+            match old_value {
+                Some(old) => println!("{} (was {})", outcome, old),
+                None => println!("{}", outcome),
+            }
+        }
+        DatabaseResponse::Deleted { value, deleted } => match (deleted, value) {
+            (true, Some(old)) => println!("deleted (was {})", old),
+            (true, None) => println!("deleted"),
+            (false, _) => println!("not found"),
+        },
+        DatabaseResponse::Bytes { value } => {
+            match value {
+                Some(bytes) => {
+                    let mut stdout = io::stdout();
+                    if let Err(e) = stdout.write_all(&bytes).and_then(|_| stdout.flush()) {
+                        eprintln!("Error: {}", e);
+                    }
+                }
+                None => println!("(null)"),
+            }
+        }
         DatabaseResponse::Error { message } => {
             eprintln!("Error: {}", message);
         }
+        DatabaseResponse::Bool { value } => {
+            println!("{}", value);
+        }
+        DatabaseResponse::ScanPage { cursor, keys } => {
+            println!("cursor: {}", cursor);
+            for key in keys {
+                println!("{}", key);
+            }
+        }
         DatabaseResponse::Keys { keys } => {
             if keys.is_empty() {
                 println!("(empty)");
@@ -146,13 +1331,211 @@ pub async fn run_client_command(addr: &str, command: DatabaseCommand) -> Result<
                 }
             }
         }
+        DatabaseResponse::Values { values } => {
+            for value in values {
+                match value {
+                    Some(v) if v.is_empty() => println!("\"\""),
+                    Some(v) => println!("{}", v),
+                    None => println!("(null)"),
+                }
+            }
+        }
+        DatabaseResponse::Pairs { pairs } => {
+            if pairs.is_empty() {
+                println!("(empty)");
+            } else {
+                for (field, value) in pairs {
+                    println!("{}: {}", field, value);
+                }
+            }
+        }
         DatabaseResponse::Len { count } => {
             println!("{}", count);
         }
+        DatabaseResponse::Ttl { seconds } => {
+            println!("{}", seconds);
+        }
+        DatabaseResponse::Age { seconds } => {
+            println!("{}", seconds);
+        }
         DatabaseResponse::Pong => {
             println!("PONG");
         }
+        DatabaseResponse::Queued => {
+            println!("QUEUED");
+        }
+        DatabaseResponse::Results { responses } => {
+            for response in responses {
+                print_response(response);
+            }
+        }
+        DatabaseResponse::Stats { stats } => {
+            println!("commands: {}", stats.commands);
+            println!("reads: {}", stats.reads);
+            println!("writes: {}", stats.writes);
+            println!("get_hits: {}", stats.get_hits);
+            println!("get_misses: {}", stats.get_misses);
+            println!("sets: {}", stats.sets);
+            println!("deletes: {}", stats.deletes);
+        }
+        DatabaseResponse::Health { health } => {
+            println!("loaded: {}", health.loaded);
+            println!("keys: {}", health.keys);
+        }
+        DatabaseResponse::Hello { protocol } => {
+            println!("protocol: {}", protocol);
+        }
+        DatabaseResponse::CommandInfo { version, protocol_version, commands } => {
+            println!("version: {}", version);
+            println!("protocol: {}", protocol_version);
+            for command in commands {
+                println!("{}", command);
+            }
+        }
+        DatabaseResponse::Persistence { persistence } => {
+            println!("backend: {}", persistence.backend);
+            println!("path: {}", persistence.path);
+            println!("format: {}", persistence.format);
+            println!("compressed: {}", persistence.compressed);
+            match persistence.last_save_at {
+                Some(at) => {
+                    let ago = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |now| now.as_secs().saturating_sub(at));
+                    println!("last_save_at: {} ({}s ago)", at, ago);
+                }
+                None => println!("last_save_at: never"),
+            }
+            println!("pending: {}", persistence.pending);
+        }
+        DatabaseResponse::SlowLog { entries } => {
+            if entries.is_empty() {
+                println!("(empty)");
+            }
+            for entry in entries {
+                println!("{} {} {} {}us", entry.id, entry.timestamp, entry.command, entry.duration_micros);
+            }
+        }
+        DatabaseResponse::Message { channel, payload } => {
+            println!("{}: {}", channel, payload);
+        }
+        DatabaseResponse::KeyChanged { key, value } => {
+            println!("{}: {}", key, value.as_deref().unwrap_or("(deleted)"));
+        }
+        DatabaseResponse::Snapshot { data } => {
+            println!("{}", data);
+        }
+        DatabaseResponse::Replicated { command } => {
+            println!("{}: {:?}", command.db, command.command);
+        }
+        DatabaseResponse::Tagged { response, .. } => print_response(*response),
     }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::framing::write_frame;
+    use tokio::io::DuplexStream;
+
+    /// A client whose server answers each command with the next of `responses`
+    fn scripted(responses: Vec<DatabaseResponse>) -> DatabaseClient<DuplexStream> {
+        let (client_side, mut server_side) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            for response in responses {
+                if read_frame(&mut server_side).await.ok().flatten().is_none() {
+                    return;
+                }
+                let frame = serde_json::to_vec(&response).unwrap();
+                write_frame(&mut server_side, &frame).await.unwrap();
+            }
+        });
+        DatabaseClient::from_stream(client_side)
+    }
+
+    #[tokio::test]
+    async fn ttl_maps_the_servers_sentinels() {
+        let client = scripted(vec![
+            DatabaseResponse::Ttl { seconds: 30 },
+            DatabaseResponse::Ttl { seconds: -1 },
+            DatabaseResponse::Ttl { seconds: -2 },
+        ]);
+        assert_eq!(client.ttl("session").await.unwrap(), Some(Duration::from_secs(30)));
+        assert_eq!(client.ttl("forever").await.unwrap(), None);
+        match client.ttl("missing").await {
+            Err(ClientError::KeyNotFound { key }) => assert_eq!(key, "missing"),
+            other => panic!("expected KeyNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ttls_round_up_to_whole_seconds() {
+        assert_eq!(ceil_secs(Duration::from_secs(2)), 2);
+        assert_eq!(ceil_secs(Duration::from_millis(1)), 1);
+        assert_eq!(ceil_secs(Duration::from_millis(1500)), 2);
+        assert_eq!(ceil_secs(Duration::ZERO), 0);
+    }
+
+
+    /// A server on a fresh duplex stream that echoes each `Get` key back as its value,
+    /// taking `slow` to answer the key "slow"
+    fn echo_server(slow: Duration) -> DuplexStream {
+        let (client_side, mut server_side) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            while let Ok(Some(request)) = read_frame(&mut server_side).await {
+                let response = match serde_json::from_slice(&request).unwrap() {
+                    DatabaseCommand::Get { key } => {
+                        if key == "slow" {
+                            tokio::time::sleep(slow).await;
+                        }
+                        DatabaseResponse::Ok { value: Some(key) }
+                    }
+                    DatabaseCommand::Hello { protocol } => DatabaseResponse::Hello { protocol },
+                    _ => DatabaseResponse::error("ERR unexpected command"),
+                };
+                let frame = serde_json::to_vec(&response).unwrap();
+                if write_frame(&mut server_side, &frame).await.is_err() {
+                    return;
+                }
+            }
+        });
+        client_side
+    }
+
+    #[tokio::test]
+    async fn a_late_reply_is_not_taken_by_another_clone() {
+        let connects = Arc::new(AtomicU32::new(0));
+        let counter = connects.clone();
+        let connector: Connector<DuplexStream> = Box::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Ok(echo_server(Duration::from_millis(300))) })
+        });
+        let client = DatabaseClient::spawn(echo_server(Duration::from_millis(300)), Some(connector));
+        let mut impatient = client.clone();
+        impatient.set_read_timeout(Some(Duration::from_millis(50)));
+
+        assert!(matches!(impatient.get("slow").await, Err(ClientError::ReadTimeout(_))));
+        // The reply to "slow" arrives on the old connection, which is replaced
+        assert_eq!(client.get("fast").await.unwrap().as_deref(), Some("fast"));
+        assert_eq!(client.get("again").await.unwrap().as_deref(), Some("again"));
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_connection_fails_every_later_command() {
+        let client = DatabaseClient::from_stream(echo_server(Duration::from_millis(300)));
+        let mut impatient = client.clone();
+        impatient.set_read_timeout(Some(Duration::from_millis(50)));
+
+        assert!(matches!(impatient.get("slow").await, Err(ClientError::ReadTimeout(_))));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        for _ in 0..2 {
+            match client.get("fast").await {
+                Err(ClientError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotConnected),
+                other => panic!("expected the connection to be closed, got {:?}", other),
+            }
+        }
+        let responses = client.pipeline(vec![DatabaseCommand::Get { key: "fast".to_string() }]).await;
+        assert!(matches!(responses, Err(ClientError::Io(_))));
+    }
 }
@@ -0,0 +1,314 @@
+//! Synchronous client for code without an async runtime, behind the `blocking` feature
+
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::runtime::{Builder, Runtime};
+
+use super::{DatabaseClient, Result};
+use crate::protocol::{DatabaseCommand, DatabaseResponse};
+
+/// A [`DatabaseClient`] driven by its own single-threaded Tokio runtime, so each
+/// method blocks the calling thread until the server answers.
+///
+/// The runtime is created with the client and shut down when it is dropped, which
+/// also closes the connection. The connection's background task only runs while a
+/// method is blocking, so nothing is read or sent between calls.
+///
+/// Methods must not be called from within an async runtime, where blocking would
+/// stall its other tasks; Tokio panics if they are. Use [`DatabaseClient`] there.
+pub struct BlockingClient<S = TcpStream> {
+    // Dropped before the runtime it was spawned on
+    client: DatabaseClient<S>,
+    runtime: Runtime,
+}
+
+impl BlockingClient {
+    pub fn new(addr: &str) -> Result<Self> {
+        let runtime = new_runtime()?;
+        let client = runtime.block_on(DatabaseClient::new(addr))?;
+        Ok(Self { client, runtime })
+    }
+
+    /// Connect, giving up with [`ClientError::ConnectTimeout`] after `limit`
+    ///
+    /// [`ClientError::ConnectTimeout`]: crate::error::ClientError::ConnectTimeout
+    pub fn connect_with_timeout(addr: &str, limit: Duration) -> Result<Self> {
+        let runtime = new_runtime()?;
+        let client = runtime.block_on(DatabaseClient::connect_with_timeout(addr, limit))?;
+        Ok(Self { client, runtime })
+    }
+
+    /// Connect in reconnecting mode; see [`DatabaseClient::new_reconnecting`]
+    pub fn new_reconnecting(addr: &str) -> Result<Self> {
+        let runtime = new_runtime()?;
+        let client = runtime.block_on(DatabaseClient::new_reconnecting(addr))?;
+        Ok(Self { client, runtime })
+    }
+}
+
+#[cfg(unix)]
+impl BlockingClient<UnixStream> {
+    /// Connect to a server listening on a Unix domain socket
+    pub fn new_unix<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let runtime = new_runtime()?;
+        let client = runtime.block_on(DatabaseClient::new_unix(path))?;
+        Ok(Self { client, runtime })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> BlockingClient<S> {
+    /// Run any of the async client's methods to completion, for those without a
+    /// blocking wrapper here:
+    ///
+    /// `client.run(|c| c.hset("user:1", "name", "Ada"))`
+    pub fn run<'a, F, T>(&'a self, call: impl FnOnce(&'a DatabaseClient<S>) -> F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        self.runtime.block_on(call(&self.client))
+    }
+
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.client.set_read_timeout(read_timeout);
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.client.read_timeout()
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.get(key))
+    }
+
+    /// Set a key, returning its previous value
+    pub fn set(&self, key: &str, value: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.set(key, value))
+    }
+
+    pub fn set_ex(&self, key: &str, value: &str, ttl: Duration) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.set_ex(key, value, ttl))
+    }
+
+    pub fn set_nx(&self, key: &str, value: &str) -> Result<bool> {
+        self.runtime.block_on(self.client.set_nx(key, value))
+    }
+
+    pub fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        self.runtime.block_on(self.client.expire(key, ttl))
+    }
+
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        self.runtime.block_on(self.client.ttl(key))
+    }
+
+    pub fn mset(&self, pairs: &[(&str, &str)]) -> Result<()> {
+        self.runtime.block_on(self.client.mset(pairs))
+    }
+
+    pub fn mget(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        self.runtime.block_on(self.client.mget(keys))
+    }
+
+    pub fn append(&self, key: &str, value: &str) -> Result<usize> {
+        self.runtime.block_on(self.client.append(key, value))
+    }
+
+    pub fn strlen(&self, key: &str) -> Result<usize> {
+        self.runtime.block_on(self.client.strlen(key))
+    }
+
+    pub fn incr(&self, key: &str) -> Result<i64> {
+        self.runtime.block_on(self.client.incr(key))
+    }
+
+    pub fn decr(&self, key: &str) -> Result<i64> {
+        self.runtime.block_on(self.client.decr(key))
+    }
+
+    pub fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        self.runtime.block_on(self.client.incr_by(key, delta))
+    }
+
+    pub fn lpush(&self, key: &str, values: &[&str]) -> Result<usize> {
+        self.runtime.block_on(self.client.lpush(key, values))
+    }
+
+    pub fn rpush(&self, key: &str, values: &[&str]) -> Result<usize> {
+        self.runtime.block_on(self.client.rpush(key, values))
+    }
+
+    pub fn lpop(&self, key: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.lpop(key))
+    }
+
+    pub fn rpop(&self, key: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.rpop(key))
+    }
+
+    pub fn llen(&self, key: &str) -> Result<usize> {
+        self.runtime.block_on(self.client.llen(key))
+    }
+
+    pub fn hset(&self, key: &str, field: &str, value: &str) -> Result<bool> {
+        self.runtime.block_on(self.client.hset(key, field, value))
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.hget(key, field))
+    }
+
+    pub fn hdel(&self, key: &str, field: &str) -> Result<bool> {
+        self.runtime.block_on(self.client.hdel(key, field))
+    }
+
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>> {
+        self.runtime.block_on(self.client.hgetall(key))
+    }
+
+    /// Delete a key, returning its value
+    pub fn delete(&self, key: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.delete(key))
+    }
+
+    pub fn exists(&self, key: &str) -> Result<bool> {
+        self.runtime.block_on(self.client.exists(key))
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>> {
+        self.runtime.block_on(self.client.keys())
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        self.runtime.block_on(self.client.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        self.runtime.block_on(self.client.is_empty())
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.runtime.block_on(self.client.clear())
+    }
+
+    pub fn pipeline(&self, commands: Vec<DatabaseCommand>) -> Result<Vec<DatabaseResponse>> {
+        self.runtime.block_on(self.client.pipeline(commands))
+    }
+
+    pub fn transaction(&self, commands: Vec<DatabaseCommand>) -> Result<Vec<DatabaseResponse>> {
+        self.runtime.block_on(self.client.transaction(commands))
+    }
+
+    pub fn auth(&self, password: &str) -> Result<()> {
+        self.runtime.block_on(self.client.auth(password))
+    }
+
+    pub fn select(&self, index: usize) -> Result<()> {
+        self.runtime.block_on(self.client.select(index))
+    }
+
+    pub fn ping(&self) -> Result<()> {
+        self.runtime.block_on(self.client.ping())
+    }
+
+    pub fn publish(&self, channel: &str, message: &str) -> Result<usize> {
+        self.runtime.block_on(self.client.publish(channel, message))
+    }
+}
+
+/// The runtime behind one client: a single thread is plenty for one connection
+fn new_runtime() -> Result<Runtime> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use std::thread;
+
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::error::ServerError;
+    use crate::server::MiniDatabase;
+    use crate::store::StorageBackend;
+
+    /// A server running on its own multi-threaded runtime, as a synchronous caller
+    /// would have it, stopped by [`Background::stop`]
+    struct Background {
+        runtime: Runtime,
+        addr: String,
+        shutdown: oneshot::Sender<()>,
+        serving: JoinHandle<std::result::Result<(), ServerError>>,
+    }
+
+    impl Background {
+        fn start() -> Self {
+            let runtime = Runtime::new().unwrap();
+            let server = runtime
+                .block_on(MiniDatabase::new(String::new()).with_backend(StorageBackend::Memory).bind("127.0.0.1:0"))
+                .unwrap();
+            let addr = server.local_addr().to_string();
+            let (shutdown, rx) = oneshot::channel();
+            let serving = runtime.spawn(server.serve_with_shutdown(rx));
+            Self { runtime, addr, shutdown, serving }
+        }
+
+        fn stop(self) {
+            let _ = self.shutdown.send(());
+            self.runtime.block_on(self.serving).unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn set_and_get_from_plain_threads() {
+        let server = Background::start();
+        let client = BlockingClient::new(&server.addr).unwrap();
+
+        assert_eq!(client.set("greeting", "héllo").unwrap(), None);
+        assert_eq!(client.get("greeting").unwrap().as_deref(), Some("héllo"));
+        assert_eq!(client.set("greeting", "hi").unwrap().as_deref(), Some("héllo"));
+        assert_eq!(client.get("missing").unwrap(), None);
+        assert_eq!(client.strlen("greeting").unwrap(), 2);
+
+        // Each thread brings its own client, and so its own runtime
+        let writers: Vec<_> = (0..4)
+            .map(|n| {
+                let addr = server.addr.clone();
+                thread::spawn(move || {
+                    let client = BlockingClient::new(&addr).unwrap();
+                    client.set(&format!("thread:{}", n), &n.to_string()).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for n in 0..4 {
+            assert_eq!(client.get(&format!("thread:{}", n)).unwrap(), Some(n.to_string()));
+        }
+        assert_eq!(client.len().unwrap(), 5);
+
+        drop(client);
+        server.stop();
+    }
+
+    #[test]
+    fn run_drives_methods_without_a_wrapper() {
+        let server = Background::start();
+        let client = BlockingClient::new(&server.addr).unwrap();
+
+        client.set("key", "value").unwrap();
+        let (previous, deleted) = client.run(|c| c.delete_detailed("key")).unwrap();
+        assert_eq!(previous.as_deref(), Some("value"));
+        assert!(deleted);
+        assert!(!client.exists("key").unwrap());
+
+        drop(client);
+        server.stop();
+    }
+}
@@ -0,0 +1,369 @@
+//! The task that owns a client's connection and runs its requests one at a time
+
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+
+use super::{ClientError, Result};
+use crate::protocol::{DatabaseCommand, DatabaseResponse, MIN_PROTOCOL_VERSION};
+use crate::protocol::framing::{read_frame, write_frame};
+
+/// Backoff between reconnect attempts starts here and doubles up to the cap
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+const RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Requests that may wait for the connection task before senders are held up
+const REQUEST_QUEUE_LEN: usize = 64;
+
+/// Most queued commands written to the server before their responses are read
+const MAX_BATCH: usize = 64;
+
+/// Opens a fresh stream to the server for a reconnecting client
+pub(super) type Connector<S> = Box<dyn Fn() -> Pin<Box<dyn Future<Output = io::Result<S>> + Send>> + Send + Sync>;
+
+/// A command's frame, read timeout and reply channel, waiting in a batch
+type QueuedCommand = (Vec<u8>, Option<Duration>, oneshot::Sender<Result<DatabaseResponse>>);
+
+/// Work for the connection task, each answered on its `reply` channel
+pub(super) enum Request<S> {
+    /// Send one command and wait for its response, redialing once if the connection drops
+    Command {
+        frame: Vec<u8>,
+        read_timeout: Option<Duration>,
+        reply: oneshot::Sender<Result<DatabaseResponse>>,
+    },
+    /// Send every frame without waiting, then collect the responses in order
+    Pipeline {
+        frames: Vec<Vec<u8>>,
+        read_timeout: Option<Duration>,
+        reply: oneshot::Sender<Result<Vec<DatabaseResponse>>>,
+    },
+    /// Run the commands between MULTI and EXEC with nothing else sent in between
+    Transaction {
+        frames: Vec<Vec<u8>>,
+        read_timeout: Option<Duration>,
+        reply: oneshot::Sender<Result<Vec<DatabaseResponse>>>,
+    },
+    /// Stop and hand the stream back, e.g. to read pushed messages from it
+    Detach { reply: oneshot::Sender<S> },
+}
+
+/// Start the task that owns `stream`, returning the queue to send it requests on.
+///
+/// The task stops, closing the stream, once every sender has been dropped.
+pub(super) fn spawn<S>(stream: S, connector: Option<Connector<S>>, protocol: Arc<AtomicU32>) -> mpsc::Sender<Request<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (requests, receiver) = mpsc::channel(REQUEST_QUEUE_LEN);
+    let connection = Connection { stream, connector, protocol, broken: false };
+    tokio::spawn(connection.run(receiver));
+    requests
+}
+
+/// Queue a request built around a reply channel and wait for the answer
+pub(super) async fn call<S, T>(
+    requests: &mpsc::Sender<Request<S>>,
+    request: impl FnOnce(oneshot::Sender<Result<T>>) -> Request<S>,
+) -> Result<T> {
+    let (reply, response) = oneshot::channel();
+    requests.send(request(reply)).await.map_err(|_| closed())?;
+    response.await.map_err(|_| closed())?
+}
+
+/// The connection task has stopped, e.g. after the stream was handed to a subscription
+pub(super) fn closed() -> ClientError {
+    ClientError::Io(io::Error::new(ErrorKind::NotConnected, "Connection closed"))
+}
+
+/// The error for a command sent after the connection fell out of step and could not
+/// be replaced
+fn out_of_step() -> ClientError {
+    ClientError::Io(io::Error::new(ErrorKind::NotConnected, "Connection closed after an earlier request failed"))
+}
+
+/// Whether `e` may have left part of a frame, or a late response, on the stream
+fn leaves_stream_out_of_step(e: &ClientError) -> bool {
+    matches!(e, ClientError::ReadTimeout(_) | ClientError::Io(_))
+}
+
+/// The error for a command still waiting on a connection that has failed with `e`
+fn unanswered_after(e: &ClientError) -> ClientError {
+    match e {
+        ClientError::ReadTimeout(limit) => ClientError::ReadTimeout(*limit),
+        ClientError::Io(e) => ClientError::Io(io::Error::new(e.kind(), "Connection failed before the response arrived")),
+        _ => closed(),
+    }
+}
+
+/// Encode `command` as a frame payload
+pub(super) fn frame(command: &DatabaseCommand) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(command)?)
+}
+
+/// Turn anything but `Ok` into an error
+pub(super) fn expect_ok(response: DatabaseResponse) -> Result<()> {
+    match response {
+        DatabaseResponse::Ok { .. } => Ok(()),
+        DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+        _ => Err(ClientError::unexpected_response()),
+    }
+}
+
+/// The version a `Hello` response agreed to; servers from before the handshake
+/// answer with an error and are taken to speak the oldest version
+pub(super) fn agreed_protocol(response: DatabaseResponse) -> u32 {
+    match response {
+        DatabaseResponse::Hello { protocol } => protocol,
+        _ => MIN_PROTOCOL_VERSION,
+    }
+}
+
+/// A stream and what is needed to replace it, owned by the connection task
+struct Connection<S> {
+    stream: S,
+    /// Set for reconnecting clients, which redial when the connection drops
+    connector: Option<Connector<S>>,
+    /// Protocol version agreed with the server, shared with the client handle so a
+    /// new connection can ask for the same one
+    protocol: Arc<AtomicU32>,
+    /// Set once a read or write stops partway, after which what is left on the
+    /// stream, e.g. a late response, would be taken as the answer to the next command
+    broken: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    /// Commands queued back to back, typically by clones of one client, are written
+    /// together and their responses read in the same order, as in a pipeline
+    async fn run(mut self, mut requests: mpsc::Receiver<Request<S>>) {
+        let mut next = None;
+        loop {
+            let Some(request) = next.take().or(requests.recv().await) else {
+                return;
+            };
+            match request {
+                Request::Command { frame, read_timeout, reply } => {
+                    let mut batch = vec![(frame, read_timeout, reply)];
+                    while batch.len() < MAX_BATCH {
+                        match requests.try_recv() {
+                            Ok(Request::Command { frame, read_timeout, reply }) => batch.push((frame, read_timeout, reply)),
+                            Ok(other) => {
+                                next = Some(other);
+                                break;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    self.run_batch(batch).await;
+                }
+                Request::Pipeline { frames, read_timeout, reply } => {
+                    let _ = reply.send(self.pipeline(&frames, read_timeout).await);
+                }
+                Request::Transaction { frames, read_timeout, reply } => {
+                    let _ = reply.send(self.transaction(&frames, read_timeout).await);
+                }
+                Request::Detach { reply } => {
+                    let _ = reply.send(self.stream);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Each command in a batch is answered on its own; one that lost its connection
+    /// is retried like a lone command would be
+    async fn run_batch(&mut self, batch: Vec<QueuedCommand>) {
+        // Sent one by one, so the first replaces an out-of-step stream if it can
+        if batch.len() == 1 || self.broken {
+            for (frame, read_timeout, reply) in batch {
+                let _ = reply.send(self.send(&frame, read_timeout).await);
+            }
+            return;
+        }
+
+        let frames: Vec<_> = batch.iter().map(|(frame, read_timeout, _)| (frame.as_slice(), *read_timeout)).collect();
+        let results = self.exchange(&frames).await;
+        for ((frame, read_timeout, reply), result) in batch.into_iter().zip(results) {
+            let result = match result {
+                Err(e) if self.connector.is_some() && e.is_disconnect() => self.send(&frame, read_timeout).await,
+                result => result,
+            };
+            let _ = reply.send(result);
+        }
+    }
+
+    async fn send(&mut self, frame: &[u8], read_timeout: Option<Duration>) -> Result<DatabaseResponse> {
+        self.ensure_in_step(read_timeout).await?;
+        match self.round_trip(frame, read_timeout).await {
+            Err(e) if self.connector.is_some() && e.is_disconnect() => {
+                warn!("Connection lost ({}), reconnecting", e);
+                self.reconnect(read_timeout).await?;
+                self.round_trip(frame, read_timeout).await
+            }
+            result => result,
+        }
+    }
+
+    /// Replace a stream left out of step by an earlier failure. Only reconnecting
+    /// clients can; every other client fails each command from then on, whichever
+    /// clone sends it.
+    async fn ensure_in_step(&mut self, read_timeout: Option<Duration>) -> Result<()> {
+        if !self.broken {
+            return Ok(());
+        }
+        if self.connector.is_none() {
+            return Err(out_of_step());
+        }
+        warn!("Connection out of step after a failed request, reconnecting");
+        self.reconnect(read_timeout).await
+    }
+
+    /// Open a new stream with the connector, backing off between failed attempts
+    async fn reconnect(&mut self, read_timeout: Option<Duration>) -> Result<()> {
+        let Some(connector) = self.connector.as_ref() else {
+            return Err(ClientError::Protocol("Client is not in reconnecting mode".to_string()));
+        };
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match connector().await {
+                Ok(stream) => {
+                    info!("Reconnected to database server");
+                    self.stream = stream;
+                    self.broken = false;
+                    // A new connection starts over, so agree on the same version again
+                    let protocol = self.protocol.load(Ordering::Relaxed);
+                    let hello = frame(&DatabaseCommand::Hello { protocol })?;
+                    let agreed = agreed_protocol(self.round_trip(&hello, read_timeout).await?);
+                    self.protocol.store(agreed, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(e) if attempt < RECONNECT_ATTEMPTS => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, e);
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    attempt += 1;
+                }
+                Err(e) => return Err(ClientError::ConnectionFailed(e)),
+            }
+        }
+    }
+
+    async fn round_trip(&mut self, frame: &[u8], read_timeout: Option<Duration>) -> Result<DatabaseResponse> {
+        let result = match write_frame(&mut self.stream, frame).await {
+            Ok(()) => read_response(&mut self.stream, read_timeout).await,
+            Err(e) => Err(e.into()),
+        };
+        if result.as_ref().is_err_and(leaves_stream_out_of_step) {
+            self.broken = true;
+        }
+        result
+    }
+
+    /// Never retried after a reconnect
+    async fn pipeline(&mut self, frames: &[Vec<u8>], read_timeout: Option<Duration>) -> Result<Vec<DatabaseResponse>> {
+        self.ensure_in_step(read_timeout).await?;
+        let frames: Vec<_> = frames.iter().map(|frame| (frame.as_slice(), read_timeout)).collect();
+        self.exchange(&frames).await.into_iter().collect()
+    }
+
+    /// Write every frame and read one response for each, in order. Responses are read
+    /// while frames are still being written so a large batch cannot fill both socket
+    /// buffers and stall. A failed read or write leaves the connection out of step.
+    async fn exchange(&mut self, frames: &[(&[u8], Option<Duration>)]) -> Vec<Result<DatabaseResponse>> {
+        let (responses, in_step) = self.exchange_frames(frames).await;
+        self.broken |= !in_step;
+        responses
+    }
+
+    /// The responses, and whether the stream is still in step after them
+    async fn exchange_frames(&mut self, frames: &[(&[u8], Option<Duration>)]) -> (Vec<Result<DatabaseResponse>>, bool) {
+        let (mut reader, mut writer) = tokio::io::split(&mut self.stream);
+
+        let write_all = async {
+            for (frame, _) in frames {
+                write_frame(&mut writer, frame).await?;
+            }
+            Ok::<_, ClientError>(())
+        };
+        let read_all = async {
+            let mut responses = Vec::with_capacity(frames.len());
+            for (_, read_timeout) in frames {
+                let response = read_response(&mut reader, *read_timeout).await;
+                // Nothing after a failed read can be trusted to line up with its command
+                if let Err(e) = &response {
+                    let unanswered = frames.len() - responses.len() - 1;
+                    let rest: Vec<_> = (0..unanswered).map(|_| Err(unanswered_after(e))).collect();
+                    responses.push(response);
+                    responses.extend(rest);
+                    break;
+                }
+                responses.push(response);
+            }
+            responses
+        };
+
+        // A failed write leaves the connection unusable, which the reads then report.
+        // Reads that fail abandon any frames still being written; ones that succeed
+        // mean the server has every frame, so the writes are left to finish.
+        tokio::pin!(write_all, read_all);
+        let mut written = None;
+        let responses = loop {
+            tokio::select! {
+                responses = &mut read_all => break responses,
+                result = &mut write_all, if written.is_none() => written = Some(result),
+            }
+        };
+        if responses.iter().any(Result::is_err) {
+            return (responses, false);
+        }
+        let written = match written {
+            Some(result) => result,
+            None => write_all.await,
+        };
+        (responses, written.is_ok())
+    }
+
+    async fn transaction(&mut self, frames: &[Vec<u8>], read_timeout: Option<Duration>) -> Result<Vec<DatabaseResponse>> {
+        expect_ok(self.send(&frame(&DatabaseCommand::Multi)?, read_timeout).await?)?;
+        for command in frames {
+            match self.send(command, read_timeout).await? {
+                DatabaseResponse::Queued => {}
+                DatabaseResponse::Error { message } => {
+                    expect_ok(self.send(&frame(&DatabaseCommand::Discard)?, read_timeout).await?)?;
+                    return Err(ClientError::Server { message });
+                }
+                _ => return Err(ClientError::unexpected_response()),
+            }
+        }
+
+        match self.send(&frame(&DatabaseCommand::Exec)?, read_timeout).await? {
+            DatabaseResponse::Results { responses } => Ok(responses),
+            DatabaseResponse::Error { message } => Err(ClientError::Server { message }),
+            _ => Err(ClientError::unexpected_response()),
+        }
+    }
+}
+
+/// Read one response frame, failing with [`ClientError::ReadTimeout`] after `read_timeout`
+async fn read_response<R: AsyncRead + Unpin>(stream: &mut R, read_timeout: Option<Duration>) -> Result<DatabaseResponse> {
+    let response_data = match read_timeout {
+        Some(limit) => timeout(limit, read_frame(stream))
+            .await
+            .map_err(|_| ClientError::ReadTimeout(limit))??,
+        None => read_frame(stream).await?,
+    }
+    .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Connection closed by server"))?;
+    let response: DatabaseResponse = serde_json::from_slice(&response_data)?;
+
+    Ok(response)
+}
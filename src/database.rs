@@ -1,5 +1,9 @@
 //! Database trait definition
 
+use anyhow::Result;
+
+use crate::protocol::{SetOptions, SetOutcome};
+
 /// Database trait for key-value storage operations
 pub trait Database<K, V> {
     fn get(&self, key: &K) -> Option<V>;
@@ -8,5 +12,64 @@ pub trait Database<K, V> {
     fn exists(&self, key: &K) -> bool;
     fn keys(&self) -> Vec<K>;
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn clear(&mut self);
 }
+
+/// Everything the server runs against one database: the string operations of
+/// [`Database`] plus expiry, binary values, lists, hashes and key management.
+///
+/// Errors carry the message sent to the client, e.g. `WRONGTYPE ...` for an
+/// operation on a key holding another kind of value.
+pub trait TypedDatabase: Database<String, String> {
+    /// The string at `key`, failing if it holds a list or hash
+    fn get_string(&self, key: &str) -> Result<Option<String>>;
+    /// Bytes `start..=end` of the string at `key`; negative offsets count from the end
+    fn get_range(&self, key: &str, start: i64, end: i64) -> Result<Option<String>>;
+    fn strlen(&self, key: &str) -> Result<usize>;
+    fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// Name of the value's type, as reported by TYPE, or `none` for a missing key
+    fn type_of(&self, key: &str) -> &'static str;
+    /// Seconds left to live, -1 without an expiry and -2 for a missing key
+    fn ttl(&self, key: &str) -> i64;
+    fn key_age(&self, key: &str) -> Result<u64>;
+    fn random_key(&self) -> Option<String>;
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String>;
+    fn keys_matching(&self, pattern: &str) -> Vec<String>;
+    /// Up to `count` keys from `cursor`, with the cursor to continue from (0 when done)
+    fn scan_page(&self, cursor: u64, count: usize) -> (Vec<String>, u64);
+    fn list_len(&self, key: &str) -> Result<usize>;
+    fn hget(&self, key: &str, field: &str) -> Result<Option<String>>;
+    fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>>;
+    /// The whole database as a string that [`TypedDatabase::restore`] accepts
+    fn to_blob(&self) -> Result<String>;
+    fn byte_size(&self) -> usize;
+
+    fn set_with_options(&mut self, key: String, value: String, opts: SetOptions) -> Result<(SetOutcome, Option<String>)>;
+    fn set_bytes(&mut self, key: String, value: Vec<u8>);
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>>;
+    fn set_nx(&mut self, key: String, value: String) -> bool;
+    fn cas(&mut self, key: String, expected: Option<String>, new: String) -> Result<bool>;
+    fn set_with_expiry(&mut self, key: String, value: String, seconds: u64) -> Result<Option<String>>;
+    fn expire(&mut self, key: &str, seconds: u64) -> Result<bool>;
+    fn append(&mut self, key: &str, value: &str) -> Result<usize>;
+    fn set_range(&mut self, key: &str, offset: usize, value: &str) -> Result<usize>;
+    fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64>;
+    fn push_front(&mut self, key: &str, values: Vec<String>) -> Result<usize>;
+    fn push_back(&mut self, key: &str, values: Vec<String>) -> Result<usize>;
+    fn pop_front(&mut self, key: &str) -> Result<Option<String>>;
+    fn pop_back(&mut self, key: &str) -> Result<Option<String>>;
+    fn hset(&mut self, key: &str, field: String, value: String) -> Result<bool>;
+    fn hdel(&mut self, key: &str, field: &str) -> Result<bool>;
+    fn rename(&mut self, from: &str, to: &str) -> Result<()>;
+    fn rename_nx(&mut self, from: &str, to: &str) -> Result<bool>;
+    fn copy(&mut self, from: &str, to: &str, replace: bool) -> Result<bool>;
+    fn touch(&mut self, keys: &[String]) -> usize;
+    fn delete_many(&mut self, keys: &[String]) -> usize;
+    /// Replace the whole database with [`TypedDatabase::to_blob`] output
+    fn restore(&mut self, blob: &str) -> Result<()>;
+    /// Evict least-recently-used keys down to `max_keys`, returning them
+    fn evict_lru(&mut self, max_keys: usize) -> Vec<String>;
+}
@@ -0,0 +1,113 @@
+//! Error types returned by the client and server APIs
+
+use std::io::{self, ErrorKind};
+use std::num::ParseIntError;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Why a [`DatabaseClient`](crate::client::DatabaseClient) call failed
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The server could not be reached
+    #[error("Failed to connect")]
+    ConnectionFailed(#[source] io::Error),
+    /// The server did not accept the connection in time
+    #[error("Timed out connecting after {0:?}")]
+    ConnectTimeout(Duration),
+    /// The server did not respond in time
+    #[error("Timed out waiting for a response after {0:?}")]
+    ReadTimeout(Duration),
+    /// An established connection failed or was closed
+    #[error("Connection error")]
+    Io(#[from] io::Error),
+    /// A message could not be encoded or decoded, or the response did not fit the command
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// The command needs an existing key, e.g. reading a TTL
+    #[error("No such key: {key}")]
+    KeyNotFound { key: String },
+    /// The server answered with an error, e.g. `WRONGTYPE ...` or `ERR no such key`
+    #[error("Server error: {message}")]
+    Server { message: String },
+}
+
+impl ClientError {
+    /// The server sent a response of the wrong kind for the command
+    pub(crate) fn unexpected_response() -> Self {
+        Self::Protocol("Unexpected response type".to_string())
+    }
+
+    /// Whether the connection itself broke, as opposed to a bad response or a
+    /// command the server refused
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            Self::Io(e) if matches!(
+                e.kind(),
+                ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::UnexpectedEof
+            )
+        )
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Protocol(e.to_string())
+    }
+}
+
+impl From<ParseIntError> for ClientError {
+    fn from(e: ParseIntError) -> Self {
+        Self::Protocol(format!("Invalid integer in response: {}", e))
+    }
+}
+
+/// Framing errors are I/O failures unless the frame itself was malformed
+impl From<anyhow::Error> for ClientError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<io::Error>() {
+            Ok(e) => Self::Io(e),
+            Err(e) => Self::Protocol(e.to_string()),
+        }
+    }
+}
+
+/// Why a [`MiniDatabase`](crate::server::MiniDatabase) failed to start or stop
+#[derive(Debug, Error)]
+pub enum ServerError {
+    /// The listening socket could not be created
+    #[error("Failed to listen on {addr}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Another running server holds the storage file's lock
+    #[error("Storage file is in use by process {pid} (lock file {}); stop that server or force the lock", path.display())]
+    Locked { path: PathBuf, pid: u32 },
+    /// The storage file's lock file could not be created or read
+    #[error("Failed to lock the storage file")]
+    Lock(#[source] io::Error),
+    /// The storage file or WAL could not be loaded
+    #[error("Failed to load data")]
+    Load(#[source] anyhow::Error),
+    /// The final save on shutdown failed
+    #[error("Failed to save on shutdown")]
+    Save(#[source] anyhow::Error),
+    /// Serving a connection failed
+    #[error("Connection error")]
+    Connection(#[source] anyhow::Error),
+}
+
+/// Why a storage file could not be trusted. Loading returns it inside an
+/// `anyhow::Error`, so callers can pick it out with `downcast_ref`.
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// The file's checksum footer is missing or does not match its contents
+    #[error("Corrupt storage file: {reason}")]
+    CorruptFile { reason: String },
+}
@@ -1,12 +1,32 @@
 //! Mini Database Library
 
+pub mod backend;
+pub mod clock;
 pub mod database;
+pub mod error;
 pub mod store;
 pub mod protocol;
 pub mod server;
 pub mod client;
+pub mod wal;
+pub mod stats;
+pub mod pubsub;
+pub mod slowlog;
+pub mod metrics;
+pub mod lockfile;
+pub mod ratelimit;
+#[cfg(test)]
+mod testutil;
 
-pub use database::Database;
-pub use store::KeyValueStore;
-pub use protocol::{DatabaseCommand, DatabaseResponse};
-pub use server::MiniDatabase;
+pub use backend::{FileStore, MemoryStore};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use database::{Database, TypedDatabase};
+pub use error::{ClientError, ServerError, StorageError};
+pub use store::{Databases, KeyValueStore, StorageBackend, StorageFormat, StorageOptions};
+pub use protocol::{DatabaseCommand, DatabaseResponse, HealthStatus, PersistenceStatus, ReplicationCommand, SetOptions, SetOutcome, WireProtocol};
+pub use server::{BoundServer, ListenAddr, MiniDatabase};
+pub use wal::WriteAheadLog;
+pub use stats::StatsSnapshot;
+pub use slowlog::SlowLogEntry;
+pub use metrics::MetricsSnapshot;
+pub use pubsub::MutationEvent;
@@ -0,0 +1,182 @@
+//! Lock file that keeps two servers from saving over the same storage file
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use tracing::{info, warn};
+
+use crate::error::ServerError;
+
+/// Attempts to create the lock file before giving up on a holder that keeps
+/// reappearing, e.g. another server forcing the same lock at the same moment
+const ACQUIRE_ATTEMPTS: usize = 3;
+
+/// Tells apart the locks taken by one process, e.g. by two servers in one test
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+/// Locks held by this process, by canonical lock file path, with the token of the
+/// holder. A lock file naming this process's id but missing here was left by an
+/// earlier process that had the same id.
+static HELD: LazyLock<Mutex<HashMap<PathBuf, u64>>> = LazyLock::new(Default::default);
+
+/// Holds `<storage>.lock`, which records this process's id and a token unique to
+/// the lock, and removes it on drop
+#[derive(Debug)]
+pub struct StorageLock {
+    path: PathBuf,
+    /// `path` made absolute, the key into [`HELD`]
+    key: PathBuf,
+    token: u64,
+}
+
+impl StorageLock {
+    /// Take the lock on `storage_path` by creating its lock file exclusively.
+    ///
+    /// A lock whose process is no longer running is stale and replaced, as is one
+    /// holding this process's id but not taken by it, which happens when a container
+    /// restarts with the same PID. A lock held by another server in this process is
+    /// not. With `force` any existing lock is replaced.
+    pub fn acquire<P: AsRef<Path>>(storage_path: P, force: bool) -> Result<Self, ServerError> {
+        let mut path = storage_path.as_ref().as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(ServerError::Lock)?;
+        }
+
+        let mut holder = None;
+        for _ in 0..ACQUIRE_ATTEMPTS {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+                    writeln!(file, "{} {}", std::process::id(), token).map_err(ServerError::Lock)?;
+                    let key = fs::canonicalize(&path).map_err(ServerError::Lock)?;
+                    HELD.lock().unwrap().insert(key.clone(), token);
+                    info!("Locked storage file with {}", path.display());
+                    return Ok(Self { path, key, token });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(ServerError::Lock(e)),
+            }
+
+            holder = read_holder(&path).map(|(pid, _)| pid);
+            match holder {
+                Some(pid) if !force && holder_alive(&path, pid) => break,
+                Some(pid) if force => warn!("Forcing the lock held by process {} in {}", pid, path.display()),
+                Some(pid) => warn!("Removing stale lock left by process {} in {}", pid, path.display()),
+                // Unreadable, or a lock file whose creator died before writing to it
+                None => warn!("Removing lock file {} with no process id", path.display()),
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(ServerError::Lock(e)),
+            }
+        }
+
+        Err(ServerError::Locked { path, pid: holder.unwrap_or_default() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StorageLock {
+    /// Leaves the file alone if another server has since forced the lock away
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap();
+        if held.get(&self.key) == Some(&self.token) {
+            held.remove(&self.key);
+        }
+        if read_holder(&self.path) == Some((std::process::id(), Some(self.token))) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Failed to remove lock file {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// The process id in the lock file at `path`, and the token after it; files written
+/// before tokens were added hold only the id
+fn read_holder(path: &Path) -> Option<(u32, Option<u64>)> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut fields = contents.split_whitespace();
+    let pid = fields.next()?.parse().ok()?;
+    let token = fields.next().and_then(|token| token.parse().ok());
+    Some((pid, token))
+}
+
+/// Whether the lock at `path`, recorded by process `pid`, is still held
+fn holder_alive(path: &Path, pid: u32) -> bool {
+    if pid != std::process::id() {
+        return process_alive(pid);
+    }
+    fs::canonicalize(path).is_ok_and(|key| HELD.lock().unwrap().contains_key(&key))
+}
+
+/// Whether a process with this id is running. Only Linux's `/proc` is consulted;
+/// elsewhere every recorded holder is assumed alive and only `force` replaces it.
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn a_held_lock_refuses_a_second_server_in_the_same_process() {
+        let dir = TempDir::new();
+        let storage = dir.join("db.json");
+        let first = StorageLock::acquire(&storage, false).unwrap();
+
+        match StorageLock::acquire(&storage, false) {
+            Err(ServerError::Locked { pid, .. }) => assert_eq!(pid, std::process::id()),
+            other => panic!("expected Locked, got {:?}", other),
+        }
+        assert!(first.path().exists());
+
+        drop(first);
+        assert!(!dir.join("db.json.lock").exists());
+        let second = StorageLock::acquire(&storage, false).unwrap();
+        assert!(second.path().exists());
+    }
+
+    #[test]
+    fn a_forced_lock_is_not_removed_by_the_server_it_replaced() {
+        let dir = TempDir::new();
+        let storage = dir.join("db.json");
+        let first = StorageLock::acquire(&storage, false).unwrap();
+        let second = StorageLock::acquire(&storage, true).unwrap();
+
+        drop(first);
+        assert!(second.path().exists());
+        assert!(matches!(StorageLock::acquire(&storage, false), Err(ServerError::Locked { .. })));
+        drop(second);
+        assert!(!dir.join("db.json.lock").exists());
+    }
+
+    #[test]
+    fn a_lock_with_our_pid_that_we_do_not_hold_is_stale() {
+        let dir = TempDir::new();
+        let storage = dir.join("db.json");
+        // As left by an earlier process with the same id, with and without a token
+        for contents in [format!("{}\n", std::process::id()), format!("{} 0\n", std::process::id())] {
+            fs::write(dir.join("db.json.lock"), contents).unwrap();
+            let lock = StorageLock::acquire(&storage, false).unwrap();
+            drop(lock);
+            assert!(!dir.join("db.json.lock").exists());
+        }
+    }
+}
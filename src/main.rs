@@ -1,92 +1,752 @@
 //! Mini Database CLI
 
-use clap::{Parser, Subcommand};
-use tracing_subscriber;
-use anyhow::Result;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::Duration;
+use clap::{Parser, Subcommand, ValueEnum};
+use anyhow::{Context, Result};
 
-use kv_store::protocol::{DatabaseCommand};
-use kv_store::server::MiniDatabase;
-use kv_store::client::run_client_command;
+use kv_store::protocol::{DatabaseCommand, DatabaseResponse, SetOptions, WireProtocol};
+use kv_store::server::{ListenAddr, MiniDatabase};
+use kv_store::store::{Databases, StorageBackend, StorageFormat, StorageOptions};
+use kv_store::Database;
+use kv_store::client::{run_client_command, OutputFormat};
+#[cfg(unix)]
+use kv_store::client::run_unix_client_command;
 
 #[derive(Parser)]
 #[command(name = "mini-db")]
 #[command(about = "A mini database server (like a tiny Redis)")]
 #[command(version = "1.0.0")]
 struct Cli {
+    /// Log output format: text, or json for log collectors
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Commands {
+    /// Write one database from a storage file as CSV, without a running server
+    Export {
+        /// Storage file to read
+        #[arg(long, default_value = "mini-db.json")]
+        storage: String,
+        /// CSV file to write
+        #[arg(long)]
+        csv: String,
+        /// Database number to export
+        #[arg(long, default_value_t = 0)]
+        db: usize,
+    },
+    /// Load keys from a CSV or flat JSON object file into a storage file, without a running server
+    Import {
+        /// Storage file to update; created if missing
+        #[arg(long, default_value = "mini-db.json")]
+        storage: String,
+        /// Input file: .csv with key,value rows, or .json with a flat object
+        #[arg(long)]
+        from: String,
+        /// Remove existing keys in the database first
+        #[arg(long)]
+        replace: bool,
+        /// Database number to import into
+        #[arg(long, default_value_t = 0)]
+        db: usize,
+        /// On-disk format for the saved storage file: json or bincode
+        #[arg(long, default_value = "json")]
+        format: StorageFormat,
+        /// Gzip-compress the saved storage file
+        #[arg(long)]
+        compress: bool,
+        /// Save JSON without indentation
+        #[arg(long)]
+        compact: bool,
+        /// Add a checksum so a damaged file fails to load instead of loading partial data
+        #[arg(long)]
+        checksum: bool,
+    },
+    /// Rebuild a storage file as of a point in time by replaying a WAL, without a running server
+    Recover {
+        /// Write-ahead log to replay
+        #[arg(long)]
+        wal: String,
+        /// Replay commands logged up to and including this Unix time, in seconds
+        #[arg(long)]
+        until: u64,
+        /// Snapshot the log was written against; recovery starts empty without one
+        #[arg(long)]
+        storage: Option<String>,
+        /// Storage file to write the recovered databases to
+        #[arg(long)]
+        out: String,
+        /// On-disk format for the recovered file: json or bincode
+        #[arg(long, default_value = "json")]
+        format: StorageFormat,
+        /// Gzip-compress the recovered file
+        #[arg(long)]
+        compress: bool,
+        /// Save JSON without indentation
+        #[arg(long)]
+        compact: bool,
+        /// Add a checksum so a damaged file fails to load instead of loading partial data
+        #[arg(long)]
+        checksum: bool,
+    },
+    /// Read or edit a storage file directly, without a running server.
+    ///
+    /// Not coordinated with a server using the same file: stop the server before
+    /// running `set`, or its next save will overwrite the change.
+    Inspect {
+        /// Storage file to open
+        #[arg(long, default_value = "mini-db.json")]
+        storage: String,
+        /// Database number to use
+        #[arg(long, default_value_t = 0)]
+        db: usize,
+        /// On-disk format used when `set` saves the file: json or bincode
+        #[arg(long, default_value = "json")]
+        format: StorageFormat,
+        /// Gzip-compress the file when `set` saves it
+        #[arg(long)]
+        compress: bool,
+        /// Save JSON without indentation when `set` saves the file
+        #[arg(long)]
+        compact: bool,
+        /// Add a checksum so a damaged file fails to load instead of loading partial data
+        #[arg(long)]
+        checksum: bool,
+        #[command(subcommand)]
+        command: InspectCommands,
+    },
     /// Start the TCP server
     Server {
-        /// Address to bind to
-        #[arg(long, default_value = "127.0.0.1:8080")]
-        addr: String,
+        /// Address to bind to; 127.0.0.1:8080 unless only --unix-socket is given
+        #[arg(long)]
+        addr: Option<String>,
         /// Storage file path
         #[arg(long, default_value = "mini-db.json")]
         storage: String,
+        /// Storage backend: file, or memory to never read or write disk
+        #[arg(long, default_value = "file")]
+        backend: StorageBackend,
+        /// On-disk format for saves: json or bincode
+        #[arg(long, default_value = "json")]
+        format: StorageFormat,
+        /// Gzip-compress the storage file
+        #[arg(long)]
+        compress: bool,
+        /// Save JSON without indentation: smaller and faster to write than the default
+        #[arg(long)]
+        compact: bool,
+        /// Add a checksum so a damaged file fails to load instead of loading partial data
+        #[arg(long)]
+        checksum: bool,
+        /// Minimum delay between background saves, in milliseconds
+        #[arg(long, default_value_t = 500)]
+        flush_interval_ms: u64,
+        /// Also save as soon as this many writes accumulate since the last save
+        #[arg(long)]
+        save_after_writes: Option<usize>,
+        /// Write-ahead log path for crash durability between saves
+        #[arg(long)]
+        wal: Option<String>,
+        /// Require clients to authenticate with this password
+        #[arg(long)]
+        password: Option<String>,
+        /// Listen on this Unix domain socket, instead of TCP unless --addr is also given
+        #[arg(long)]
+        unix_socket: Option<String>,
+        /// Serve reads only and refuse every mutation
+        #[arg(long)]
+        read_only: bool,
+        /// Mirror the primary server at this address, serving reads only
+        #[arg(long)]
+        replica_of: Option<String>,
+        /// Password to authenticate with on the primary
+        #[arg(long, requires = "replica_of")]
+        primary_password: Option<String>,
+        /// Maximum keys per database, evicting the least recently used beyond it
+        #[arg(long)]
+        max_keys: Option<usize>,
+        /// With --max-keys, reject writes that would add keys instead of evicting
+        #[arg(long, requires = "max_keys")]
+        reject_over_capacity: bool,
+        /// Refuse writes creating keys longer than this many bytes
+        #[arg(long)]
+        max_key_size: Option<usize>,
+        /// Refuse writes carrying values longer than this many bytes
+        #[arg(long)]
+        max_value_size: Option<usize>,
+        /// Maximum concurrent connections; further ones wait until one closes
+        #[arg(long)]
+        max_connections: Option<usize>,
+        /// With --max-connections, reject connections beyond it with an error instead
+        #[arg(long, requires = "max_connections")]
+        reject_over_connections: bool,
+        /// Close connections that send no command for this many milliseconds
+        #[arg(long)]
+        idle_timeout_ms: Option<u64>,
+        /// Commands per second each client may send; extra ones get "ERR rate limited"
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        rate_limit: Option<u32>,
+        /// Serve Prometheus metrics over HTTP on this address, at /metrics
+        #[arg(long)]
+        metrics_addr: Option<String>,
+        /// Start even if the storage file's lock names a running process
+        #[arg(long)]
+        force: bool,
+        /// Wire protocol: framed (length-prefixed) or ndjson (one JSON value per line)
+        #[arg(long, default_value = "framed")]
+        protocol: WireProtocol,
+        /// Largest command frame accepted, in bytes
+        #[arg(long, default_value_t = 16 * 1024 * 1024)]
+        max_frame_size: usize,
+        /// Log commands slower than this many milliseconds
+        #[arg(long, default_value_t = 10)]
+        slow_log_threshold_ms: u64,
+        /// Number of slow commands kept for the slowlog command
+        #[arg(long, default_value_t = 128)]
+        slow_log_len: usize,
+        /// How often to sweep expired keys, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        sweep_interval_ms: u64,
+        /// Maximum expired keys removed per sweep batch
+        #[arg(long, default_value_t = 100)]
+        sweep_batch: usize,
     },
     /// Run a client command
     Client {
         /// Server address
         #[arg(long, default_value = "127.0.0.1:8080")]
         addr: String,
+        /// Password to authenticate with before running the command
+        #[arg(long)]
+        password: Option<String>,
+        /// Connect to this Unix domain socket instead of TCP
+        #[arg(long)]
+        unix_socket: Option<String>,
+        /// Database number to SELECT before running the command
+        #[arg(long, default_value_t = 0)]
+        db: usize,
+        /// Output format: plain, or json to print responses and errors as JSON
+        #[arg(long, global = true, default_value = "plain")]
+        output: OutputFormat,
         #[command(subcommand)]
         command: ClientCommands,
     },
 }
 
 #[derive(Subcommand)]
-enum ClientCommands {
+enum InspectCommands {
     /// Get a value by key
     Get { key: String },
-    /// Set a key-value pair
+    /// Set a key-value pair and save the file
     Set { key: String, value: String },
-    /// Delete a key
-    Delete { key: String },
-    /// Check if key exists
-    Exists { key: String },
     /// List all keys
     Keys,
     /// Get the number of keys
     Len,
+}
+
+#[derive(Subcommand)]
+enum ClientCommands {
+    /// Get a value by key
+    Get { key: String },
+    /// Set a key-value pair
+    Set {
+        key: String,
+        value: String,
+        /// Only set if the key does not exist
+        #[arg(long, conflicts_with = "xx")]
+        nx: bool,
+        /// Only set if the key already exists
+        #[arg(long)]
+        xx: bool,
+        /// Also print the previous value
+        #[arg(long)]
+        get: bool,
+    },
+    /// Store the contents of FILE, which may be binary, at a key
+    #[command(name = "setbytes")]
+    SetBytes { key: String, file: String },
+    /// Write a value's raw bytes to stdout
+    #[command(name = "getbytes")]
+    GetBytes { key: String },
+    /// Set a new value and print the previous one
+    #[command(name = "getset")]
+    GetSet { key: String, value: String },
+    /// Set a key-value pair only if the key does not exist
+    #[command(name = "setnx")]
+    SetNx { key: String, value: String },
+    /// Set a key to NEW only if it currently holds --expected (or is absent without it)
+    Cas {
+        key: String,
+        new: String,
+        #[arg(long)]
+        expected: Option<String>,
+    },
+    /// Set a key-value pair that expires after the given seconds
+    #[command(name = "setex")]
+    SetEx { key: String, value: String, seconds: u64 },
+    /// Set a key's time to live in seconds
+    Expire { key: String, seconds: u64 },
+    /// Get a key's remaining time to live in seconds
+    Ttl { key: String },
+    /// Get the seconds since a key's value last changed
+    #[command(name = "keyage")]
+    KeyAge { key: String },
+    /// Set multiple key-value pairs: KEY VALUE [KEY VALUE ...]
+    #[command(name = "mset")]
+    MSet {
+        #[arg(required = true)]
+        pairs: Vec<String>,
+    },
+    /// Get the values of multiple keys
+    #[command(name = "mget")]
+    MGet {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Append to a key's value and print the new length
+    Append { key: String, value: String },
+    /// Print bytes START..=END of a value; negative offsets count from the end
+    #[command(name = "getrange", allow_negative_numbers = true)]
+    GetRange { key: String, start: i64, end: i64 },
+    /// Overwrite a value from byte OFFSET and print the new length
+    #[command(name = "setrange")]
+    SetRange { key: String, offset: usize, value: String },
+    /// Print the length of a value in bytes
+    #[command(name = "strlen")]
+    StrLen { key: String },
+    /// Increment the integer value of a key by one
+    Incr { key: String },
+    /// Decrement the integer value of a key by one
+    Decr { key: String },
+    /// Increment the integer value of a key by the given amount
+    #[command(name = "incrby", allow_negative_numbers = true)]
+    IncrBy { key: String, delta: i64 },
+    /// Push values onto the head of a list
+    #[command(name = "lpush")]
+    LPush {
+        key: String,
+        #[arg(required = true)]
+        values: Vec<String>,
+    },
+    /// Push values onto the tail of a list
+    #[command(name = "rpush")]
+    RPush {
+        key: String,
+        #[arg(required = true)]
+        values: Vec<String>,
+    },
+    /// Pop a value from the head of a list
+    #[command(name = "lpop")]
+    LPop { key: String },
+    /// Pop a value from the tail of a list
+    #[command(name = "rpop")]
+    RPop { key: String },
+    /// Get the length of a list
+    #[command(name = "llen")]
+    LLen { key: String },
+    /// Set a field in a hash
+    #[command(name = "hset")]
+    HSet { key: String, field: String, value: String },
+    /// Get a field from a hash
+    #[command(name = "hget")]
+    HGet { key: String, field: String },
+    /// Delete a field from a hash
+    #[command(name = "hdel")]
+    HDel { key: String, field: String },
+    /// Get all fields and values of a hash
+    #[command(name = "hgetall")]
+    HGetAll { key: String },
+    /// Rename a key, replacing any value at the new name
+    Rename { from: String, to: String },
+    /// Rename a key only if the new name does not exist
+    #[command(name = "renamenx")]
+    RenameNx { from: String, to: String },
+    /// Copy a key's value and TTL to a new key
+    Copy {
+        from: String,
+        to: String,
+        /// Overwrite the destination if it exists
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Delete one or more keys
+    Delete {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Check if a key exists; with several, print how many exist
+    Exists {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Mark keys as recently used for LRU eviction and print how many exist
+    Touch {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Show the type of the value stored at a key
+    Type { key: String },
+    /// List all keys, or those matching a glob pattern (`*` and `?`)
+    Keys { pattern: Option<String> },
+    /// List keys starting with a prefix
+    Scan { prefix: String },
+    /// Print the next cursor and a page of keys; start at 0 and repeat until the cursor is 0
+    #[command(name = "scancursor")]
+    ScanCursor {
+        cursor: u64,
+        /// Keys per page
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Print a random existing key
+    #[command(name = "randomkey")]
+    RandomKey,
+    /// Get the number of keys
+    Len,
+    /// Print whether the database has no keys
+    #[command(name = "isempty")]
+    IsEmpty,
+    /// Get the total bytes of keys and values
+    #[command(name = "bytesize")]
+    ByteSize,
     /// Clear all data
     Clear,
+    /// Empty the selected database
+    #[command(name = "flushdb")]
+    FlushDb {
+        /// Wait until the empty database is saved and fsynced
+        #[arg(long)]
+        sync: bool,
+    },
+    /// Print the selected database as one JSON string, for restore
+    Dump,
+    /// Replace the selected database with the output of dump
+    Restore { data: String },
     /// Ping the server
     Ping,
+    /// Show whether the server has finished loading, and its key count
+    Health,
+    /// Ask for a protocol version and print the one the server agrees to
+    Hello { protocol: u32 },
+    /// Show the server version, protocol version and supported commands
+    Command,
+    /// Show operation counters since the server started
+    Stats,
+    /// Show the storage path and format, last save time, and whether a save is pending
+    Persistence,
+    /// Show recent commands slower than the server's threshold, newest first
+    #[command(name = "slowlog")]
+    SlowLog,
+    /// Replace the server's data with its storage file, e.g. after editing the file
+    Reload {
+        /// Discard writes the server has not saved yet instead of refusing
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print messages published to a channel until interrupted
+    Subscribe { channel: String },
+    /// Publish a message to a channel
+    Publish { channel: String, message: String },
+    /// Print each new value of a key (or a deletion) until interrupted
+    Watch { key: String },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
     let cli = Cli::parse();
+    // Logs go to stderr so `getbytes` and `dump` output can be redirected cleanly
+    let logs = tracing_subscriber::fmt().with_writer(std::io::stderr);
+    match cli.log_format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
 
     match cli.command {
-        Commands::Server { addr, storage } => {
+        Commands::Server { addr, storage, backend, format, compress, compact, checksum, flush_interval_ms, save_after_writes, wal, password, unix_socket, read_only, replica_of, primary_password, max_keys, reject_over_capacity, max_key_size, max_value_size, max_connections, reject_over_connections, idle_timeout_ms, rate_limit, metrics_addr, force, protocol, max_frame_size, slow_log_threshold_ms, slow_log_len, sweep_interval_ms, sweep_batch } => {
             println!("🚀 Starting mini database server...");
-            println!("📡 Listening on: {}", addr);
-            println!("💾 Storage file: {}", storage);
+            let addr = match (addr, &unix_socket) {
+                (Some(addr), _) => Some(addr),
+                (None, Some(_)) => None,
+                (None, None) => Some("127.0.0.1:8080".to_string()),
+            };
+            let mut listen: Vec<ListenAddr> = addr.map(ListenAddr::Tcp).into_iter().collect();
+            if let Some(path) = unix_socket {
+                #[cfg(unix)]
+                listen.push(ListenAddr::Unix(path.into()));
+                #[cfg(not(unix))]
+                anyhow::bail!("--unix-socket {} needs a platform with Unix domain sockets", path);
+            }
+            let shown: Vec<String> = listen.iter().map(ToString::to_string).collect();
+            println!("📡 Listening on: {}", shown.join(", "));
+            match backend {
+                StorageBackend::File => println!("💾 Storage file: {}", storage),
+                StorageBackend::Memory => println!("💾 Storage: memory only, nothing is saved"),
+            }
             println!("📝 Logs will appear below:");
             println!();
             
-            let db = MiniDatabase::new(storage);
-            db.start_server(&addr).await?;
+            let db = if read_only {
+                MiniDatabase::new_readonly(storage)
+            } else {
+                MiniDatabase::new(storage)
+            };
+            let mut db = db
+                .with_backend(backend)
+                .with_format(format)
+                .with_compression(compress)
+                .with_pretty_json(!compact)
+                .with_checksum(checksum)
+                .with_force_lock(force)
+                .with_flush_interval(Duration::from_millis(flush_interval_ms))
+                .with_protocol(protocol)
+                .with_max_frame_size(max_frame_size)
+                .with_expiry_sweep(Duration::from_millis(sweep_interval_ms), sweep_batch)
+                .with_slow_log(Duration::from_millis(slow_log_threshold_ms), slow_log_len);
+            if let Some(wal) = wal {
+                db = db.with_wal(wal);
+            }
+            if let Some(password) = password {
+                db = db.with_password(password);
+            }
+            if let Some(primary) = replica_of {
+                println!("🔁 Replicating from: {}", primary);
+                db = db.with_replica_of(primary);
+            }
+            if let Some(primary_password) = primary_password {
+                db = db.with_primary_password(primary_password);
+            }
+            if let Some(max_keys) = max_keys {
+                db = db
+                    .with_max_keys(max_keys)
+                    .with_reject_over_capacity(reject_over_capacity);
+            }
+            if let Some(save_after_writes) = save_after_writes {
+                db = db.with_save_after_writes(save_after_writes);
+            }
+            if let Some(max_key_size) = max_key_size {
+                db = db.with_max_key_size(max_key_size);
+            }
+            if let Some(max_value_size) = max_value_size {
+                db = db.with_max_value_size(max_value_size);
+            }
+            if let Some(max_connections) = max_connections {
+                db = db
+                    .with_max_connections(max_connections)
+                    .with_reject_over_connections(reject_over_connections);
+            }
+            if let Some(idle_timeout_ms) = idle_timeout_ms {
+                db = db.with_idle_timeout(Duration::from_millis(idle_timeout_ms));
+            }
+            if let Some(rate_limit) = rate_limit {
+                db = db.with_rate_limit(rate_limit);
+            }
+            if let Some(metrics_addr) = metrics_addr {
+                println!("📈 Metrics: http://{}/metrics", metrics_addr);
+                db = db.with_metrics_addr(metrics_addr);
+            }
+            match listen.as_slice() {
+                [ListenAddr::Tcp(addr)] => db.start_server(addr).await?,
+                listen => db.start_server_multi(listen).await?,
+            }
+        }
+        Commands::Export { storage, csv, db } => {
+            let databases = Databases::load_from_file(&storage)?;
+            if db >= databases.count() {
+                anyhow::bail!("Database {} does not exist in {}", db, storage);
+            }
+            let file = File::create(&csv)
+                .with_context(|| format!("Failed to create {}", csv))?;
+            let store = databases.get(db);
+            store.export_csv(BufWriter::new(file))?;
+            println!("Exported {} keys from database {} to {}", store.keys().len(), db, csv);
         }
-        Commands::Client { addr, command } => {
+        Commands::Import { storage, from, replace, db, format, compress, compact, checksum } => {
+            let mut databases = Databases::load_from_file(&storage)?;
+            if db >= databases.count() {
+                anyhow::bail!("Database {} does not exist in {}", db, storage);
+            }
+            let input = File::open(&from)
+                .with_context(|| format!("Failed to open {}", from))?;
+            let input = BufReader::new(input);
+            let store = databases.get_mut(db);
+            let count = match Path::new(&from).extension().and_then(|ext| ext.to_str()) {
+                Some("csv") => store.import_csv(input, replace)?,
+                Some("json") => store.import_json_object(input, replace)?,
+                _ => anyhow::bail!("Cannot tell the format of {}; use a .csv or .json file", from),
+            };
+            databases.save_to_file_with(&storage, StorageOptions { format, compress, pretty: !compact, checksum })?;
+            println!("Imported {} keys into database {} of {}", count, db, storage);
+        }
+        Commands::Recover { wal, until, storage, out, format, compress, compact, checksum } => {
+            let mut databases = match &storage {
+                Some(storage) if !Path::new(storage).exists() => anyhow::bail!("{} does not exist", storage),
+                Some(storage) => Databases::load_from_file(storage)?,
+                None => Databases::new(),
+            };
+            let count = databases.replay_until(&wal, until)?;
+            databases.save_to_file_with(&out, StorageOptions { format, compress, pretty: !compact, checksum })?;
+            println!("Replayed {} WAL records up to {} into {}", count, until, out);
+        }
+        Commands::Inspect { storage, db, format, compress, compact, checksum, command } => {
+            let mut databases = Databases::load_from_file(&storage)?;
+            if db >= databases.count() {
+                anyhow::bail!("Database {} does not exist in {}", db, storage);
+            }
+            let store = databases.get_mut(db);
+            match command {
+                InspectCommands::Get { key } => {
+                    match store.get_string(&key)? {
+                        Some(value) if value.is_empty() => println!("\"\""),
+                        Some(value) => println!("{}", value),
+                        None => println!("(null)"),
+                    }
+                }
+                InspectCommands::Set { key, value } => {
+                    store.set(key, value);
+                    databases.save_to_file_with(&storage, StorageOptions { format, compress, pretty: !compact, checksum })?;
+                    println!("OK");
+                }
+                InspectCommands::Keys => {
+                    let mut keys = store.keys();
+                    keys.sort();
+                    if keys.is_empty() {
+                        println!("(empty)");
+                    }
+                    for key in keys {
+                        println!("{}", key);
+                    }
+                }
+                InspectCommands::Len => println!("{}", store.keys().len()),
+            }
+        }
+        Commands::Client { addr, password, unix_socket, db, output, command } => {
             let db_command = match command {
                 ClientCommands::Get { key } => DatabaseCommand::Get { key },
-                ClientCommands::Set { key, value } => DatabaseCommand::Set { key, value },
-                ClientCommands::Delete { key } => DatabaseCommand::Delete { key },
-                ClientCommands::Exists { key } => DatabaseCommand::Exists { key },
-                ClientCommands::Keys => DatabaseCommand::Keys,
+                ClientCommands::Set { key, value, nx, xx, get } => {
+                    // Plain `set` keeps the classic reply; any flag asks for the outcome
+                    let opts = (nx || xx || get).then_some(SetOptions {
+                        get_old: get,
+                        only_if_exists: xx,
+                        only_if_absent: nx,
+                    });
+                    DatabaseCommand::Set { key, value, opts }
+                }
+                ClientCommands::SetBytes { key, file } => {
+                    let value = std::fs::read(&file)
+                        .with_context(|| format!("Failed to read {}", file))?;
+                    DatabaseCommand::SetBytes { key, value }
+                }
+                ClientCommands::GetBytes { key } => DatabaseCommand::GetBytes { key },
+                ClientCommands::GetSet { key, value } => DatabaseCommand::GetSet { key, value },
+                ClientCommands::SetNx { key, value } => DatabaseCommand::SetNx { key, value },
+                ClientCommands::Cas { key, new, expected } => DatabaseCommand::Cas { key, expected, new },
+                ClientCommands::SetEx { key, value, seconds } => DatabaseCommand::SetEx { key, value, seconds },
+                ClientCommands::Expire { key, seconds } => DatabaseCommand::Expire { key, seconds },
+                ClientCommands::Ttl { key } => DatabaseCommand::Ttl { key },
+                ClientCommands::KeyAge { key } => DatabaseCommand::KeyAge { key },
+                ClientCommands::MSet { pairs } => {
+                    if pairs.len() % 2 != 0 {
+                        anyhow::bail!("mset expects KEY VALUE pairs");
+                    }
+                    let pairs = pairs
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect();
+                    DatabaseCommand::MSet { pairs }
+                }
+                ClientCommands::MGet { keys } => DatabaseCommand::MGet { keys },
+                ClientCommands::Append { key, value } => DatabaseCommand::Append { key, value },
+                ClientCommands::GetRange { key, start, end } => DatabaseCommand::GetRange { key, start, end },
+                ClientCommands::SetRange { key, offset, value } => DatabaseCommand::SetRange { key, offset, value },
+                ClientCommands::StrLen { key } => DatabaseCommand::StrLen { key },
+                ClientCommands::Incr { key } => DatabaseCommand::Incr { key },
+                ClientCommands::Decr { key } => DatabaseCommand::Decr { key },
+                ClientCommands::IncrBy { key, delta } => DatabaseCommand::IncrBy { key, delta },
+                ClientCommands::LPush { key, values } => DatabaseCommand::LPush { key, values },
+                ClientCommands::RPush { key, values } => DatabaseCommand::RPush { key, values },
+                ClientCommands::LPop { key } => DatabaseCommand::LPop { key },
+                ClientCommands::RPop { key } => DatabaseCommand::RPop { key },
+                ClientCommands::LLen { key } => DatabaseCommand::LLen { key },
+                ClientCommands::HSet { key, field, value } => DatabaseCommand::HSet { key, field, value },
+                ClientCommands::HGet { key, field } => DatabaseCommand::HGet { key, field },
+                ClientCommands::HDel { key, field } => DatabaseCommand::HDel { key, field },
+                ClientCommands::HGetAll { key } => DatabaseCommand::HGetAll { key },
+                ClientCommands::Rename { from, to } => DatabaseCommand::Rename { from, to },
+                ClientCommands::RenameNx { from, to } => DatabaseCommand::RenameNx { from, to },
+                ClientCommands::Copy { from, to, replace } => DatabaseCommand::Copy { from, to, replace },
+                ClientCommands::Delete { mut keys } => {
+                    if keys.len() == 1 {
+                        DatabaseCommand::Delete { key: keys.remove(0), detailed: true }
+                    } else {
+                        DatabaseCommand::DeleteMany { keys }
+                    }
+                }
+                ClientCommands::Exists { mut keys } => {
+                    if keys.len() == 1 {
+                        DatabaseCommand::Exists { key: keys.remove(0) }
+                    } else {
+                        DatabaseCommand::ExistsMany { keys }
+                    }
+                }
+                ClientCommands::Touch { keys } => DatabaseCommand::Touch { keys },
+                ClientCommands::Type { key } => DatabaseCommand::Type { key },
+                ClientCommands::Keys { pattern: None } => DatabaseCommand::Keys,
+                ClientCommands::Keys { pattern: Some(pattern) } => DatabaseCommand::KeysMatching { pattern },
+                ClientCommands::Scan { prefix } => DatabaseCommand::Scan { prefix },
+                ClientCommands::ScanCursor { cursor, count } => DatabaseCommand::ScanCursor { cursor, count },
+                ClientCommands::RandomKey => DatabaseCommand::RandomKey,
                 ClientCommands::Len => DatabaseCommand::Len,
+                ClientCommands::IsEmpty => DatabaseCommand::IsEmpty,
+                ClientCommands::ByteSize => DatabaseCommand::ByteSize,
                 ClientCommands::Clear => DatabaseCommand::Clear,
+                ClientCommands::FlushDb { sync } => DatabaseCommand::FlushDb { sync },
+                ClientCommands::Dump => DatabaseCommand::Dump,
+                ClientCommands::Restore { data } => DatabaseCommand::Restore { data },
                 ClientCommands::Ping => DatabaseCommand::Ping,
+                ClientCommands::Health => DatabaseCommand::Health,
+                ClientCommands::Hello { protocol } => DatabaseCommand::Hello { protocol },
+                ClientCommands::Command => DatabaseCommand::Command,
+                ClientCommands::Stats => DatabaseCommand::Stats,
+                ClientCommands::Persistence => DatabaseCommand::Persistence,
+                ClientCommands::SlowLog => DatabaseCommand::SlowLog,
+                ClientCommands::Reload { force } => DatabaseCommand::Reload { force },
+                ClientCommands::Subscribe { channel } => DatabaseCommand::Subscribe { channel },
+                ClientCommands::Publish { channel, message } => DatabaseCommand::Publish { channel, message },
+                ClientCommands::Watch { key } => DatabaseCommand::Watch { key },
             };
 
-            run_client_command(&addr, db_command).await?;
+            let result = match unix_socket {
+                #[cfg(unix)]
+                Some(path) => run_unix_client_command(&path, password.as_deref(), db, db_command, output).await,
+                #[cfg(not(unix))]
+                Some(path) => Err(kv_store::ClientError::ConnectionFailed(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("{} is a Unix domain socket, which this platform does not have", path),
+                ))),
+                None => run_client_command(&addr, password.as_deref(), db, db_command, output).await,
+            };
+            match result {
+                Err(e) if output == OutputFormat::Json => {
+                    // Failures that never reached the server look like a server error to scripts
+                    let message = format!("{:#}", anyhow::Error::new(e));
+                    eprintln!("{}", serde_json::to_string(&DatabaseResponse::error(&message))?);
+                    std::process::exit(1);
+                }
+                result => result?,
+            }
         }
     }
 
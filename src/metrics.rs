@@ -0,0 +1,128 @@
+//! Prometheus metrics, served over plain HTTP on a separate port
+
+use std::fmt::Write as _;
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::stats::StatsSnapshot;
+
+/// Longest request head read before the scrape is refused
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// How long a scraper may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Values exposed on `/metrics`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub stats: StatsSnapshot,
+    /// Live keys across all databases
+    pub keys: usize,
+    /// Client connections currently open
+    pub connections: usize,
+    /// Seconds since the last successful save, `None` if nothing was saved yet
+    pub last_save_age: Option<u64>,
+}
+
+impl MetricsSnapshot {
+    /// Render in the Prometheus text exposition format, version 0.0.4
+    pub fn render(&self) -> String {
+        let counters = [
+            ("commands", "Commands run against the store, including those inside EXEC", self.stats.commands),
+            ("reads", "Read-only commands run", self.stats.reads),
+            ("writes", "Mutating commands run", self.stats.writes),
+            ("get_hits", "GETs that found a value", self.stats.get_hits),
+            ("get_misses", "GETs of a missing or expired key", self.stats.get_misses),
+            ("sets", "Keys written by SET-style commands", self.stats.sets),
+            ("deletes", "Keys named by DELETE-style commands", self.stats.deletes),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            metric(&mut out, &format!("kv_store_{}_total", name), "counter", help, value);
+        }
+        metric(&mut out, "kv_store_keys", "gauge", "Live keys across all databases", self.keys as u64);
+        metric(&mut out, "kv_store_connections", "gauge", "Client connections currently open", self.connections as u64);
+        // Left out until the first save rather than reported as a misleading zero
+        if let Some(age) = self.last_save_age {
+            metric(&mut out, "kv_store_last_save_age_seconds", "gauge", "Seconds since the last successful save", age);
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Answer one HTTP request on `stream` and close it.
+///
+/// `GET /metrics` gets the rendered `snapshot`, which is only taken for that path;
+/// anything else gets a 404 or 405. Only as much HTTP as a scraper needs is spoken:
+/// the request body is ignored and every response closes the connection.
+pub(crate) async fn serve_http<S, F, Fut>(stream: S, snapshot: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = MetricsSnapshot>,
+{
+    let mut stream = BufReader::new(stream);
+    let (method, path) = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await??;
+
+    let (status, body) = match (method.as_str(), path.as_str()) {
+        ("GET", "/metrics") => ("200 OK", snapshot().await.render()),
+        (_, "/metrics") => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+        _ => ("404 Not Found", "Metrics are served on /metrics\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    );
+
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the request line and headers, returning the method and the path without
+/// its query string
+async fn read_request_head<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<(String, String)> {
+    let mut head_len = 0;
+    let mut line = String::new();
+    let mut request_line = None;
+    loop {
+        line.clear();
+        // One byte past the limit tells an oversized head from one that just fits
+        let limit = (MAX_REQUEST_HEAD - head_len + 1) as u64;
+        let read = (&mut *reader).take(limit).read_line(&mut line).await?;
+        if read == 0 {
+            bail!("Connection closed before the request was complete");
+        }
+        head_len += read;
+        if head_len > MAX_REQUEST_HEAD {
+            bail!("Request head exceeds {} bytes", MAX_REQUEST_HEAD);
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+        request_line.get_or_insert_with(|| line.trim_end().to_string());
+    }
+
+    let Some(request_line) = request_line else {
+        bail!("Empty request");
+    };
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("Malformed request line: {}", request_line);
+    };
+    let path = target.split('?').next().unwrap_or(target);
+    Ok((method.to_string(), path.to_string()))
+}
@@ -1,28 +1,538 @@
 //! Protocol definitions for client-server communication
 
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 
+use crate::slowlog::SlowLogEntry;
+use crate::stats::StatsSnapshot;
+
+pub mod framing;
+
+/// Version of the command set, bumped when commands or responses change.
+///
+/// Version 2 added `Hello`, `Command`, `IsEmpty`, `ScanCursor`, `KeyAge` and `Set` options.
+/// Version 3 added `Tagged` requests, answered out of order, `Delete` with `detailed`,
+/// `Touch`, `Reload` and `Replicate`. Version 4 added `GetRange`, `SetRange` and `StrLen`.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Oldest protocol version a client may negotiate with `Hello`
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// How commands and responses are delimited on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireProtocol {
+    /// Each message is prefixed with its length; see [`framing`]
+    #[default]
+    Framed,
+    /// Each message is one line of JSON, so tools like `nc` can talk to the server
+    Ndjson,
+}
+
+impl FromStr for WireProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "framed" => Ok(Self::Framed),
+            "ndjson" => Ok(Self::Ndjson),
+            other => Err(format!("unknown protocol '{}' (expected framed or ndjson)", other)),
+        }
+    }
+}
+
+/// Conditions and extras for `Set`, like Redis's NX, XX and GET flags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetOptions {
+    /// Return the previous string value; fails with WRONGTYPE if the key holds a collection
+    #[serde(default)]
+    pub get_old: bool,
+    /// Only set if the key already exists (XX)
+    #[serde(default)]
+    pub only_if_exists: bool,
+    /// Only set if the key does not exist (NX)
+    #[serde(default)]
+    pub only_if_absent: bool,
+}
+
+/// What a `Set` with options did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SetOutcome {
+    Created,
+    Updated,
+    /// A condition in the options was not met and nothing was written
+    Skipped,
+}
+
 /// Database commands sent over TCP
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum DatabaseCommand {
+    /// Answered with `Ok`, whose `value` is `None` only when the key is missing or
+    /// expired; an empty string comes back as `Some("")`
     Get { key: String },
-    Set { key: String, value: String },
-    Delete { key: String },
+    /// Set a value. Without `opts` the reply is `Ok` with the previous value; with
+    /// `opts` it is a `SetResult` saying whether the key was created, updated or skipped.
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        opts: Option<SetOptions>,
+    },
+    /// Store binary data; `Get` on the key then fails with WRONGTYPE
+    SetBytes { key: String, value: Vec<u8> },
+    /// Read a value as bytes, answered with a `Bytes` response
+    GetBytes { key: String },
+    /// Set a new value and return the previous one atomically
+    GetSet { key: String, value: String },
+    /// Set only if the key is absent; responds "1" when set and "0" otherwise
+    SetNx { key: String, value: String },
+    /// Set `new` only if the current value equals `expected` (`None`: key must not exist)
+    Cas { key: String, expected: Option<String>, new: String },
+    SetEx { key: String, value: String, seconds: u64 },
+    Expire { key: String, seconds: u64 },
+    Ttl { key: String },
+    /// Seconds since the key's value last changed, as an `Age` response
+    KeyAge { key: String },
+    MSet { pairs: Vec<(String, String)> },
+    MGet { keys: Vec<String> },
+    /// Append to a value; responds with the new length in bytes
+    Append { key: String, value: String },
+    /// Bytes `start..=end` of a string, with negative offsets counting back from the
+    /// end; responds with `""` for an empty range and `None` for a missing key
+    GetRange { key: String, start: i64, end: i64 },
+    /// Overwrite a string from byte `offset`, zero-padding it first if shorter;
+    /// responds with the new length in bytes
+    SetRange { key: String, offset: usize, value: String },
+    /// Length of a value in bytes, 0 for a missing key, as a `Len` response
+    StrLen { key: String },
+    Incr { key: String },
+    Decr { key: String },
+    IncrBy { key: String, delta: i64 },
+    /// Push values onto the head of a list; responds with the new length
+    LPush { key: String, values: Vec<String> },
+    /// Push values onto the tail of a list; responds with the new length
+    RPush { key: String, values: Vec<String> },
+    LPop { key: String },
+    RPop { key: String },
+    LLen { key: String },
+    /// Set a hash field; responds true if the field is new
+    HSet { key: String, field: String, value: String },
+    HGet { key: String, field: String },
+    /// Remove a hash field; responds true if it existed
+    HDel { key: String, field: String },
+    HGetAll { key: String },
+    /// Move a value and its TTL to a new key, replacing any value there
+    Rename { from: String, to: String },
+    /// Rename only if `to` does not exist; responds true if renamed
+    RenameNx { from: String, to: String },
+    /// Copy a value and its TTL; responds true if copied, false if `to` exists and `replace` is unset
+    Copy { from: String, to: String, replace: bool },
+    /// Delete a key. Without `detailed` the reply is `Ok` with the previous string
+    /// value, which cannot tell a missing key from one holding a list or hash; with
+    /// `detailed` it is a `Deleted` response that also says whether anything was removed.
+    Delete {
+        key: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        detailed: bool,
+    },
+    /// Delete several keys at once; responds with the number actually removed
+    DeleteMany { keys: Vec<String> },
     Exists { key: String },
+    /// Count how many of `keys` exist; a key named twice is counted twice
+    ExistsMany { keys: Vec<String> },
+    /// Mark keys as just used for LRU eviction without reading them; responds with
+    /// how many exist
+    Touch { keys: Vec<String> },
+    /// Type name of the value at a key: string, int, list, hash or none
+    Type { key: String },
     Keys,
+    /// A random live key, or no value if the database is empty
+    RandomKey,
+    Scan { prefix: String },
+    /// A page of at most `count` keys, answered with `ScanPage`. Start at cursor 0
+    /// and pass each reply's cursor back until it is 0 again, so huge keyspaces are
+    /// listed without one giant `Keys` frame.
+    ScanCursor { cursor: u64, count: usize },
+    /// Keys matching a glob pattern with `*` and `?` wildcards
+    KeysMatching { pattern: String },
     Len,
+    /// Whether the database has no live keys, as a `Bool` response
+    IsEmpty,
+    /// Total bytes of keys and values in the database, as a `Len` response
+    ByteSize,
     Clear,
+    /// Empty the database; with `sync`, respond only once the empty state is saved
+    /// and fsynced, otherwise leave saving to the background flush
+    FlushDb { sync: bool },
+    /// The selected database serialized as one string, for `Restore`
+    Dump,
+    /// Replace the selected database with the contents of a `Dump`
+    Restore { data: String },
     Ping,
+    /// Whether the server has finished loading its data, for readiness probes.
+    ///
+    /// Like `Ping` it is answered while loading and without authentication; every
+    /// other command gets a `LOADING` error until the load completes.
+    Health,
+    /// Negotiate the protocol version for this connection: the server answers with
+    /// `Hello` carrying the lower of `protocol` and its own [`PROTOCOL_VERSION`], and
+    /// then rejects commands newer than that. Connections that never send `Hello`
+    /// may use every command.
+    Hello { protocol: u32 },
+    /// Command names this server understands, with its version, answered with
+    /// `CommandInfo` so clients can check compatibility
+    Command,
+    /// Operation counters since the server started
+    Stats,
+    /// Where data is saved, when it last was, and whether a save is pending
+    Persistence,
+    /// Recent commands slower than the server's slow log threshold, newest first
+    SlowLog,
+    /// Replace every database with the storage file's current contents, e.g. after it
+    /// was edited by hand, answered with the new key count. Refused while writes are
+    /// unsaved unless `force` is set, which discards them.
+    Reload {
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        force: bool,
+    },
+    /// Authenticate this connection when the server requires a password
+    Auth { password: String },
+    /// Switch this connection to database `index`; connections start on database 0
+    Select { index: usize },
+    /// Start queuing commands on this connection until `Exec` or `Discard`
+    Multi,
+    /// Run the queued commands under a single store lock
+    Exec,
+    /// Drop the queued commands
+    Discard,
+    /// Turn this connection into a stream of `Message` responses for `channel`.
+    ///
+    /// After the initial `Ok`, the connection only answers `Ping`; other commands
+    /// get an error until the client disconnects.
+    Subscribe { channel: String },
+    /// Send a message to a channel's subscribers; responds with how many received it
+    Publish { channel: String, message: String },
+    /// Turn this connection into a stream of `KeyChanged` responses for `key` in the
+    /// selected database, with the same restrictions as `Subscribe`
+    Watch { key: String },
+    /// Turn this connection into a replication stream: the reply is a `Snapshot` of
+    /// every database, followed by a `Replicated` response for each mutation applied
+    /// after it, in order. The server closes the stream with an error when the
+    /// replica falls behind or the data is reloaded, and the replica must sync again.
+    Replicate,
+    /// Run `command` without holding up the connection: its reply is a `Tagged`
+    /// response with the same `id`, sent as soon as it is ready and possibly before
+    /// replies to earlier requests. Commands that change the connection, like
+    /// `Select` or `Multi`, and everything inside MULTI still run in order.
+    Tagged { id: u64, command: Box<DatabaseCommand> },
+}
+
+impl DatabaseCommand {
+    /// Whether the command modifies the store
+    pub fn is_mutation(&self) -> bool {
+        matches!(
+            self,
+            Self::Set { .. }
+                | Self::SetBytes { .. }
+                | Self::GetSet { .. }
+                | Self::SetNx { .. }
+                | Self::Cas { .. }
+                | Self::SetEx { .. }
+                | Self::Expire { .. }
+                | Self::MSet { .. }
+                | Self::Append { .. }
+                | Self::SetRange { .. }
+                | Self::Incr { .. }
+                | Self::Decr { .. }
+                | Self::IncrBy { .. }
+                | Self::LPush { .. }
+                | Self::RPush { .. }
+                | Self::LPop { .. }
+                | Self::RPop { .. }
+                | Self::HSet { .. }
+                | Self::HDel { .. }
+                | Self::Rename { .. }
+                | Self::RenameNx { .. }
+                | Self::Copy { .. }
+                | Self::Delete { .. }
+                | Self::DeleteMany { .. }
+                | Self::Clear
+                | Self::FlushDb { .. }
+                | Self::Restore { .. }
+        )
+    }
+
+    /// Whether the command reads or changes the state of its connection, such as
+    /// the selected database or an open transaction, and so must run in order with
+    /// the connection's other commands
+    pub fn is_connection_command(&self) -> bool {
+        matches!(
+            self,
+            Self::Hello { .. }
+                | Self::Auth { .. }
+                | Self::Select { .. }
+                | Self::Multi
+                | Self::Exec
+                | Self::Discard
+                | Self::Subscribe { .. }
+                | Self::Watch { .. }
+                | Self::Replicate
+                | Self::Tagged { .. }
+        )
+    }
+
+    /// Whether the command needs the store's write lock. `Touch` only changes
+    /// in-memory access times, so it is not a mutation to log or save, but it may
+    /// start tracking keys loaded from disk.
+    pub fn needs_write_lock(&self) -> bool {
+        self.is_mutation() || matches!(self, Self::Touch { .. })
+    }
+
+    /// Lowest protocol version that may send this command
+    pub fn min_protocol(&self) -> u32 {
+        match self {
+            Self::Hello { .. }
+            | Self::Command
+            | Self::IsEmpty
+            | Self::ScanCursor { .. }
+            | Self::KeyAge { .. }
+            | Self::Set { opts: Some(_), .. } => 2,
+            Self::Tagged { .. }
+            | Self::Delete { detailed: true, .. }
+            | Self::Touch { .. }
+            | Self::Reload { .. }
+            | Self::Replicate => 3,
+            Self::GetRange { .. } | Self::SetRange { .. } | Self::StrLen { .. } => 4,
+            _ => 1,
+        }
+    }
+
+    /// Every variant name, as returned by [`DatabaseCommand::name`]
+    pub const NAMES: &'static [&'static str] = &[
+        "Get",
+        "Set",
+        "SetBytes",
+        "GetBytes",
+        "GetSet",
+        "SetNx",
+        "Cas",
+        "SetEx",
+        "Expire",
+        "Ttl",
+        "KeyAge",
+        "MSet",
+        "MGet",
+        "Append",
+        "GetRange",
+        "SetRange",
+        "StrLen",
+        "Incr",
+        "Decr",
+        "IncrBy",
+        "LPush",
+        "RPush",
+        "LPop",
+        "RPop",
+        "LLen",
+        "HSet",
+        "HGet",
+        "HDel",
+        "HGetAll",
+        "Rename",
+        "RenameNx",
+        "Copy",
+        "Delete",
+        "DeleteMany",
+        "Exists",
+        "ExistsMany",
+        "Touch",
+        "Type",
+        "Keys",
+        "RandomKey",
+        "Scan",
+        "ScanCursor",
+        "KeysMatching",
+        "Len",
+        "IsEmpty",
+        "ByteSize",
+        "Clear",
+        "FlushDb",
+        "Dump",
+        "Restore",
+        "Ping",
+        "Health",
+        "Hello",
+        "Command",
+        "Stats",
+        "Persistence",
+        "SlowLog",
+        "Reload",
+        "Auth",
+        "Select",
+        "Multi",
+        "Exec",
+        "Discard",
+        "Subscribe",
+        "Publish",
+        "Watch",
+        "Replicate",
+        "Tagged",
+    ];
+
+    /// Variant name, for logs and the slow log
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Get { .. } => "Get",
+            Self::Set { .. } => "Set",
+            Self::SetBytes { .. } => "SetBytes",
+            Self::GetBytes { .. } => "GetBytes",
+            Self::GetSet { .. } => "GetSet",
+            Self::SetNx { .. } => "SetNx",
+            Self::Cas { .. } => "Cas",
+            Self::SetEx { .. } => "SetEx",
+            Self::Expire { .. } => "Expire",
+            Self::Ttl { .. } => "Ttl",
+            Self::KeyAge { .. } => "KeyAge",
+            Self::MSet { .. } => "MSet",
+            Self::MGet { .. } => "MGet",
+            Self::Append { .. } => "Append",
+            Self::GetRange { .. } => "GetRange",
+            Self::SetRange { .. } => "SetRange",
+            Self::StrLen { .. } => "StrLen",
+            Self::Incr { .. } => "Incr",
+            Self::Decr { .. } => "Decr",
+            Self::IncrBy { .. } => "IncrBy",
+            Self::LPush { .. } => "LPush",
+            Self::RPush { .. } => "RPush",
+            Self::LPop { .. } => "LPop",
+            Self::RPop { .. } => "RPop",
+            Self::LLen { .. } => "LLen",
+            Self::HSet { .. } => "HSet",
+            Self::HGet { .. } => "HGet",
+            Self::HDel { .. } => "HDel",
+            Self::HGetAll { .. } => "HGetAll",
+            Self::Rename { .. } => "Rename",
+            Self::RenameNx { .. } => "RenameNx",
+            Self::Copy { .. } => "Copy",
+            Self::Delete { .. } => "Delete",
+            Self::DeleteMany { .. } => "DeleteMany",
+            Self::Exists { .. } => "Exists",
+            Self::ExistsMany { .. } => "ExistsMany",
+            Self::Touch { .. } => "Touch",
+            Self::Type { .. } => "Type",
+            Self::Keys => "Keys",
+            Self::RandomKey => "RandomKey",
+            Self::Scan { .. } => "Scan",
+            Self::ScanCursor { .. } => "ScanCursor",
+            Self::KeysMatching { .. } => "KeysMatching",
+            Self::Len => "Len",
+            Self::IsEmpty => "IsEmpty",
+            Self::ByteSize => "ByteSize",
+            Self::Clear => "Clear",
+            Self::FlushDb { .. } => "FlushDb",
+            Self::Dump => "Dump",
+            Self::Restore { .. } => "Restore",
+            Self::Ping => "Ping",
+            Self::Health => "Health",
+            Self::Hello { .. } => "Hello",
+            Self::Command => "Command",
+            Self::Stats => "Stats",
+            Self::Persistence => "Persistence",
+            Self::SlowLog => "SlowLog",
+            Self::Reload { .. } => "Reload",
+            Self::Auth { .. } => "Auth",
+            Self::Select { .. } => "Select",
+            Self::Multi => "Multi",
+            Self::Exec => "Exec",
+            Self::Discard => "Discard",
+            Self::Subscribe { .. } => "Subscribe",
+            Self::Publish { .. } => "Publish",
+            Self::Watch { .. } => "Watch",
+            Self::Replicate => "Replicate",
+            Self::Tagged { .. } => "Tagged",
+        }
+    }
+}
+
+/// A mutation a primary applied to database `db`, as streamed to replicas
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationCommand {
+    pub db: usize,
+    pub command: DatabaseCommand,
+}
+
+/// Server readiness, as reported by `Health`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthStatus {
+    /// Whether the storage file and WAL have been loaded
+    pub loaded: bool,
+    /// Live keys across all databases; 0 while loading
+    pub keys: usize,
+}
+
+/// Storage settings and save state, as reported by `Persistence`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistenceStatus {
+    /// Storage backend: file, or memory when nothing is written to disk
+    pub backend: String,
+    /// Storage file, made absolute against the server's working directory
+    pub path: String,
+    /// On-disk format: json or bincode
+    pub format: String,
+    pub compressed: bool,
+    /// Unix time in seconds of the last successful save, `None` if nothing was saved yet
+    pub last_save_at: Option<u64>,
+    /// Whether writes are waiting for the background flush
+    pub pending: bool,
 }
 
 /// Database responses sent back to clients
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum DatabaseResponse {
     Ok { value: Option<String> },
+    /// Reply to a `Set` with options; `old_value` is only filled in with `get_old`
+    SetResult { outcome: SetOutcome, old_value: Option<String> },
+    /// Binary value from `GetBytes`, `None` for a missing key
+    Bytes { value: Option<Vec<u8>> },
     Error { message: String },
+    Bool { value: bool },
     Keys { keys: Vec<String> },
+    /// Reply to a detailed `Delete`: the previous string value, and whether a live key
+    /// was removed at all
+    Deleted { value: Option<String>, deleted: bool },
+    /// One page of a `ScanCursor`; `cursor` is 0 when there are no more pages
+    ScanPage { cursor: u64, keys: Vec<String> },
+    /// Values in request order, `None` for missing keys
+    Values { values: Vec<Option<String>> },
+    /// Field/value pairs of a hash
+    Pairs { pairs: Vec<(String, String)> },
     Len { count: usize },
+    /// Remaining TTL in seconds, -1 for no expiry, -2 for a missing key
+    Ttl { seconds: i64 },
+    /// Seconds since a key last changed
+    Age { seconds: u64 },
     Pong,
+    /// A command was queued inside MULTI
+    Queued,
+    /// Per-command results of an EXEC, in queue order
+    Results { responses: Vec<DatabaseResponse> },
+    Stats { stats: StatsSnapshot },
+    Health { health: HealthStatus },
+    /// The protocol version agreed by `Hello`
+    Hello { protocol: u32 },
+    /// Reply to `Command`: the crate version, [`PROTOCOL_VERSION`] and command names
+    CommandInfo { version: String, protocol_version: u32, commands: Vec<String> },
+    Persistence { persistence: PersistenceStatus },
+    SlowLog { entries: Vec<SlowLogEntry> },
+    /// A message published to a channel this connection subscribed to
+    Message { channel: String, payload: String },
+    /// A watched key was set (`value` is the new value) or removed (`value` is `None`)
+    KeyChanged { key: String, value: Option<String> },
+    /// Reply to `Replicate`: every database, serialized as JSON
+    Snapshot { data: String },
+    /// A mutation streamed to a replica
+    Replicated { command: ReplicationCommand },
+    /// Reply to the `Tagged` request with the same `id`
+    Tagged { id: u64, response: Box<DatabaseResponse> },
 }
 
 impl DatabaseResponse {
@@ -34,19 +544,111 @@ impl DatabaseResponse {
         Self::Ok { value: None }
     }
 
+    pub fn bytes(value: Option<Vec<u8>>) -> Self {
+        Self::Bytes { value }
+    }
+
+    pub fn set_result(outcome: SetOutcome, old_value: Option<String>) -> Self {
+        Self::SetResult { outcome, old_value }
+    }
+
     pub fn error(message: &str) -> Self {
         Self::Error { message: message.to_string() }
     }
 
+    pub fn boolean(value: bool) -> Self {
+        Self::Bool { value }
+    }
+
     pub fn keys(keys: Vec<String>) -> Self {
         Self::Keys { keys }
     }
 
+    pub fn deleted(value: Option<String>, deleted: bool) -> Self {
+        Self::Deleted { value, deleted }
+    }
+
+    pub fn scan_page(cursor: u64, keys: Vec<String>) -> Self {
+        Self::ScanPage { cursor, keys }
+    }
+
+    pub fn values(values: Vec<Option<String>>) -> Self {
+        Self::Values { values }
+    }
+
+    pub fn pairs(pairs: Vec<(String, String)>) -> Self {
+        Self::Pairs { pairs }
+    }
+
     pub fn length(count: usize) -> Self {
         Self::Len { count }
     }
 
+    pub fn ttl(seconds: i64) -> Self {
+        Self::Ttl { seconds }
+    }
+
+    pub fn age(seconds: u64) -> Self {
+        Self::Age { seconds }
+    }
+
     pub fn pong() -> Self {
         Self::Pong
     }
+
+    pub fn queued() -> Self {
+        Self::Queued
+    }
+
+    pub fn results(responses: Vec<DatabaseResponse>) -> Self {
+        Self::Results { responses }
+    }
+
+    pub fn stats(stats: StatsSnapshot) -> Self {
+        Self::Stats { stats }
+    }
+
+    pub fn health(health: HealthStatus) -> Self {
+        Self::Health { health }
+    }
+
+    pub fn persistence(persistence: PersistenceStatus) -> Self {
+        Self::Persistence { persistence }
+    }
+
+    pub fn hello(protocol: u32) -> Self {
+        Self::Hello { protocol }
+    }
+
+    pub fn command_info(commands: Vec<String>) -> Self {
+        Self::CommandInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            commands,
+        }
+    }
+
+    pub fn slow_log(entries: Vec<SlowLogEntry>) -> Self {
+        Self::SlowLog { entries }
+    }
+
+    pub fn message(channel: String, payload: String) -> Self {
+        Self::Message { channel, payload }
+    }
+
+    pub fn key_changed(key: String, value: Option<String>) -> Self {
+        Self::KeyChanged { key, value }
+    }
+
+    pub fn snapshot(data: String) -> Self {
+        Self::Snapshot { data }
+    }
+
+    pub fn replicated(command: ReplicationCommand) -> Self {
+        Self::Replicated { command }
+    }
+
+    pub fn tagged(id: u64, response: DatabaseResponse) -> Self {
+        Self::Tagged { id, response: Box::new(response) }
+    }
 }
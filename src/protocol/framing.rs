@@ -0,0 +1,66 @@
+//! Length-prefixed framing for messages sent over a stream
+
+use std::fmt;
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use anyhow::{Result, Context};
+
+/// A frame header announced more bytes than the reader accepts.
+///
+/// Returned inside `anyhow::Error`; use `downcast_ref::<FrameTooLarge>()` to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame of {} bytes exceeds the maximum of {} bytes", self.len, self.max)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// Write `bytes` preceded by its length as a 4-byte big-endian integer
+pub async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len())
+        .context("Frame exceeds maximum length")?;
+    // One write: sending the header on its own lets Nagle's algorithm hold the
+    // payload back until the peer's delayed ACK, stalling every round trip
+    let mut frame = Vec::with_capacity(4 + bytes.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(bytes);
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read exactly one frame, returning `None` if the peer closed the stream.
+///
+/// The header and payload are read with `read_exact`, so a frame split across any
+/// number of TCP segments is reassembled before it is parsed.
+pub async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<Vec<u8>>> {
+    read_frame_limited(stream, u32::MAX as usize).await
+}
+
+/// Like [`read_frame`], but fails with [`FrameTooLarge`] before allocating for a
+/// frame longer than `max_len`. The payload is left unread, so the stream should be
+/// closed afterwards.
+pub async fn read_frame_limited<R: AsyncRead + Unpin>(stream: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match stream.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_len {
+        return Err(FrameTooLarge { len, max: max_len }.into());
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await
+        .context("Connection closed mid-frame")?;
+    Ok(Some(payload))
+}
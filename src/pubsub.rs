@@ -0,0 +1,94 @@
+//! In-process publish/subscribe channels
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Messages a subscriber may fall behind by before it starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A change to a watched key, broadcast on a channel named after the key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Database the key lives in; watchers ignore events from other databases
+    pub db: usize,
+    pub key: String,
+    /// The new value, or `None` as a tombstone when the key was removed
+    pub value: Option<String>,
+}
+
+/// A change applied to the store, from [`MiniDatabase::subscribe_mutations`]
+///
+/// [`MiniDatabase::subscribe_mutations`]: crate::MiniDatabase::subscribe_mutations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutationEvent {
+    /// `key` in database `db` now holds the string `value`
+    Set { db: usize, key: String, value: String },
+    /// `key` was deleted, renamed away, expired or evicted
+    Delete { db: usize, key: String },
+    /// Every key in `db` was removed, or replaced by `Restore`, `Reload` or a
+    /// replica's sync; re-read the database rather than patching a copy of it
+    Clear { db: usize },
+}
+
+/// Named broadcast channels, created on first subscribe.
+///
+/// A channel's sender is dropped once its last subscriber goes away, so idle
+/// channel names do not accumulate.
+#[derive(Debug)]
+pub struct Channels<T> {
+    senders: Mutex<HashMap<String, broadcast::Sender<T>>>,
+}
+
+impl<T: Clone> Default for Channels<T> {
+    fn default() -> Self {
+        Self { senders: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<T: Clone> Channels<T> {
+    pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<T> {
+        let mut senders = self.senders.lock().unwrap();
+        match senders.get(channel) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(CHANNEL_CAPACITY);
+                senders.insert(channel.to_string(), sender);
+                receiver
+            }
+        }
+    }
+
+    /// Send `message` to every subscriber of `channel`; returns how many received it
+    pub fn publish(&self, channel: &str, message: T) -> usize {
+        let mut senders = self.senders.lock().unwrap();
+        let Some(sender) = senders.get(channel) else {
+            return 0;
+        };
+        match sender.send(message) {
+            Ok(count) => count,
+            Err(_) => {
+                senders.remove(channel);
+                0
+            }
+        }
+    }
+
+    /// Whether no channel currently has subscribers
+    pub fn is_empty(&self) -> bool {
+        self.senders.lock().unwrap().is_empty()
+    }
+
+    /// Names of the channels that currently have subscribers
+    pub fn names(&self) -> Vec<String> {
+        self.senders.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Drop `channel` if it has no subscribers left; call after dropping a receiver
+    pub fn release(&self, channel: &str) {
+        let mut senders = self.senders.lock().unwrap();
+        if senders.get(channel).is_some_and(|sender| sender.receiver_count() == 0) {
+            senders.remove(channel);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Token bucket limiting how fast one connection may send commands
+
+use tokio::time::Instant;
+
+/// Holds up to one second's worth of commands, refilled continuously at `rate`
+/// tokens per second. A fresh bucket starts full, so a client may burst up to
+/// `rate` commands before being slowed to the steady rate.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(commands_per_second: u32) -> Self {
+        let rate = f64::from(commands_per_second);
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take a token for one command, returning false when the bucket is empty
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -1,161 +1,2336 @@
 //! Database server implementation
 
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
-use tracing::{info, error, warn};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, oneshot, watch, Mutex, OwnedRwLockReadGuard, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{info, info_span, error, warn, Instrument};
 use anyhow::Result;
 use serde_json;
 
-use crate::store::KeyValueStore;
-use crate::database::Database;
-use crate::protocol::{DatabaseCommand, DatabaseResponse};
+use crate::backend::Backend;
+use crate::store::{Databases, PendingSave, StorageBackend, StorageFormat, StorageOptions};
+use crate::clock::{Clock, SystemClock};
+use crate::database::TypedDatabase;
+use crate::error::{ServerError, StorageError};
+use crate::lockfile::StorageLock;
+use crate::metrics::{self, MetricsSnapshot};
+use crate::protocol::{DatabaseCommand, DatabaseResponse, HealthStatus, PersistenceStatus, ReplicationCommand, SetOutcome, WireProtocol, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::protocol::framing::{read_frame_limited, write_frame, FrameTooLarge};
+use crate::pubsub::{Channels, KeyEvent, MutationEvent};
+use crate::ratelimit::TokenBucket;
+use crate::slowlog::SlowLog;
+use crate::stats::Stats;
+use crate::wal::{WalCheckpoint, WriteAheadLog};
+
+use replication::ReplicationEvent;
+
+mod replication;
+
+/// Default delay between background flushes of a dirty store
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the sweeper looks for expired keys, and how many it removes per lock
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_SWEEP_BATCH: usize = 100;
+
+/// Commands slower than this are logged, keeping the most recent entries
+const DEFAULT_SLOW_LOG_THRESHOLD: Duration = Duration::from_millis(10);
+const DEFAULT_SLOW_LOG_LEN: usize = 128;
+
+/// Largest command frame accepted before the connection is dropped
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long a rejected connection is kept open for its first command
+const REJECT_LINGER: Duration = Duration::from_secs(1);
+
+/// Mutations buffered for each replica; one that falls further behind is cut off and
+/// has to sync again
+const REPLICATION_BACKLOG: usize = 10_000;
+
+/// Mutation events a subscriber may fall behind by before it starts missing them
+const MUTATION_BACKLOG: usize = 1024;
+
+/// State that belongs to a single client connection
+struct Session {
+    /// Commands received so far, numbering each command's tracing span
+    seq: u64,
+    /// Whether AUTH has succeeded, or no password is configured
+    authenticated: bool,
+    /// Database chosen with SELECT
+    db: usize,
+    /// Commands queued since MULTI, `None` outside a transaction
+    queue: Option<Vec<DatabaseCommand>>,
+    /// Set by SUBSCRIBE or WATCH; the connection streams notifications from then on
+    subscription: Option<Subscription>,
+    /// Whether the transport supports SUBSCRIBE and WATCH
+    can_subscribe: bool,
+    /// Protocol version agreed with HELLO; the latest until the client asks for less
+    protocol: u32,
+    /// Commands this connection may still send, when rate limited
+    rate_limit: Option<TokenBucket>,
+}
+
+impl Session {
+    fn new(authenticated: bool, can_subscribe: bool, rate_limit: Option<u32>) -> Self {
+        Self {
+            seq: 0,
+            authenticated,
+            db: 0,
+            queue: None,
+            subscription: None,
+            can_subscribe,
+            protocol: PROTOCOL_VERSION,
+            rate_limit: rate_limit.map(TokenBucket::new),
+        }
+    }
+}
+
+/// What a subscribed connection streams
+enum Subscription {
+    Channel(String, broadcast::Receiver<String>),
+    /// A key in the given database
+    Key(usize, String, broadcast::Receiver<KeyEvent>),
+    /// Every mutation, for a replica
+    Replica(broadcast::Receiver<ReplicationEvent>),
+}
 
 /// Mini database server with TCP networking
 pub struct MiniDatabase {
-    store: Arc<Mutex<KeyValueStore>>,
+    store: Arc<RwLock<Databases>>,
     storage_path: String,
+    storage: StorageOptions,
+    backend: Backend,
+    /// Set by mutating commands, cleared when the store is flushed to disk
+    dirty: Arc<AtomicBool>,
+    flush_interval: Duration,
+    /// Save as soon as this many mutations accumulate, without waiting for the interval
+    save_after_writes: Option<usize>,
+    /// Mutations since the last save, counted by `mark_dirty`
+    writes_since_save: Arc<AtomicUsize>,
+    protocol: WireProtocol,
+    /// Command frames longer than this are refused without being read
+    max_frame_size: usize,
+    sweep_interval: Duration,
+    sweep_batch: usize,
+    /// Write-ahead log location; logging is disabled when unset
+    wal_path: Option<PathBuf>,
+    /// Opened log, shared by all connections once the server starts
+    wal: Arc<StdMutex<Option<WriteAheadLog>>>,
+    /// Held for the length of a save so saves never overlap, and by the expiry
+    /// sweeper so it never queues for the write lock behind one
+    saving: Arc<Mutex<()>>,
+    password: Option<String>,
+    /// Refuse mutations, e.g. for a replica or a lockdown
+    read_only: bool,
+    stats: Arc<Stats>,
+    slow_log: Arc<SlowLog>,
+    /// Per-database key limit enforced by LRU eviction
+    max_keys: Option<usize>,
+    /// Refuse writes that would exceed `max_keys` instead of evicting
+    reject_over_capacity: bool,
+    /// Largest key name, in bytes, a write may create
+    max_key_size: Option<usize>,
+    /// Largest value, in bytes, a write may store
+    max_value_size: Option<usize>,
+    pubsub: Arc<Channels<String>>,
+    /// Key change notifications, one channel per watched key name
+    watchers: Arc<Channels<KeyEvent>>,
+    /// Mutations in the order they are applied, for connected replicas
+    replicas: broadcast::Sender<ReplicationEvent>,
+    /// Changes applied to the store, for [`MiniDatabase::subscribe_mutations`]
+    mutations: broadcast::Sender<MutationEvent>,
+    /// Primary this server mirrors; a replica refuses writes from its own clients
+    replica_of: Option<String>,
+    /// Password to AUTH with on the primary
+    primary_password: Option<String>,
+    /// Id given to the next connection, recorded on its tracing span
+    next_connection_id: Arc<AtomicU64>,
+    /// Set while the snapshot and WAL load in the background; only PING, HEALTH and
+    /// AUTH are answered until it clears
+    loading: Arc<AtomicBool>,
+    /// Unix time of the last successful save, 0 before the first
+    last_save_at: Arc<AtomicU64>,
+    /// Connections currently being served, for logging
+    connections: Arc<AtomicUsize>,
+    /// One permit per allowed concurrent connection, when limited
+    connection_slots: Option<Arc<Semaphore>>,
+    /// With a connection limit, turn away connections beyond it instead of waiting
+    reject_over_connections: bool,
+    /// Close connections that send no command for this long
+    idle_timeout: Option<Duration>,
+    /// Commands per second each connection may send
+    rate_limit: Option<u32>,
+    /// Where to serve Prometheus metrics over HTTP; off when unset
+    metrics_addr: Option<String>,
+    /// Take the storage file's lock even from a process that seems to be running
+    force_lock: bool,
+    /// Set on shutdown to end open connections and background tasks, so nothing
+    /// writes after the final save
+    closing: Arc<watch::Sender<bool>>,
 }
 
-impl MiniDatabase {
-    pub fn new(storage_path: String) -> Self {
-        Self {
-            store: Arc::new(Mutex::new(KeyValueStore::new())),
-            storage_path,
+impl MiniDatabase {
+    pub fn new(storage_path: String) -> Self {
+        let backend = Backend::new(StorageBackend::default(), &storage_path);
+        Self {
+            store: Arc::new(RwLock::new(Databases::new())),
+            storage_path,
+            storage: StorageOptions::default(),
+            backend,
+            dirty: Arc::new(AtomicBool::new(false)),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            save_after_writes: None,
+            writes_since_save: Arc::new(AtomicUsize::new(0)),
+            protocol: WireProtocol::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            sweep_batch: DEFAULT_SWEEP_BATCH,
+            wal_path: None,
+            wal: Arc::new(StdMutex::new(None)),
+            saving: Arc::new(Mutex::new(())),
+            password: None,
+            read_only: false,
+            stats: Arc::new(Stats::default()),
+            slow_log: Arc::new(SlowLog::new(DEFAULT_SLOW_LOG_THRESHOLD, DEFAULT_SLOW_LOG_LEN)),
+            max_keys: None,
+            reject_over_capacity: false,
+            max_key_size: None,
+            max_value_size: None,
+            pubsub: Arc::new(Channels::default()),
+            watchers: Arc::new(Channels::default()),
+            replicas: broadcast::channel(REPLICATION_BACKLOG).0,
+            mutations: broadcast::channel(MUTATION_BACKLOG).0,
+            replica_of: None,
+            primary_password: None,
+            next_connection_id: Arc::new(AtomicU64::new(1)),
+            loading: Arc::new(AtomicBool::new(false)),
+            last_save_at: Arc::new(AtomicU64::new(0)),
+            connections: Arc::new(AtomicUsize::new(0)),
+            connection_slots: None,
+            reject_over_connections: false,
+            idle_timeout: None,
+            rate_limit: None,
+            metrics_addr: None,
+            force_lock: false,
+            closing: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// A server that serves reads but refuses every mutation with READONLY
+    pub fn new_readonly(storage_path: String) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(storage_path)
+        }
+    }
+
+    /// Encoding used when saving to disk; existing files of either format still load
+    pub fn with_format(mut self, format: StorageFormat) -> Self {
+        self.storage.format = format;
+        self
+    }
+
+    /// Gzip the storage file; compressed and plain files both load
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.storage.compress = compress;
+        self
+    }
+
+    /// Indent JSON saves (the default), or write compact JSON to save disk space and
+    /// time on large stores; pretty and compact files both load
+    pub fn with_pretty_json(mut self, pretty: bool) -> Self {
+        self.storage.pretty = pretty;
+        self
+    }
+
+    /// Save with a CRC32 checksum, so a damaged storage file stops the server from
+    /// loading instead of serving partial data; files without one still load
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.storage.checksum = checksum;
+        self
+    }
+
+    /// Keep data in the storage file, or only in memory for an ephemeral cache. The
+    /// memory backend never touches disk: no load, no saves and no WAL.
+    pub fn with_backend(mut self, backend: StorageBackend) -> Self {
+        self.backend = Backend::new(backend, &self.storage_path);
+        self
+    }
+
+    /// Flush writes to disk at most once per `interval`
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Also save once `writes` mutations have accumulated since the last save, so
+    /// bursts of writes bound the WAL and the data at risk rather than waiting for
+    /// the flush interval
+    pub fn with_save_after_writes(mut self, writes: usize) -> Self {
+        self.save_after_writes = Some(writes.max(1));
+        self
+    }
+
+    /// Sweep for expired keys every `interval`, removing at most `batch_size`
+    /// per write lock so client requests are not starved
+    pub fn with_expiry_sweep(mut self, interval: Duration, batch_size: usize) -> Self {
+        self.sweep_interval = interval;
+        self.sweep_batch = batch_size.max(1);
+        self
+    }
+
+    /// Log every mutation to `path` before applying it, replaying the log on startup
+    pub fn with_wal<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.wal_path = Some(path.into());
+        self
+    }
+
+    /// Cap each database at `max_keys`, evicting the least recently used keys on writes
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// With `max_keys` set, answer writes that would add keys beyond it with an
+    /// OOM error rather than evicting; updates to existing keys still succeed
+    pub fn with_reject_over_capacity(mut self, reject: bool) -> Self {
+        self.reject_over_capacity = reject;
+        self
+    }
+
+    /// Refuse writes that would create a key longer than `bytes`
+    pub fn with_max_key_size(mut self, bytes: usize) -> Self {
+        self.max_key_size = Some(bytes);
+        self
+    }
+
+    /// Refuse writes carrying a value longer than `bytes`. Each list element, hash
+    /// value and appended string is checked on its own.
+    pub fn with_max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = Some(bytes);
+        self
+    }
+
+    /// How connections delimit messages; length-prefixed frames unless set to NDJSON
+    pub fn with_protocol(mut self, protocol: WireProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Reject command frames larger than `bytes` with an error and close the connection,
+    /// instead of allocating whatever length a client announces
+    pub fn with_max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Warn about commands that take longer than `threshold`, including time spent
+    /// waiting for the store lock, and keep the last `len` of them for `SlowLog`
+    pub fn with_slow_log(mut self, threshold: Duration, len: usize) -> Self {
+        self.slow_log = Arc::new(SlowLog::new(threshold, len));
+        self
+    }
+
+    /// Serve at most `max` connections at once. Further connections wait in the
+    /// listen backlog until one closes, unless rejection is turned on.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.connection_slots = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// With a connection limit, answer connections beyond it with an error frame and
+    /// close them, rather than leaving them waiting
+    pub fn with_reject_over_connections(mut self, reject: bool) -> Self {
+        self.reject_over_connections = reject;
+        self
+    }
+
+    /// Close a connection when no command arrives within `timeout`, so clients that
+    /// crashed without disconnecting do not hold a connection forever. Subscribers
+    /// and watchers are exempt, since waiting is all they do.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Let each connection send at most `commands_per_second` commands per second,
+    /// after an initial burst of as many. Commands beyond that are answered with
+    /// "ERR rate limited" without running, so one busy client cannot starve the rest.
+    pub fn with_rate_limit(mut self, commands_per_second: u32) -> Self {
+        self.rate_limit = Some(commands_per_second);
+        self
+    }
+
+    /// Serve Prometheus metrics on `GET /metrics` from a separate HTTP listener on `addr`
+    pub fn with_metrics_addr<S: Into<String>>(mut self, addr: S) -> Self {
+        self.metrics_addr = Some(addr.into());
+        self
+    }
+
+    /// Start even if the storage file's lock names a running process, e.g. one that
+    /// reused the PID of a server that crashed. Only use this when no other server is
+    /// using the file, or their saves will overwrite each other.
+    pub fn with_force_lock(mut self, force: bool) -> Self {
+        self.force_lock = force;
+        self
+    }
+
+    /// Mirror the primary at `addr`: sync every database from it once loaded, then
+    /// apply its writes as they happen, syncing again whenever the stream breaks.
+    /// The replica is read-only to its own clients.
+    pub fn with_replica_of<S: Into<String>>(mut self, addr: S) -> Self {
+        self.replica_of = Some(addr.into());
+        self.read_only = true;
+        self
+    }
+
+    /// Password the replica sends with AUTH when the primary requires one
+    pub fn with_primary_password<S: Into<String>>(mut self, password: S) -> Self {
+        self.primary_password = Some(password.into());
+        self
+    }
+
+    /// Receive an event for each change applied to the store, from any connection,
+    /// expiry or eviction, in the order they are applied. Events are sent only while
+    /// someone is subscribed, and a receiver more than 1024 events behind misses the
+    /// oldest ones. Clones of this server share the same events.
+    pub fn subscribe_mutations(&self) -> broadcast::Receiver<MutationEvent> {
+        self.mutations.subscribe()
+    }
+
+    /// Require connections to send AUTH with this password before other commands
+    pub fn with_password<S: Into<String>>(mut self, password: S) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    async fn load_from_disk(&self) -> Result<()> {
+        let Some(store) = self.read_storage_file().await? else {
+            return Ok(());
+        };
+        *self.store.write().await = store;
+        info!("Loaded database from {}", self.storage_path);
+        Ok(())
+    }
+
+    /// Load from the backend off the async workers, so other connections are still
+    /// answered meanwhile; `None` when the backend keeps nothing to load
+    async fn read_storage_file(&self) -> Result<Option<Databases>> {
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || backend.load()).await?
+    }
+
+    /// Replace every database with the storage file's contents, answering with the
+    /// new key count.
+    ///
+    /// Writes not yet saved would be lost, so they make this fail unless `force` is
+    /// set. Either way the WAL is emptied: its records were made against the old data
+    /// and must not be replayed over the new file on the next start.
+    async fn reload(&self, force: bool) -> DatabaseResponse {
+        if self.replica_of.is_some() {
+            return DatabaseResponse::error("ERR a replica takes its data from the primary, not the storage file");
+        }
+        // No save may run meanwhile, or it could write the old data over the file
+        let _saving = self.saving.lock().await;
+        let loaded = match self.read_storage_file().await {
+            Ok(Some(loaded)) => loaded,
+            Ok(None) => {
+                return DatabaseResponse::error(&format!("ERR the {} backend has no storage file to reload", self.backend.kind()));
+            }
+            Err(e) => return DatabaseResponse::error(&format!("ERR failed to reload: {}", e)),
+        };
+
+        let mut store = self.store.write().await;
+        // Checked under the write lock, so no write can land between here and the swap
+        let unsaved = self.writes_since_save.load(Ordering::Acquire);
+        if unsaved > 0 && !force {
+            return DatabaseResponse::error(&format!(
+                "ERR {} writes are not saved yet; wait for the next save or reload with force to discard them",
+                unsaved,
+            ));
+        }
+        if let Some(wal) = self.wal.lock().unwrap().as_mut() {
+            if let Err(e) = wal.truncate() {
+                error!("Failed to truncate WAL: {}", e);
+                return DatabaseResponse::error("ERR failed to truncate the WAL");
+            }
+        }
+        *store = loaded;
+        // Replicas can not follow a wholesale swap command by command
+        let _ = self.replicas.send(ReplicationEvent::Resync);
+        self.publish_replaced(&store);
+        self.writes_since_save.store(0, Ordering::Release);
+        self.dirty.store(false, Ordering::Release);
+        let keys = store.total_len();
+        info!("Reloaded {} keys from {}, discarding {} unsaved writes", keys, self.storage_path, unsaved);
+        DatabaseResponse::length(keys)
+    }
+
+    /// Replay logged mutations on top of the loaded snapshot and open the log for appending
+    async fn replay_wal(&self) -> Result<()> {
+        let Some(wal_path) = &self.wal_path else {
+            return Ok(());
+        };
+
+        let path = wal_path.clone();
+        let entries = tokio::task::spawn_blocking(move || WriteAheadLog::read_entries(&path)).await??;
+        if !entries.is_empty() {
+            let count = entries.len();
+            self.store.write().await.replay(entries);
+            // Compact the replayed records into the snapshot on the next flush
+            self.mark_dirty();
+            info!("Replayed {} WAL records from {}", count, wal_path.display());
+        }
+
+        *self.wal.lock().unwrap() = Some(WriteAheadLog::open(wal_path)?);
+        Ok(())
+    }
+
+    /// Append a mutation to the WAL, then pass it on to connected replicas. Called
+    /// under the write lock before the mutation is applied, so both see mutations in
+    /// the order they are applied; nothing is sent on when the WAL append fails.
+    fn log_mutation(&self, db: usize, command: &DatabaseCommand) -> Result<()> {
+        if let Some(wal) = self.wal.lock().unwrap().as_mut() {
+            wal.append(db, command)?;
+        }
+        if self.replicas.receiver_count() > 0 {
+            let command = ReplicationCommand { db, command: command.clone() };
+            // Only fails if the last replica disconnected meanwhile
+            let _ = self.replicas.send(ReplicationEvent::Command(command));
+        }
+        Ok(())
+    }
+
+    /// Save without keeping writers out for the whole save. The store is serialized
+    /// into the temp file under a read lock, so reads carry on and writes wait only
+    /// for that; the fsync and rename, often the slowest part, happen after the lock
+    /// is released. The work runs on a blocking thread so the file I/O never holds up
+    /// other connections' tasks.
+    async fn save_to_disk(&self) -> Result<()> {
+        if !self.backend.is_persistent() {
+            return Ok(());
+        }
+        // One save at a time, so WAL checkpoints are discarded in the order they were taken
+        let saving = Arc::clone(&self.saving).lock_owned().await;
+        let store = Arc::clone(&self.store).read_owned().await;
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _saving = saving;
+            db.save_snapshot(store)
+        })
+        .await?
+    }
+
+    fn save_snapshot(&self, store: OwnedRwLockReadGuard<Databases>) -> Result<()> {
+        let started = Instant::now();
+        // No write can land while the read lock is held, so the snapshot and the WAL
+        // checkpoint both cover exactly the writes counted here
+        let writes = self.writes_since_save.swap(0, Ordering::AcqRel);
+        let prepared = self.backend.begin_save(&store, self.storage)
+            .and_then(|pending| Ok((pending, self.wal_checkpoint()?)));
+        drop(store);
+        let locked_for = started.elapsed();
+
+        let checkpoint = match prepared.and_then(|(pending, checkpoint)| {
+            pending.map(PendingSave::commit).transpose().map(|_| checkpoint)
+        }) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                self.writes_since_save.fetch_add(writes, Ordering::AcqRel);
+                return Err(e);
+            }
+        };
+        // The snapshot now holds every record logged before the checkpoint; later
+        // ones stay in the log for the next save
+        if let (Some(checkpoint), Some(wal)) = (checkpoint, self.wal.lock().unwrap().as_mut()) {
+            wal.discard_through(checkpoint)?;
+        }
+        self.last_save_at.store(SystemClock.now_secs(), Ordering::Relaxed);
+        info!(
+            "Saved database to {} in {:?}, writes held off for {:?}",
+            self.storage_path,
+            started.elapsed(),
+            locked_for,
+        );
+        Ok(())
+    }
+
+    fn wal_checkpoint(&self) -> Result<Option<WalCheckpoint>> {
+        self.wal.lock().unwrap().as_ref().map(WriteAheadLog::checkpoint).transpose()
+    }
+
+    fn mark_dirty(&self) {
+        if !self.backend.is_persistent() {
+            return;
+        }
+        self.writes_since_save.fetch_add(1, Ordering::AcqRel);
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Save immediately, reporting failure to the caller instead of only logging it
+    async fn save_now(&self) -> Result<()> {
+        self.dirty.store(false, Ordering::Release);
+        let result = self.save_to_disk().await;
+        if result.is_err() {
+            self.mark_dirty();
+        }
+        result
+    }
+
+    /// Whether enough writes have accumulated to save before the next interval
+    fn save_due(&self) -> bool {
+        self.save_after_writes
+            .is_some_and(|writes| self.writes_since_save.load(Ordering::Acquire) >= writes)
+    }
+
+    /// Save now if anything changed since the last flush
+    async fn flush_if_dirty(&self) {
+        if self.dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = self.save_to_disk().await {
+                error!("Failed to save to disk: {}", e);
+                self.mark_dirty();
+            }
+        }
+    }
+
+    /// Spawn the task that actively removes expired keys, which otherwise
+    /// linger until a client touches them
+    fn spawn_expiry_sweeper(&self) {
+        if self.read_only {
+            return;
+        }
+        let db = self.clone();
+        self.spawn_until_closed(async move {
+            let mut interval = tokio::time::interval(db.sweep_interval);
+            loop {
+                interval.tick().await;
+                db.sweep_expired().await;
+            }
+        });
+    }
+
+    /// Remove expired keys from every database in batches, releasing the
+    /// write lock between batches
+    async fn sweep_expired(&self) {
+        // Waiting for the write lock during a save would hold up every read that
+        // arrives behind the sweep, so leave expired keys to the next tick
+        let Ok(_saving) = self.saving.try_lock() else {
+            return;
+        };
+        let count = self.store.read().await.count();
+        let mut total = 0;
+        for index in 0..count {
+            loop {
+                let removed = {
+                    let mut store = self.store.write().await;
+                    let removed = store.get_mut(index).purge_expired_batch(self.sweep_batch);
+                    // Log the removals so a replayed SetEx cannot resurrect them, still
+                    // under the lock so they stay ordered before any write that re-creates a key
+                    for key in &removed {
+                        if let Err(e) = self.log_mutation(index, &DatabaseCommand::Delete { key: key.clone(), detailed: false }) {
+                            error!("Failed to append expiry to WAL: {}", e);
+                        }
+                    }
+                    self.notify_removed(index, &removed);
+                    removed
+                };
+                total += removed.len();
+                if removed.len() < self.sweep_batch {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+
+        if total > 0 {
+            self.mark_dirty();
+            info!("Swept {} expired keys", total);
+        }
+    }
+
+    /// Spawn the task that periodically flushes a dirty store to disk
+    fn spawn_flush_task(&self) {
+        if !self.backend.is_persistent() {
+            return;
+        }
+        let db = self.clone();
+        self.spawn_until_closed(async move {
+            // Nothing was written yet, so skip the immediate first tick; a save at a
+            // random point early on would also restart the count of `save_after_writes`
+            let start = tokio::time::Instant::now() + db.flush_interval;
+            let mut interval = tokio::time::interval_at(start, db.flush_interval);
+            loop {
+                interval.tick().await;
+                db.flush_if_dirty().await;
+            }
+        });
+    }
+
+    /// Run `task` on its own task until it finishes or the server shuts down
+    fn spawn_until_closed<F: Future<Output = ()> + Send + 'static>(&self, task: F) {
+        let mut closing = self.closing.subscribe();
+        tokio::spawn(async move {
+            tokio::select! {
+                // Checked first, so a task woken after shutdown stops before doing more
+                biased;
+                _ = closing.wait_for(|&closed| closed) => {}
+                _ = task => {}
+            }
+        });
+    }
+
+    fn incr_by<S: TypedDatabase>(&self, store: &mut S, key: &str, delta: i64) -> DatabaseResponse {
+        match store.incr_by(key, delta) {
+            Ok(value) => {
+                self.mark_dirty();
+                DatabaseResponse::success_with_value(value.to_string())
+            }
+            Err(e) => DatabaseResponse::error(&e.to_string()),
+        }
+    }
+
+    /// Respond to a fallible mutation, marking the store dirty when it succeeded
+    fn mutation_response(&self, result: Result<DatabaseResponse>) -> DatabaseResponse {
+        match result {
+            Ok(response) => {
+                self.mark_dirty();
+                response
+            }
+            Err(e) => DatabaseResponse::error(&e.to_string()),
+        }
+    }
+
+    /// Run a command against database `db`, timing it for the slow log
+    async fn handle_command(&self, db: usize, command: DatabaseCommand) -> DatabaseResponse {
+        let name = command.name();
+        let started = Instant::now();
+        let response = self.run_command(db, command).await;
+        self.record_duration(name, started.elapsed());
+        response
+    }
+
+    fn record_duration(&self, command: &str, elapsed: Duration) {
+        if self.slow_log.record(command, elapsed) {
+            warn!(
+                "Slow command {} took {:?} (threshold {:?})",
+                command, elapsed, self.slow_log.threshold()
+            );
+        }
+    }
+
+    async fn run_command(&self, db: usize, command: DatabaseCommand) -> DatabaseResponse {
+        self.stats.record_command(&command);
+        if let DatabaseCommand::Reload { force } = command {
+            return self.reload(force).await;
+        }
+        if !command.needs_write_lock() {
+            let is_get = matches!(command, DatabaseCommand::Get { .. });
+            let response = {
+                let store = self.store.read().await;
+                self.execute_read(store.get(db), command)
+            };
+            if is_get {
+                self.stats.record_get(&response);
+            }
+            return response;
+        }
+
+        let flush_now = matches!(command, DatabaseCommand::Clear);
+        let sync = matches!(command, DatabaseCommand::FlushDb { sync: true });
+        let response = {
+            let mut store = self.store.write().await;
+            self.execute_write(store.get_mut(db), db, command)
+        };
+        if flush_now || self.save_due() {
+            self.flush_if_dirty().await;
+        }
+        if sync && !matches!(response, DatabaseResponse::Error { .. }) {
+            if let Err(e) = self.save_now().await {
+                error!("Failed to save after FLUSHDB: {}", e);
+                return DatabaseResponse::error(&format!("ERR flushed but failed to save: {}", e));
+            }
+        }
+        response
+    }
+
+    /// Handle a command in the context of one connection's transaction state
+    async fn handle_session_command(&self, session: &mut Session, command: DatabaseCommand) -> DatabaseResponse {
+        if let DatabaseCommand::Auth { password } = command {
+            return match &self.password {
+                Some(expected) if *expected == password => {
+                    session.authenticated = true;
+                    DatabaseResponse::success()
+                }
+                Some(_) => DatabaseResponse::error("WRONGPASS invalid password"),
+                None => DatabaseResponse::error("ERR AUTH called without a password configured"),
+            };
+        }
+        if let DatabaseCommand::Health = command {
+            return self.health().await;
+        }
+        if let DatabaseCommand::Hello { protocol } = command {
+            if protocol < MIN_PROTOCOL_VERSION {
+                return DatabaseResponse::error(&format!("ERR unsupported protocol version {}", protocol));
+            }
+            session.protocol = protocol.min(PROTOCOL_VERSION);
+            return DatabaseResponse::hello(session.protocol);
+        }
+        if let Some(refusal) = self.refuse(session, &command) {
+            return refusal;
+        }
+
+        match command {
+            DatabaseCommand::Select { .. } if session.queue.is_some() => {
+                DatabaseResponse::error("ERR SELECT is not allowed inside MULTI")
+            }
+            DatabaseCommand::Select { index } => {
+                if index >= self.store.read().await.count() {
+                    return DatabaseResponse::error("ERR DB index is out of range");
+                }
+                session.db = index;
+                DatabaseResponse::success()
+            }
+            DatabaseCommand::Subscribe { .. } | DatabaseCommand::Watch { .. } if !session.can_subscribe => {
+                DatabaseResponse::error("ERR SUBSCRIBE and WATCH need the framed protocol")
+            }
+            DatabaseCommand::Subscribe { .. } if session.queue.is_some() => {
+                DatabaseResponse::error("ERR SUBSCRIBE is not allowed inside MULTI")
+            }
+            DatabaseCommand::Subscribe { channel } => {
+                let receiver = self.pubsub.subscribe(&channel);
+                session.subscription = Some(Subscription::Channel(channel, receiver));
+                DatabaseResponse::success()
+            }
+            DatabaseCommand::Watch { .. } if session.queue.is_some() => {
+                DatabaseResponse::error("ERR WATCH is not allowed inside MULTI")
+            }
+            DatabaseCommand::Replicate if !session.can_subscribe => {
+                DatabaseResponse::error("ERR REPLICATE needs the framed protocol")
+            }
+            DatabaseCommand::Replicate if session.queue.is_some() => {
+                DatabaseResponse::error("ERR REPLICATE is not allowed inside MULTI")
+            }
+            DatabaseCommand::Replicate => {
+                let store = self.store.read().await;
+                // Writers send to replicas under the write lock, so subscribing under the
+                // read lock yields exactly the mutations that follow the snapshot
+                let receiver = self.replicas.subscribe();
+                match serde_json::to_string(&*store) {
+                    Ok(data) => {
+                        info!("Replica connected, sending {} keys", store.total_len());
+                        session.subscription = Some(Subscription::Replica(receiver));
+                        DatabaseResponse::snapshot(data)
+                    }
+                    Err(e) => DatabaseResponse::error(&format!("ERR failed to serialize snapshot: {}", e)),
+                }
+            }
+            DatabaseCommand::Reload { .. } if session.queue.is_some() => {
+                DatabaseResponse::error("ERR RELOAD is not allowed inside MULTI")
+            }
+            DatabaseCommand::Watch { key } => {
+                let receiver = self.watchers.subscribe(&key);
+                session.subscription = Some(Subscription::Key(session.db, key, receiver));
+                DatabaseResponse::success()
+            }
+            DatabaseCommand::Multi => {
+                if session.queue.is_some() {
+                    return DatabaseResponse::error("ERR MULTI calls can not be nested");
+                }
+                session.queue = Some(Vec::new());
+                DatabaseResponse::success()
+            }
+            DatabaseCommand::Exec => match session.queue.take() {
+                Some(commands) => {
+                    let started = Instant::now();
+                    let response = self.execute_transaction(session.db, commands).await;
+                    self.record_duration("Exec", started.elapsed());
+                    response
+                }
+                None => DatabaseResponse::error("ERR EXEC without MULTI"),
+            },
+            DatabaseCommand::Discard => match session.queue.take() {
+                Some(_) => DatabaseResponse::success(),
+                None => DatabaseResponse::error("ERR DISCARD without MULTI"),
+            },
+            command => match session.queue.as_mut() {
+                Some(queue) => {
+                    queue.push(command);
+                    DatabaseResponse::queued()
+                }
+                None => self.handle_command(session.db, command).await,
+            },
+        }
+    }
+
+    /// Why `command` may not run on this connection right now, if it may not
+    fn refuse(&self, session: &Session, command: &DatabaseCommand) -> Option<DatabaseResponse> {
+        if let Some(refusal) = Self::too_new(session, command) {
+            return Some(refusal);
+        }
+        if !session.authenticated && !matches!(command, DatabaseCommand::Ping) {
+            return Some(DatabaseResponse::error("NOAUTH Authentication required"));
+        }
+        if self.loading.load(Ordering::Acquire) && !matches!(command, DatabaseCommand::Ping) {
+            return Some(DatabaseResponse::error("LOADING Dataset is being loaded"));
+        }
+        // Checked before queuing or locking, so a read-only server never takes the write lock
+        if self.read_only && command.is_mutation() {
+            return Some(DatabaseResponse::error("READONLY You can't write against a read only server"));
+        }
+        None
+    }
+
+    fn too_new(session: &Session, command: &DatabaseCommand) -> Option<DatabaseResponse> {
+        (command.min_protocol() > session.protocol).then(|| {
+            DatabaseResponse::error(&format!(
+                "ERR {} needs protocol version {}, this connection negotiated {}",
+                command.name(),
+                command.min_protocol(),
+                session.protocol,
+            ))
+        })
+    }
+
+    /// Handle the command inside a `Tagged` request.
+    ///
+    /// With `in_flight`, a command that leaves the connection's state alone is spawned
+    /// there and `None` returned; its task resolves to the tagged reply. Anything else
+    /// runs now, in order, and its reply is returned tagged.
+    async fn handle_tagged(
+        &self,
+        session: &mut Session,
+        id: u64,
+        command: DatabaseCommand,
+        in_flight: Option<&mut JoinSet<DatabaseResponse>>,
+    ) -> Option<DatabaseResponse> {
+        if let DatabaseCommand::Tagged { .. } = command {
+            return Some(DatabaseResponse::tagged(id, DatabaseResponse::error("ERR Tagged requests can not be nested")));
+        }
+        // Health is answered without the store, so it gains nothing from a task
+        let concurrent = session.queue.is_none()
+            && !command.is_connection_command()
+            && !matches!(command, DatabaseCommand::Health);
+        let Some(in_flight) = in_flight.filter(|_| concurrent) else {
+            return Some(DatabaseResponse::tagged(id, self.handle_session_command(session, command).await));
+        };
+        if let Some(refusal) = self.refuse(session, &command) {
+            return Some(DatabaseResponse::tagged(id, refusal));
+        }
+
+        let server = self.clone();
+        let db = session.db;
+        in_flight.spawn(
+            async move { DatabaseResponse::tagged(id, server.handle_command(db, command).await) }.in_current_span(),
+        );
+        None
+    }
+
+    /// Run queued commands back to back under one write lock.
+    ///
+    /// Other clients never observe a partially applied transaction, but there is no
+    /// rollback: a command that fails still leaves earlier ones applied.
+    async fn execute_transaction(&self, db: usize, commands: Vec<DatabaseCommand>) -> DatabaseResponse {
+        let flush_now = commands.iter().any(|command| {
+            matches!(command, DatabaseCommand::Clear | DatabaseCommand::FlushDb { sync: true })
+        });
+        let gets: Vec<bool> = commands
+            .iter()
+            .map(|command| {
+                self.stats.record_command(command);
+                matches!(command, DatabaseCommand::Get { .. })
+            })
+            .collect();
+        let responses: Vec<DatabaseResponse> = {
+            let mut store = self.store.write().await;
+            let store = store.get_mut(db);
+            commands
+                .into_iter()
+                .map(|command| self.execute_write(store, db, command))
+                .collect()
+        };
+        for (response, _) in responses.iter().zip(gets).filter(|(_, is_get)| *is_get) {
+            self.stats.record_get(response);
+        }
+        if flush_now || self.save_due() {
+            self.flush_if_dirty().await;
+        }
+        DatabaseResponse::results(responses)
+    }
+
+    /// Run a read-only command; expired keys are filtered out but left for writers to remove
+    fn execute_read<S: TypedDatabase>(&self, store: &S, command: DatabaseCommand) -> DatabaseResponse {
+        match command {
+            DatabaseCommand::Get { key } => {
+                match store.get_string(&key) {
+                    Ok(Some(value)) => DatabaseResponse::success_with_value(value),
+                    Ok(None) => DatabaseResponse::success(),
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::GetRange { key, start, end } => {
+                match store.get_range(&key, start, end) {
+                    Ok(value) => DatabaseResponse::Ok { value },
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::StrLen { key } => {
+                match store.strlen(&key) {
+                    Ok(len) => DatabaseResponse::length(len),
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::GetBytes { key } => {
+                match store.get_bytes(&key) {
+                    Ok(value) => DatabaseResponse::bytes(value),
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::Type { key } => {
+                DatabaseResponse::success_with_value(store.type_of(&key).to_string())
+            }
+            DatabaseCommand::Ttl { key } => {
+                DatabaseResponse::ttl(store.ttl(&key))
+            }
+            DatabaseCommand::KeyAge { key } => {
+                match store.key_age(&key) {
+                    Ok(seconds) => DatabaseResponse::age(seconds),
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::MGet { keys } => {
+                let values = keys.iter().map(|key| store.get(key)).collect();
+                DatabaseResponse::values(values)
+            }
+            DatabaseCommand::Exists { key } => {
+                let exists = store.exists(&key);
+                DatabaseResponse::boolean(exists)
+            }
+            DatabaseCommand::ExistsMany { keys } => {
+                let count = keys.iter().filter(|key| store.exists(key)).count();
+                DatabaseResponse::length(count)
+            }
+            DatabaseCommand::Keys => {
+                let keys = store.keys();
+                DatabaseResponse::keys(keys)
+            }
+            DatabaseCommand::RandomKey => {
+                DatabaseResponse::Ok { value: store.random_key() }
+            }
+            DatabaseCommand::Scan { prefix } => {
+                DatabaseResponse::keys(store.keys_with_prefix(&prefix))
+            }
+            DatabaseCommand::KeysMatching { pattern } => {
+                DatabaseResponse::keys(store.keys_matching(&pattern))
+            }
+            DatabaseCommand::ScanCursor { cursor, count } => {
+                let (keys, cursor) = store.scan_page(cursor, count);
+                DatabaseResponse::scan_page(cursor, keys)
+            }
+            DatabaseCommand::LLen { key } => {
+                match store.list_len(&key) {
+                    Ok(len) => DatabaseResponse::length(len),
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::HGet { key, field } => {
+                match store.hget(&key, &field) {
+                    Ok(value) => DatabaseResponse::Ok { value },
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::HGetAll { key } => {
+                match store.hgetall(&key) {
+                    Ok(pairs) => DatabaseResponse::pairs(pairs),
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::Len => {
+                let count = store.len();
+                DatabaseResponse::length(count)
+            }
+            DatabaseCommand::IsEmpty => {
+                DatabaseResponse::boolean(store.is_empty())
+            }
+            DatabaseCommand::Dump => {
+                match store.to_blob() {
+                    Ok(blob) => DatabaseResponse::success_with_value(blob),
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::ByteSize => {
+                DatabaseResponse::length(store.byte_size())
+            }
+            DatabaseCommand::Ping => {
+                DatabaseResponse::pong()
+            }
+            DatabaseCommand::Stats => {
+                DatabaseResponse::stats(self.stats.snapshot())
+            }
+            DatabaseCommand::Command => {
+                let commands = DatabaseCommand::NAMES.iter().map(|name| name.to_string()).collect();
+                DatabaseResponse::command_info(commands)
+            }
+            DatabaseCommand::Persistence => {
+                DatabaseResponse::persistence(self.persistence_status())
+            }
+            DatabaseCommand::SlowLog => {
+                DatabaseResponse::slow_log(self.slow_log.entries())
+            }
+            DatabaseCommand::Publish { channel, message } => {
+                DatabaseResponse::length(self.pubsub.publish(&channel, message))
+            }
+            DatabaseCommand::Auth { .. }
+            | DatabaseCommand::Health
+            | DatabaseCommand::Hello { .. }
+            | DatabaseCommand::Subscribe { .. }
+            | DatabaseCommand::Watch { .. }
+            | DatabaseCommand::Select { .. }
+            | DatabaseCommand::Multi
+            | DatabaseCommand::Exec
+            | DatabaseCommand::Discard
+            | DatabaseCommand::Tagged { .. } => {
+                DatabaseResponse::error("ERR transaction commands are handled per connection")
+            }
+            command => DatabaseResponse::error(&format!("Command needs write access: {:?}", command)),
+        }
+    }
+
+    /// Run any command with exclusive access, logging mutations to the WAL first
+    fn execute_write<S: TypedDatabase>(&self, store: &mut S, db: usize, command: DatabaseCommand) -> DatabaseResponse {
+        if let Some(message) = self.oversized(&command) {
+            return DatabaseResponse::error(message);
+        }
+        if let (true, Some(max_keys)) = (self.reject_over_capacity, self.max_keys) {
+            if Self::would_exceed(store, &command, max_keys) {
+                return DatabaseResponse::error("OOM: max keys reached");
+            }
+        }
+        if command.is_mutation() {
+            if let Err(e) = self.log_mutation(db, &command) {
+                error!("Failed to append to WAL: {}", e);
+                return DatabaseResponse::error("Failed to write to WAL");
+            }
+        }
+        let before = self.values_before(store, &command);
+        let whole_db = matches!(
+            command,
+            DatabaseCommand::Clear | DatabaseCommand::FlushDb { .. } | DatabaseCommand::Restore { .. }
+        );
+
+        let response = match command {
+            DatabaseCommand::Set { key, value, opts: None } => {
+                let old_value = store.set(key, value);
+                self.mark_dirty();
+                DatabaseResponse::Ok { value: old_value }
+            }
+            DatabaseCommand::Set { key, value, opts: Some(opts) } => {
+                match store.set_with_options(key, value, opts) {
+                    Ok((outcome, old_value)) => {
+                        if outcome != SetOutcome::Skipped {
+                            self.mark_dirty();
+                        }
+                        DatabaseResponse::set_result(outcome, old_value)
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::SetBytes { key, value } => {
+                store.set_bytes(key, value);
+                self.mark_dirty();
+                DatabaseResponse::success()
+            }
+            DatabaseCommand::GetSet { key, value } => {
+                match store.get_set(key, value) {
+                    Ok(old_value) => {
+                        self.mark_dirty();
+                        DatabaseResponse::Ok { value: old_value }
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::SetNx { key, value } => {
+                let inserted = store.set_nx(key, value);
+                if inserted {
+                    self.mark_dirty();
+                }
+                let flag = if inserted { "1" } else { "0" };
+                DatabaseResponse::success_with_value(flag.to_string())
+            }
+            DatabaseCommand::Cas { key, expected, new } => {
+                match store.cas(key, expected, new) {
+                    Ok(swapped) => {
+                        if swapped {
+                            self.mark_dirty();
+                        }
+                        DatabaseResponse::boolean(swapped)
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::SetEx { key, value, seconds } => {
+                match store.set_with_expiry(key, value, seconds) {
+                    Ok(old_value) => {
+                        self.mark_dirty();
+                        DatabaseResponse::Ok { value: old_value }
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::Expire { key, seconds } => {
+                match store.expire(&key, seconds) {
+                    Ok(updated) => {
+                        if updated {
+                            self.mark_dirty();
+                        }
+                        DatabaseResponse::boolean(updated)
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::MSet { pairs } => {
+                for (key, value) in pairs {
+                    store.set(key, value);
+                }
+                self.mark_dirty();
+                DatabaseResponse::success()
+            }
+            DatabaseCommand::Append { key, value } => {
+                match store.append(&key, &value) {
+                    Ok(len) => {
+                        self.mark_dirty();
+                        DatabaseResponse::success_with_value(len.to_string())
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::SetRange { key, offset, value } => {
+                match store.set_range(&key, offset, &value) {
+                    Ok(len) => {
+                        self.mark_dirty();
+                        DatabaseResponse::success_with_value(len.to_string())
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::Incr { key } => self.incr_by(store, &key, 1),
+            DatabaseCommand::Decr { key } => self.incr_by(store, &key, -1),
+            DatabaseCommand::IncrBy { key, delta } => self.incr_by(store, &key, delta),
+            DatabaseCommand::LPush { key, values } => {
+                let result = store.push_front(&key, values);
+                self.mutation_response(result.map(DatabaseResponse::length))
+            }
+            DatabaseCommand::RPush { key, values } => {
+                let result = store.push_back(&key, values);
+                self.mutation_response(result.map(DatabaseResponse::length))
+            }
+            DatabaseCommand::LPop { key } => {
+                let result = store.pop_front(&key);
+                self.mutation_response(result.map(|value| DatabaseResponse::Ok { value }))
+            }
+            DatabaseCommand::RPop { key } => {
+                let result = store.pop_back(&key);
+                self.mutation_response(result.map(|value| DatabaseResponse::Ok { value }))
+            }
+            DatabaseCommand::HSet { key, field, value } => {
+                let result = store.hset(&key, field, value);
+                self.mutation_response(result.map(DatabaseResponse::boolean))
+            }
+            DatabaseCommand::HDel { key, field } => {
+                let result = store.hdel(&key, &field);
+                self.mutation_response(result.map(DatabaseResponse::boolean))
+            }
+            DatabaseCommand::Rename { from, to } => {
+                let result = store.rename(&from, &to);
+                self.mutation_response(result.map(|()| DatabaseResponse::success()))
+            }
+            DatabaseCommand::RenameNx { from, to } => {
+                match store.rename_nx(&from, &to) {
+                    Ok(renamed) => {
+                        if renamed {
+                            self.mark_dirty();
+                        }
+                        DatabaseResponse::boolean(renamed)
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::Copy { from, to, replace } => {
+                match store.copy(&from, &to, replace) {
+                    Ok(copied) => {
+                        if copied {
+                            self.mark_dirty();
+                        }
+                        DatabaseResponse::boolean(copied)
+                    }
+                    Err(e) => DatabaseResponse::error(&e.to_string()),
+                }
+            }
+            DatabaseCommand::Delete { key, detailed } => {
+                let deleted = store.exists(&key);
+                let old_value = store.delete(&key);
+                self.mark_dirty();
+                if detailed {
+                    DatabaseResponse::deleted(old_value, deleted)
+                } else {
+                    DatabaseResponse::Ok { value: old_value }
+                }
+            }
+            DatabaseCommand::Touch { keys } => DatabaseResponse::length(store.touch(&keys)),
+            DatabaseCommand::DeleteMany { keys } => {
+                let count = store.delete_many(&keys);
+                if count > 0 {
+                    self.mark_dirty();
+                }
+                DatabaseResponse::length(count)
+            }
+            DatabaseCommand::Clear | DatabaseCommand::FlushDb { .. } => {
+                store.clear();
+                self.mark_dirty();
+                self.publish_mutation(MutationEvent::Clear { db });
+                DatabaseResponse::success()
+            }
+            DatabaseCommand::Restore { data } => {
+                let result = store.restore(&data).map(|()| {
+                    self.publish_mutation(MutationEvent::Clear { db });
+                    DatabaseResponse::success()
+                });
+                self.mutation_response(result)
+            }
+            command => return self.execute_read(store, command),
+        };
+        self.notify_changes(store, db, before, whole_db);
+        self.enforce_max_keys(store, db);
+        response
+    }
+
+    /// Current values of the keys `command` may set or delete, to diff afterwards for
+    /// watchers and mutation subscribers; the outer `None` marks a value that is not a
+    /// string. Of a whole database, only the watched keys are looked at.
+    fn values_before<S: TypedDatabase>(&self, store: &S, command: &DatabaseCommand) -> Vec<(String, Option<Option<String>>)> {
+        if self.watchers.is_empty() && self.mutations.receiver_count() == 0 {
+            return Vec::new();
         }
+        let keys = match command {
+            DatabaseCommand::Set { key, .. }
+            | DatabaseCommand::GetSet { key, .. }
+            | DatabaseCommand::SetNx { key, .. }
+            | DatabaseCommand::Cas { key, .. }
+            | DatabaseCommand::SetEx { key, .. }
+            | DatabaseCommand::Append { key, .. }
+            | DatabaseCommand::SetRange { key, .. }
+            | DatabaseCommand::Incr { key }
+            | DatabaseCommand::Decr { key }
+            | DatabaseCommand::IncrBy { key, .. }
+            | DatabaseCommand::Delete { key, .. } => vec![key.clone()],
+            DatabaseCommand::MSet { pairs } => pairs.iter().map(|(key, _)| key.clone()).collect(),
+            DatabaseCommand::DeleteMany { keys } => keys.clone(),
+            DatabaseCommand::Rename { from, to } | DatabaseCommand::RenameNx { from, to } => {
+                vec![from.clone(), to.clone()]
+            }
+            DatabaseCommand::Copy { to, .. } => vec![to.clone()],
+            DatabaseCommand::Clear
+            | DatabaseCommand::FlushDb { .. }
+            | DatabaseCommand::Restore { .. } => self.watchers.names(),
+            _ => return Vec::new(),
+        };
+        keys.into_iter()
+            .map(|key| {
+                let value = store.get_string(&key).ok();
+                (key, value)
+            })
+            .collect()
     }
 
-    async fn load_from_disk(&self) -> Result<()> {
-        let store = KeyValueStore::load_from_file(&self.storage_path)?;
-        *self.store.lock().await = store;
-        info!("Loaded database from {}", self.storage_path);
-        Ok(())
+    /// Send an event for each key whose string value changed to its watchers and to
+    /// mutation subscribers, who already got a `Clear` when the write covered the
+    /// `whole_db`. Values that are not strings (lists, hashes) are not reported, but
+    /// removing them is.
+    fn notify_changes<S: TypedDatabase>(&self, store: &S, db: usize, before: Vec<(String, Option<Option<String>>)>, whole_db: bool) {
+        for (key, before) in before {
+            let Ok(value) = store.get_string(&key) else {
+                continue;
+            };
+            if before.as_ref() == Some(&value) {
+                continue;
+            }
+            if !whole_db {
+                self.publish_mutation(match &value {
+                    Some(value) => MutationEvent::Set { db, key: key.clone(), value: value.clone() },
+                    None => MutationEvent::Delete { db, key: key.clone() },
+                });
+            }
+            self.watchers.publish(&key, KeyEvent { db, key: key.clone(), value });
+        }
     }
 
-    async fn save_to_disk(&self) -> Result<()> {
-        let store = self.store.lock().await;
-        store.save_to_file(&self.storage_path)?;
-        info!("Saved database to {}", self.storage_path);
-        Ok(())
+    /// Send tombstones for keys removed by expiry or eviction
+    fn notify_removed(&self, db: usize, keys: &[String]) {
+        if self.watchers.is_empty() && self.mutations.receiver_count() == 0 {
+            return;
+        }
+        for key in keys {
+            self.publish_mutation(MutationEvent::Delete { db, key: key.clone() });
+            self.watchers.publish(key, KeyEvent { db, key: key.clone(), value: None });
+        }
     }
 
-    async fn handle_command(&self, command: DatabaseCommand) -> DatabaseResponse {
-        let mut store = self.store.lock().await;
-        
-        match command {
-            DatabaseCommand::Get { key } => {
-                match store.get(&key) {
-                    Some(value) => DatabaseResponse::success_with_value(value),
-                    None => DatabaseResponse::success(),
-                }
+    /// Send a mutation event to subscribers, if there are any. Best effort: a
+    /// subscriber that falls behind misses the oldest events.
+    fn publish_mutation(&self, event: MutationEvent) {
+        if self.mutations.receiver_count() > 0 {
+            let _ = self.mutations.send(event);
+        }
+    }
+
+    /// Tell mutation subscribers that every database was replaced, e.g. by `Reload`
+    fn publish_replaced(&self, store: &Databases) {
+        for db in 0..store.count() {
+            self.publish_mutation(MutationEvent::Clear { db });
+        }
+    }
+
+    /// The error for a write whose key or value exceeds the configured size limits
+    fn oversized(&self, command: &DatabaseCommand) -> Option<&'static str> {
+        if self.max_key_size.is_none() && self.max_value_size.is_none() {
+            return None;
+        }
+        let (keys, values): (Vec<&String>, Vec<usize>) = match command {
+            DatabaseCommand::Set { key, value, .. }
+            | DatabaseCommand::GetSet { key, value }
+            | DatabaseCommand::SetNx { key, value }
+            | DatabaseCommand::SetEx { key, value, .. }
+            | DatabaseCommand::Append { key, value }
+            | DatabaseCommand::Cas { key, new: value, .. } => (vec![key], vec![value.len()]),
+            // The value written ends at offset + len, whatever was there before
+            DatabaseCommand::SetRange { key, offset, value } => (vec![key], vec![offset.saturating_add(value.len())]),
+            DatabaseCommand::SetBytes { key, value } => (vec![key], vec![value.len()]),
+            DatabaseCommand::HSet { key, value, .. } => (vec![key], vec![value.len()]),
+            DatabaseCommand::LPush { key, values } | DatabaseCommand::RPush { key, values } => {
+                (vec![key], values.iter().map(String::len).collect())
             }
-            DatabaseCommand::Set { key, value } => {
-                let old_value = store.set(key.clone(), value.clone());
-                drop(store); // Release lock before async operation
-                if let Err(e) = self.save_to_disk().await {
-                    error!("Failed to save to disk: {}", e);
-                }
-                DatabaseResponse::Ok { value: old_value }
+            DatabaseCommand::MSet { pairs } => {
+                pairs.iter().map(|(key, value)| (key, value.len())).unzip()
             }
-            DatabaseCommand::Delete { key } => {
-                let old_value = store.delete(&key);
-                drop(store);
-                if let Err(e) = self.save_to_disk().await {
-                    error!("Failed to save to disk: {}", e);
-                }
-                DatabaseResponse::Ok { value: old_value }
+            DatabaseCommand::Incr { key }
+            | DatabaseCommand::Decr { key }
+            | DatabaseCommand::IncrBy { key, .. } => (vec![key], Vec::new()),
+            DatabaseCommand::Rename { to, .. }
+            | DatabaseCommand::RenameNx { to, .. }
+            | DatabaseCommand::Copy { to, .. } => (vec![to], Vec::new()),
+            _ => return None,
+        };
+        if let Some(max) = self.max_key_size {
+            if keys.iter().any(|key| key.len() > max) {
+                return Some("ERR key too large");
             }
-            DatabaseCommand::Exists { key } => {
-                let exists = store.exists(&key);
-                DatabaseResponse::success_with_value(exists.to_string())
+        }
+        if let Some(max) = self.max_value_size {
+            if values.iter().any(|&len| len > max) {
+                return Some("ERR value too large");
             }
-            DatabaseCommand::Keys => {
-                let keys = store.keys();
-                DatabaseResponse::keys(keys)
+        }
+        None
+    }
+
+    /// Whether `command` would create enough new keys to take `store` past `max_keys`
+    fn would_exceed<S: TypedDatabase>(store: &S, command: &DatabaseCommand, max_keys: usize) -> bool {
+        let targets: Vec<&String> = match command {
+            DatabaseCommand::Set { opts: Some(opts), .. } if opts.only_if_exists => return false,
+            DatabaseCommand::Set { key, .. }
+            | DatabaseCommand::SetBytes { key, .. }
+            | DatabaseCommand::GetSet { key, .. }
+            | DatabaseCommand::SetNx { key, .. }
+            | DatabaseCommand::Cas { key, .. }
+            | DatabaseCommand::SetEx { key, .. }
+            | DatabaseCommand::Append { key, .. }
+            | DatabaseCommand::SetRange { key, .. }
+            | DatabaseCommand::Incr { key }
+            | DatabaseCommand::Decr { key }
+            | DatabaseCommand::IncrBy { key, .. }
+            | DatabaseCommand::LPush { key, .. }
+            | DatabaseCommand::RPush { key, .. }
+            | DatabaseCommand::HSet { key, .. } => vec![key],
+            DatabaseCommand::MSet { pairs } => pairs.iter().map(|(key, _)| key).collect(),
+            DatabaseCommand::Copy { to, .. } => vec![to],
+            _ => return false,
+        };
+        let added: HashSet<&String> = targets
+            .into_iter()
+            .filter(|key| !store.exists(key))
+            .collect();
+        // Expired keys do not count against the limit
+        !added.is_empty() && store.len() + added.len() > max_keys
+    }
+
+    /// Evict least-recently-used keys once database `db` exceeds `max_keys`,
+    /// logging each eviction to the WAL so replay reaches the same state
+    fn enforce_max_keys<S: TypedDatabase>(&self, store: &mut S, db: usize) {
+        let Some(max_keys) = self.max_keys.filter(|_| !self.reject_over_capacity) else {
+            return;
+        };
+        let evicted = store.evict_lru(max_keys);
+        if evicted.is_empty() {
+            return;
+        }
+
+        for key in &evicted {
+            if let Err(e) = self.log_mutation(db, &DatabaseCommand::Delete { key: key.clone(), detailed: false }) {
+                error!("Failed to append eviction to WAL: {}", e);
             }
-            DatabaseCommand::Len => {
-                let count = store.len();
-                DatabaseResponse::length(count)
+        }
+        self.notify_removed(db, &evicted);
+        self.mark_dirty();
+        info!("Evicted {} keys from database {} to stay within {} keys", evicted.len(), db, max_keys);
+    }
+
+    /// Serve one client connection until it closes.
+    ///
+    /// Works over any byte stream, so a `tokio::io::duplex` pipe can stand in for a socket.
+    ///
+    /// Logs inside a `connection` span carrying a `connection_id`, and each command
+    /// inside a nested `command` span with its sequence number `seq` on the connection.
+    pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<(), ServerError> {
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let span = info_span!("connection", connection_id);
+        let served = match self.protocol {
+            WireProtocol::Framed => self.serve_connection(stream).instrument(span).await,
+            WireProtocol::Ndjson => self.serve_ndjson_connection(stream).instrument(span).await,
+        };
+        served.map_err(ServerError::Connection)
+    }
+
+    /// Parse and run one command, answering malformed input with an error.
+    ///
+    /// Returns `None` when a `Tagged` command was spawned on `in_flight` instead.
+    async fn handle_request(
+        &self,
+        session: &mut Session,
+        data: &[u8],
+        in_flight: Option<&mut JoinSet<DatabaseResponse>>,
+    ) -> Option<DatabaseResponse> {
+        let command: DatabaseCommand = match serde_json::from_slice(data) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                error!("Failed to parse command: {}", e);
+                return Some(match serde_json::from_slice(data) {
+                    Ok(value) => invalid_command(&value, &e),
+                    Err(_) => DatabaseResponse::error(&format!("Invalid command: {}", e)),
+                });
             }
-            DatabaseCommand::Clear => {
-                store.clear();
-                drop(store);
-                if let Err(e) = self.save_to_disk().await {
-                    error!("Failed to save to disk: {}", e);
+        };
+
+        if session.rate_limit.as_mut().is_some_and(|bucket| !bucket.try_acquire()) {
+            warn!("Refusing {} from a client over its rate limit", command.name());
+            let refusal = DatabaseResponse::error("ERR rate limited");
+            return Some(match command {
+                DatabaseCommand::Tagged { id, .. } => DatabaseResponse::tagged(id, refusal),
+                _ => refusal,
+            });
+        }
+
+        session.seq += 1;
+        let span = info_span!("command", seq = session.seq, db = session.db);
+        async {
+            let logged = match &command {
+                DatabaseCommand::Tagged { command, .. } => command,
+                command => command,
+            };
+            if let DatabaseCommand::Auth { .. } = logged {
+                info!("Received command: Auth");
+            } else {
+                info!("Received command: {:?}", command);
+            }
+            if matches!(command, DatabaseCommand::Tagged { .. }) {
+                if let Some(refusal) = Self::too_new(session, &command) {
+                    return Some(refusal);
                 }
-                DatabaseResponse::success()
             }
-            DatabaseCommand::Ping => {
-                DatabaseResponse::pong()
+            match command {
+                DatabaseCommand::Tagged { id, command } => self.handle_tagged(session, id, *command, in_flight).await,
+                command => Some(self.handle_session_command(session, command).await),
             }
         }
+        .instrument(span)
+        .await
     }
 
-    async fn handle_client(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = vec![0; 1024];
-        
+    async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<()> {
+        let mut session = Session::new(self.password.is_none(), true, self.rate_limit);
+        let (reader, mut writer) = tokio::io::split(stream);
+        // Owned so a half-read frame survives writing a tagged reply in between
+        let mut next_frame = Box::pin(read_owned_frame(reader, self.max_frame_size));
+        // Tagged commands still running, each resolving to its reply
+        let mut in_flight = JoinSet::new();
         loop {
-            match stream.read(&mut buffer).await {
-                Ok(0) => {
+            // The connection is not idle while it waits on its own commands
+            let idle = in_flight.is_empty();
+            let (reader, read) = tokio::select! {
+                Some(done) = in_flight.join_next() => {
+                    match done {
+                        Ok(response) => write_frame(&mut writer, &serde_json::to_vec(&response)?).await?,
+                        Err(e) => {
+                            error!("Tagged command failed: {}", e);
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                read = async {
+                    if idle {
+                        self.until_idle(&mut next_frame).await
+                    } else {
+                        Some((&mut next_frame).await)
+                    }
+                } => match read {
+                    Some(read) => read,
+                    None => break,
+                },
+            };
+            let data = match read {
+                Ok(Some(data)) => data,
+                Ok(None) => {
                     info!("Client disconnected");
                     break;
                 }
-                Ok(n) => {
-                    let data = &buffer[..n];
-                    
-                    let command: DatabaseCommand = match serde_json::from_slice(data) {
-                        Ok(cmd) => cmd,
-                        Err(e) => {
-                            error!("Failed to parse command: {}", e);
-                            let response = DatabaseResponse::error(&format!("Invalid command: {}", e));
-                            let response_data = serde_json::to_vec(&response)?;
-                            stream.write_all(&response_data).await?;
-                            continue;
-                        }
-                    };
-
-                    info!("Received command: {:?}", command);
-                    
-                    let response = self.handle_command(command).await;
-                    
-                    let response_data = serde_json::to_vec(&response)?;
-                    stream.write_all(&response_data).await?;
+                Err(e) if e.is::<FrameTooLarge>() => {
+                    warn!("Closing connection: {}", e);
+                    let response = DatabaseResponse::error(&format!("ERR {}", e));
+                    write_frame(&mut writer, &serde_json::to_vec(&response)?).await?;
+                    break;
                 }
                 Err(e) => {
                     error!("Error reading from stream: {}", e);
                     break;
                 }
+            };
+
+            if let Some(response) = self.handle_request(&mut session, &data, Some(&mut in_flight)).await {
+                let response_data = serde_json::to_vec(&response)?;
+                write_frame(&mut writer, &response_data).await?;
+            }
+
+            let Some(subscription) = session.subscription.take() else {
+                next_frame = Box::pin(read_owned_frame(reader, self.max_frame_size));
+                continue;
+            };
+            // Tagged commands sent before subscribing still get their replies
+            while let Some(done) = in_flight.join_next().await {
+                write_frame(&mut writer, &serde_json::to_vec(&done?)?).await?;
+            }
+            let stream = reader.unsplit(writer);
+            return match subscription {
+                Subscription::Channel(channel, receiver) => {
+                    let result = self.stream_notifications(stream, &channel, receiver, |payload| {
+                        Some(DatabaseResponse::message(channel.clone(), payload))
+                    }).await;
+                    self.pubsub.release(&channel);
+                    result
+                }
+                Subscription::Key(db, key, receiver) => {
+                    let result = self.stream_notifications(stream, &key, receiver, |event| {
+                        (event.db == db).then(|| DatabaseResponse::key_changed(event.key, event.value))
+                    }).await;
+                    self.watchers.release(&key);
+                    result
+                }
+                Subscription::Replica(receiver) => self.stream_replication(stream, receiver).await,
+            };
+        }
+
+        // Let commands the client already sent finish even though nobody reads the replies
+        in_flight.detach_all();
+        Ok(())
+    }
+
+    /// Wait for `read`, giving up with `None` once the idle timeout passes
+    async fn until_idle<F: Future>(&self, read: F) -> Option<F::Output> {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Some(read.await);
+        };
+        match tokio::time::timeout(idle_timeout, read).await {
+            Ok(output) => Some(output),
+            Err(_) => {
+                info!("Closing connection idle for {:?}", idle_timeout);
+                None
+            }
+        }
+    }
+
+    /// Serve a connection speaking NDJSON: one command per line in, one response per line out.
+    ///
+    /// Lines longer than the maximum frame size get an error and close the connection.
+    async fn serve_ndjson_connection<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<()> {
+        let mut session = Session::new(self.password.is_none(), false, self.rate_limit);
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            // One byte past the limit tells an oversized line from one that just fits
+            let limit = self.max_frame_size as u64 + 1;
+            let Some(read) = self.until_idle((&mut reader).take(limit).read_until(b'\n', &mut line)).await else {
+                break;
+            };
+            if read? == 0 {
+                info!("Client disconnected");
+                break;
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            } else if line.len() > self.max_frame_size {
+                let e = FrameTooLarge { len: line.len(), max: self.max_frame_size };
+                warn!("Closing connection: {}", e);
+                let mut response_data = serde_json::to_vec(&DatabaseResponse::error(&format!("ERR {}", e)))?;
+                response_data.push(b'\n');
+                writer.write_all(&response_data).await?;
+                break;
+            }
+            if line.trim_ascii().is_empty() {
+                continue;
             }
+
+            // Tagged commands run in order here: a line-based client reads one reply at a time
+            let Some(response) = self.handle_request(&mut session, &line, None).await else {
+                continue;
+            };
+
+            let mut response_data = serde_json::to_vec(&response)?;
+            response_data.push(b'\n');
+            writer.write_all(&response_data).await?;
+            writer.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forward notifications from `receiver` until the client disconnects, skipping
+    /// those `to_response` maps to `None`.
+    ///
+    /// The client may still send `Ping`; anything else is answered with an error.
+    async fn stream_notifications<S, T, F>(
+        &self,
+        stream: S,
+        channel: &str,
+        mut receiver: broadcast::Receiver<T>,
+        to_response: F,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        T: Clone,
+        F: Fn(T) -> Option<DatabaseResponse>,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        // Owned so a half-read frame survives a message arriving in between
+        let mut next_frame = Box::pin(read_owned_frame(reader, self.max_frame_size));
+
+        loop {
+            let response = tokio::select! {
+                notification = receiver.recv() => match notification {
+                    Ok(notification) => match to_response(notification) {
+                        Some(response) => response,
+                        None => continue,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Subscriber to {} fell behind and missed {} messages", channel, skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                (reader, frame) = &mut next_frame => {
+                    match frame {
+                        Ok(Some(data)) => {
+                            next_frame = Box::pin(read_owned_frame(reader, self.max_frame_size));
+                            match serde_json::from_slice(&data) {
+                                Ok(DatabaseCommand::Ping) => DatabaseResponse::pong(),
+                                _ => DatabaseResponse::error("ERR only Ping is allowed while subscribed"),
+                            }
+                        }
+                        Ok(None) => {
+                            info!("Subscriber to {} disconnected", channel);
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Error reading from stream: {}", e);
+                            break;
+                        }
+                    }
+                }
+            };
+            let response_data = serde_json::to_vec(&response)?;
+            write_frame(&mut writer, &response_data).await?;
+        }
+
+        writer.shutdown().await.ok();
+        Ok(())
+    }
+
+    fn persistence_status(&self) -> PersistenceStatus {
+        let path = std::path::absolute(&self.storage_path)
+            .unwrap_or_else(|_| PathBuf::from(&self.storage_path));
+        let last_save_at = self.last_save_at.load(Ordering::Relaxed);
+        PersistenceStatus {
+            backend: self.backend.kind().to_string(),
+            path: path.display().to_string(),
+            format: self.storage.format.to_string(),
+            compressed: self.storage.compress,
+            last_save_at: (last_save_at > 0).then_some(last_save_at),
+            pending: self.dirty.load(Ordering::Acquire),
+        }
+    }
+
+    /// Readiness for HEALTH; never waits on the store while a load is in progress
+    async fn health(&self) -> DatabaseResponse {
+        let loaded = !self.loading.load(Ordering::Acquire);
+        let keys = if loaded { self.store.read().await.total_len() } else { 0 };
+        DatabaseResponse::health(HealthStatus { loaded, keys })
+    }
+
+    /// Close open connections and stop background tasks, then flush the store one
+    /// last time before the server stops
+    async fn shutdown(&self) -> Result<()> {
+        self.closing.send_replace(true);
+        if self.loading.load(Ordering::Acquire) {
+            info!("Stopped before loading finished; not saving");
+            return Ok(());
         }
-        
+        if !self.backend.is_persistent() {
+            info!("Stopped the {} backend; nothing to save", self.backend.kind());
+            return Ok(());
+        }
+        if self.read_only && !self.dirty.load(Ordering::Acquire) {
+            info!("Read-only server stopped without saving");
+            return Ok(());
+        }
+        self.dirty.store(false, Ordering::Release);
+        self.save_to_disk().await?;
+        let count = self.store.read().await.total_len();
+        info!("Persisted {} keys to {} on shutdown", count, self.storage_path);
         Ok(())
     }
 
-    pub async fn start_server(&self, addr: &str) -> Result<()> {
+    /// Load the snapshot and replay the WAL before serving
+    async fn prepare(&self) -> Result<()> {
+        if !self.backend.is_persistent() {
+            if self.wal_path.is_some() {
+                warn!("Ignoring the WAL with the {} backend", self.backend.kind());
+            }
+            info!("Using the {} backend; starting empty", self.backend.kind());
+            return Ok(());
+        }
         if let Err(e) = self.load_from_disk().await {
+            // Starting empty would overwrite the damaged file on the next save
+            if e.downcast_ref::<StorageError>().is_some() {
+                return Err(e);
+            }
             warn!("Failed to load from disk: {}", e);
         }
+        self.replay_wal().await
+    }
 
-        let listener = TcpListener::bind(addr).await?;
-        info!("Mini database server listening on {}", addr);
+    /// Load in the background so PING and HEALTH are answered meanwhile, then start the
+    /// flush task and expiry sweeper. The handle resolves to the load's result.
+    fn spawn_loader(&self) -> JoinHandle<Result<()>> {
+        self.loading.store(true, Ordering::Release);
+        let db = self.clone();
+        tokio::spawn(async move {
+            db.prepare().await?;
+            db.loading.store(false, Ordering::Release);
+            info!("Finished loading, ready to accept commands");
+            db.spawn_flush_task();
+            db.spawn_expiry_sweeper();
+            db.spawn_replication();
+            Ok(())
+        })
+    }
 
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    info!("New client connected: {}", addr);
+    /// Wait for a free connection slot before accepting, so a full server leaves new
+    /// connections in the listen backlog. `None` when unlimited or rejecting instead.
+    async fn wait_for_slot(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.connection_slots {
+            Some(slots) if !self.reject_over_connections => {
+                Some(Arc::clone(slots).acquire_owned().await.expect("connection slots are never closed"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Serve one accepted connection on its own task, holding `slot` until it closes.
+    ///
+    /// Without a slot, one is taken now if the connections are limited; when none is
+    /// free the client is sent an error and disconnected.
+    fn spawn_client<S>(&self, stream: S, slot: Option<OwnedSemaphorePermit>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let slot = match (slot, &self.connection_slots) {
+            (None, Some(slots)) => match Arc::clone(slots).try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    warn!("Rejecting connection: max number of clients reached");
                     let db = self.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = db.handle_client(stream).await {
-                            error!("Error handling client: {}", e);
+                        if let Err(e) = db.reject_client(stream).await {
+                            error!("Error rejecting client: {}", e);
                         }
                     });
+                    return;
                 }
+            },
+            (slot, _) => slot,
+        };
+
+        let open = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        info!("{} connections open", open);
+        let db = self.clone();
+        let mut closing = self.closing.subscribe();
+        tokio::spawn(async move {
+            tokio::select! {
+                biased;
+                _ = closing.wait_for(|&closed| closed) => info!("Closing connection for shutdown"),
+                served = db.handle_client(stream) => {
+                    if let Err(e) = served {
+                        error!("Error handling client: {:#}", anyhow::Error::new(e));
+                    }
+                }
+            }
+            drop(slot);
+            let open = db.connections.fetch_sub(1, Ordering::Relaxed) - 1;
+            info!("{} connections open", open);
+        });
+    }
+
+    /// Tell a client over the connection limit why it is being disconnected.
+    ///
+    /// The connection is held open briefly so the client's first command is read
+    /// rather than reset, letting the client see the error as that command's reply.
+    async fn reject_client<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S) -> Result<()> {
+        let mut response_data = serde_json::to_vec(&DatabaseResponse::error("ERR max number of clients reached"))?;
+        match self.protocol {
+            WireProtocol::Framed => write_frame(&mut stream, &response_data).await?,
+            WireProtocol::Ndjson => {
+                response_data.push(b'\n');
+                stream.write_all(&response_data).await?;
+            }
+        }
+        stream.shutdown().await.ok();
+        let mut discard = [0u8; 1024];
+        let _ = tokio::time::timeout(REJECT_LINGER, stream.read(&mut discard)).await;
+        Ok(())
+    }
+
+    /// Load data and bind `addr` without accepting connections yet.
+    ///
+    /// Binding to port 0 picks a free port, reported by [`BoundServer::local_addr`].
+    pub async fn bind(&self, addr: &str) -> Result<BoundServer, ServerError> {
+        let lock = self.lock_storage()?;
+        self.prepare().await.map_err(ServerError::Load)?;
+        self.bind_listener(addr, lock, false).await
+    }
+
+    /// Lock the storage file so a second server cannot save over it; nothing to lock
+    /// with the memory backend
+    fn lock_storage(&self) -> Result<Option<StorageLock>, ServerError> {
+        self.backend.lock(self.force_lock)
+    }
+
+    async fn bind_listener(&self, addr: &str, lock: Option<StorageLock>, load_pending: bool) -> Result<BoundServer, ServerError> {
+        let bind_error = |source| ServerError::Bind { addr: addr.to_string(), source };
+        let listener = TcpListener::bind(addr).await.map_err(bind_error)?;
+        let local_addr = listener.local_addr().map_err(bind_error)?;
+        info!("Mini database server listening on {}", local_addr);
+        // A server that was shut down may be started again
+        self.closing.send_replace(false);
+        let metrics = self.bind_metrics().await?;
+
+        Ok(BoundServer {
+            db: self.clone(),
+            listener,
+            local_addr,
+            metrics,
+            load_pending,
+            lock,
+        })
+    }
+
+    /// Bind the metrics listener, if one is configured
+    async fn bind_metrics(&self) -> Result<Option<TcpListener>, ServerError> {
+        let Some(addr) = &self.metrics_addr else {
+            return Ok(None);
+        };
+        let bind_error = |source| ServerError::Bind { addr: addr.clone(), source };
+        let listener = TcpListener::bind(addr).await.map_err(bind_error)?;
+        info!("Serving metrics on http://{}/metrics", listener.local_addr().map_err(bind_error)?);
+        Ok(Some(listener))
+    }
+
+    /// Spawn the task answering metrics scrapes on `listener`, each on its own task
+    fn spawn_metrics(&self, listener: TcpListener) -> JoinHandle<()> {
+        let db = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Failed to accept metrics connection: {}", e);
+                        continue;
+                    }
+                };
+                let db = db.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = metrics::serve_http(stream, || db.metrics()).await {
+                        warn!("Failed to serve metrics: {}", e);
+                    }
+                });
+            }
+        })
+    }
+
+    async fn metrics(&self) -> MetricsSnapshot {
+        let keys = if self.loading.load(Ordering::Acquire) { 0 } else { self.store.read().await.total_len() };
+        let last_save_at = self.last_save_at.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            stats: self.stats.snapshot(),
+            keys,
+            connections: self.connections.load(Ordering::Relaxed),
+            last_save_age: (last_save_at > 0).then(|| SystemClock.now_secs().saturating_sub(last_save_at)),
+        }
+    }
+
+    /// Serve clients until Ctrl+C, then flush to disk and return.
+    ///
+    /// Connections are accepted while the data loads; see [`DatabaseCommand::Health`].
+    pub async fn start_server(&self, addr: &str) -> Result<(), ServerError> {
+        self.bind_listener(addr, self.lock_storage()?, true).await?.serve().await
+    }
+
+    /// Serve clients until `shutdown` fires (or its sender is dropped), then flush to disk and return
+    pub async fn start_server_with_shutdown(&self, addr: &str, shutdown: oneshot::Receiver<()>) -> Result<(), ServerError> {
+        self.bind_listener(addr, self.lock_storage()?, true).await?.serve_with_shutdown(shutdown).await
+    }
+
+    /// Serve clients on a Unix domain socket until Ctrl+C, then flush to disk and return
+    #[cfg(unix)]
+    pub async fn start_server_unix<P: AsRef<Path>>(&self, path: P) -> Result<(), ServerError> {
+        self.start_server_unix_with_shutdown(path, ctrl_c_shutdown()).await
+    }
+
+    /// Serve clients on a Unix domain socket until `shutdown` fires.
+    ///
+    /// Refuses to start if `path` already exists, and removes the socket file on shutdown.
+    #[cfg(unix)]
+    pub async fn start_server_unix_with_shutdown<P: AsRef<Path>>(&self, path: P, shutdown: oneshot::Receiver<()>) -> Result<(), ServerError> {
+        self.start_server_multi_with_shutdown(&[ListenAddr::Unix(path.as_ref().to_path_buf())], shutdown).await
+    }
+
+    /// Serve clients on every address in `addrs` at once until Ctrl+C, then flush to
+    /// disk and return
+    pub async fn start_server_multi(&self, addrs: &[ListenAddr]) -> Result<(), ServerError> {
+        self.start_server_multi_with_shutdown(addrs, ctrl_c_shutdown()).await
+    }
+
+    /// Serve clients on every address in `addrs` at once, e.g. a TCP port and a Unix
+    /// socket, until `shutdown` fires. All of them share the one store and connection
+    /// limit. Nothing is served unless every address binds, and Unix socket files are
+    /// removed on shutdown.
+    pub async fn start_server_multi_with_shutdown(&self, addrs: &[ListenAddr], shutdown: oneshot::Receiver<()>) -> Result<(), ServerError> {
+        if addrs.is_empty() {
+            let source = io::Error::new(ErrorKind::InvalidInput, "no addresses to listen on");
+            return Err(ServerError::Bind { addr: String::new(), source });
+        }
+        // Checked before anything is bound, so a clash leaves no socket files behind
+        #[cfg(unix)]
+        for addr in addrs {
+            if let ListenAddr::Unix(path) = addr {
+                check_socket_path(path).await?;
+            }
+        }
+        // Released once the final save is done and this returns
+        let _lock = self.lock_storage()?;
+        self.closing.send_replace(false);
+
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            match Listener::bind(addr).await {
+                Ok(listener) => listeners.push(listener),
                 Err(e) => {
-                    error!("Failed to accept connection: {}", e);
+                    remove_socket_files(&listeners);
+                    return Err(e);
+                }
+            }
+        }
+        let metrics = match self.bind_metrics().await {
+            Ok(metrics) => metrics.map(|listener| self.spawn_metrics(listener)),
+            Err(e) => {
+                remove_socket_files(&listeners);
+                return Err(e);
+            }
+        };
+        let loader = Some(self.spawn_loader());
+
+        let served = self.serve_listeners(&listeners, loader, metrics, shutdown).await;
+        remove_socket_files(&listeners);
+        served
+    }
+
+    /// Accept connections on `listeners` until `shutdown` fires or `loader` fails, then
+    /// stop `metrics`, close every connection and save one last time. Nothing is saved
+    /// after a failed load, which leaves the server still loading.
+    async fn serve_listeners(
+        &self,
+        listeners: &[Listener],
+        mut loader: Option<JoinHandle<Result<()>>>,
+        metrics: Option<JoinHandle<()>>,
+        shutdown: oneshot::Receiver<()>,
+    ) -> Result<(), ServerError> {
+        tokio::pin!(shutdown);
+
+        // Listener polled first, rotated so a busy one cannot starve the others
+        let mut first = 0;
+        let stopped = loop {
+            tokio::select! {
+                accepted = async { (self.wait_for_slot().await, accept_any(listeners, first).await) } => {
+                    first = (first + 1) % listeners.len();
+                    match accepted {
+                        (slot, Ok(Accepted::Tcp(stream, addr))) => {
+                            info!("New client connected: {}", addr);
+                            self.spawn_client(stream, slot);
+                        }
+                        #[cfg(unix)]
+                        (slot, Ok(Accepted::Unix(stream, path))) => {
+                            info!("New client connected on {}", path.display());
+                            self.spawn_client(stream, slot);
+                        }
+                        (_, Err(e)) => {
+                            error!("Failed to accept connection: {}", e);
+                        }
+                    }
                 }
+                loaded = wait_for_load(&mut loader), if loader.is_some() => {
+                    loader = None;
+                    if let Err(e) = loaded {
+                        break Err(ServerError::Load(e));
+                    }
+                }
+                _ = &mut shutdown => break Ok(()),
+            }
+        };
+
+        if let Some(metrics) = metrics {
+            metrics.abort();
+        }
+        let saved = self.shutdown().await.map_err(ServerError::Save);
+        stopped.and(saved)
+    }
+}
+
+/// An address [`MiniDatabase::start_server_multi`] listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// A TCP address such as `127.0.0.1:8080`
+    Tcp(String),
+    /// A Unix domain socket path, which must not exist yet
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    /// A connection and the socket path it arrived on
+    #[cfg(unix)]
+    Unix(UnixStream, PathBuf),
+}
+
+impl Listener {
+    async fn bind(addr: &ListenAddr) -> Result<Self, ServerError> {
+        let bind_error = |source| ServerError::Bind { addr: addr.to_string(), source };
+        let listener = match addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await.map_err(bind_error)?;
+                info!("Mini database server listening on {}", listener.local_addr().map_err(bind_error)?);
+                Listener::Tcp(listener)
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let listener = UnixListener::bind(path).map_err(bind_error)?;
+                info!("Mini database server listening on {}", path.display());
+                Listener::Unix(listener, path.clone())
+            }
+        };
+        Ok(listener)
+    }
+
+    fn poll_accept(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<Accepted>> {
+        match self {
+            Listener::Tcp(listener) => listener.poll_accept(cx).map_ok(|(stream, addr)| Accepted::Tcp(stream, addr)),
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                listener.poll_accept(cx).map_ok(|(stream, _)| Accepted::Unix(stream, path.clone()))
+            }
+        }
+    }
+}
+
+/// Accept the next connection on any of `listeners`, polling from index `first`
+async fn accept_any(listeners: &[Listener], first: usize) -> io::Result<Accepted> {
+    std::future::poll_fn(|cx| {
+        for i in 0..listeners.len() {
+            let listener = &listeners[(first + i) % listeners.len()];
+            if let std::task::Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return std::task::Poll::Ready(accepted);
+            }
+        }
+        std::task::Poll::Pending
+    })
+    .await
+}
+
+/// Refuse a socket path that already exists, telling a live server from a stale file
+#[cfg(unix)]
+async fn check_socket_path(path: &Path) -> Result<(), ServerError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let message = if UnixStream::connect(path).await.is_ok() {
+        "another server is already listening there"
+    } else {
+        "a stale socket file exists; remove it and try again"
+    };
+    let source = io::Error::new(ErrorKind::AddrInUse, message);
+    Err(ServerError::Bind { addr: path.display().to_string(), source })
+}
+
+fn remove_socket_files(listeners: &[Listener]) {
+    for listener in listeners {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = listener {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove socket file {}: {}", path.display(), e);
             }
         }
+        #[cfg(not(unix))]
+        let _ = listener;
+    }
+}
+
+/// The error for well-formed JSON that is not a command `DatabaseCommand` can parse,
+/// naming the variant when a newer client sent one this server does not know.
+/// Tagged requests get a tagged error so a multiplexing client can match it up.
+fn invalid_command(value: &serde_json::Value, e: &serde_json::Error) -> DatabaseResponse {
+    if let Some(tagged) = value.get("Tagged") {
+        if let (Some(id), Some(command)) = (tagged.get("id").and_then(serde_json::Value::as_u64), tagged.get("command")) {
+            return DatabaseResponse::tagged(id, invalid_command(command, e));
+        }
+    }
+    // Unit variants are sent as a bare string, the rest as an object with one key
+    let tag = match value {
+        serde_json::Value::String(tag) => Some(tag),
+        serde_json::Value::Object(fields) if fields.len() == 1 => fields.keys().next(),
+        _ => None,
+    };
+    match tag {
+        Some(tag) if !DatabaseCommand::NAMES.contains(&tag.as_str()) => {
+            DatabaseResponse::error(&format!("ERR unknown command: {}", tag))
+        }
+        _ => DatabaseResponse::error(&format!("Invalid command: {}", e)),
+    }
+}
+
+/// Read the next frame, handing the reader back so the read can be restarted
+async fn read_owned_frame<R: AsyncRead + Unpin>(mut reader: R, max_len: usize) -> (R, Result<Option<Vec<u8>>>) {
+    let frame = read_frame_limited(&mut reader, max_len).await;
+    (reader, frame)
+}
+
+/// Wait for a [`MiniDatabase::spawn_loader`] task; only polled while `loader` is `Some`
+async fn wait_for_load(loader: &mut Option<JoinHandle<Result<()>>>) -> Result<()> {
+    match loader {
+        Some(handle) => handle.await?,
+        None => std::future::pending().await,
+    }
+}
+
+/// A shutdown signal that fires on Ctrl+C
+fn ctrl_c_shutdown() -> oneshot::Receiver<()> {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Received Ctrl+C, shutting down");
+        }
+        let _ = shutdown_tx.send(());
+    });
+    shutdown_rx
+}
+
+/// A server bound to its listening socket, ready to accept connections
+pub struct BoundServer {
+    db: MiniDatabase,
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    metrics: Option<TcpListener>,
+    /// Whether the data still has to be loaded once serving starts
+    load_pending: bool,
+    /// Held until serving stops and the final save is done
+    lock: Option<StorageLock>,
+}
+
+impl BoundServer {
+    /// The address actually bound, including the port chosen for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address metrics are served on, when enabled with [`MiniDatabase::with_metrics_addr`]
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Serve clients until Ctrl+C, then flush to disk and return
+    pub async fn serve(self) -> Result<(), ServerError> {
+        self.serve_with_shutdown(ctrl_c_shutdown()).await
+    }
+
+    /// Serve clients until `shutdown` fires (or its sender is dropped), then flush to disk and return
+    pub async fn serve_with_shutdown(self, shutdown: oneshot::Receiver<()>) -> Result<(), ServerError> {
+        let db = self.db;
+        let _lock = self.lock;
+        let loader = if self.load_pending {
+            Some(db.spawn_loader())
+        } else {
+            db.spawn_flush_task();
+            db.spawn_expiry_sweeper();
+            db.spawn_replication();
+            None
+        };
+        let metrics = self.metrics.map(|listener| db.spawn_metrics(listener));
+        db.serve_listeners(&[Listener::Tcp(self.listener)], loader, metrics, shutdown).await
     }
 }
 
@@ -164,6 +2339,145 @@ impl Clone for MiniDatabase {
         Self {
             store: Arc::clone(&self.store),
             storage_path: self.storage_path.clone(),
+            storage: self.storage,
+            backend: self.backend.clone(),
+            dirty: Arc::clone(&self.dirty),
+            flush_interval: self.flush_interval,
+            save_after_writes: self.save_after_writes,
+            writes_since_save: Arc::clone(&self.writes_since_save),
+            protocol: self.protocol,
+            max_frame_size: self.max_frame_size,
+            sweep_interval: self.sweep_interval,
+            sweep_batch: self.sweep_batch,
+            wal_path: self.wal_path.clone(),
+            wal: Arc::clone(&self.wal),
+            saving: Arc::clone(&self.saving),
+            password: self.password.clone(),
+            read_only: self.read_only,
+            stats: Arc::clone(&self.stats),
+            slow_log: Arc::clone(&self.slow_log),
+            max_keys: self.max_keys,
+            reject_over_capacity: self.reject_over_capacity,
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+            pubsub: Arc::clone(&self.pubsub),
+            watchers: Arc::clone(&self.watchers),
+            replicas: self.replicas.clone(),
+            mutations: self.mutations.clone(),
+            replica_of: self.replica_of.clone(),
+            primary_password: self.primary_password.clone(),
+            next_connection_id: Arc::clone(&self.next_connection_id),
+            loading: Arc::clone(&self.loading),
+            last_save_at: Arc::clone(&self.last_save_at),
+            connections: Arc::clone(&self.connections),
+            connection_slots: self.connection_slots.clone(),
+            reject_over_connections: self.reject_over_connections,
+            idle_timeout: self.idle_timeout,
+            rate_limit: self.rate_limit,
+            metrics_addr: self.metrics_addr.clone(),
+            force_lock: self.force_lock,
+            closing: Arc::clone(&self.closing),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::DatabaseClient;
+    use crate::database::Database;
+    use crate::testutil::TempDir;
+
+    /// A memory-backed server answering one client over an in-process pipe
+    fn serve_pipe(db: &MiniDatabase) -> DatabaseClient<tokio::io::DuplexStream> {
+        let (client_side, server_side) = tokio::io::duplex(64 * 1024);
+        let db = db.clone();
+        tokio::spawn(async move { db.handle_client(server_side).await });
+        DatabaseClient::from_stream(client_side)
+    }
+
+    #[tokio::test]
+    async fn reads_do_not_wait_for_other_readers() {
+        let db = MiniDatabase::new(String::new()).with_backend(StorageBackend::Memory);
+        let client = serve_pipe(&db);
+        client.set("name", "Ada").await.unwrap();
+
+        // Another reader holding the store, e.g. a long KEYS or a save
+        let reading = db.store.read().await;
+        let read = tokio::time::timeout(Duration::from_secs(5), client.get("name"))
+            .await
+            .expect("a read waited for another reader");
+        assert_eq!(read.unwrap().as_deref(), Some("Ada"));
+
+        // Writes still wait for readers to finish
+        let write = tokio::time::timeout(Duration::from_millis(100), client.set("name", "Grace")).await;
+        assert!(write.is_err(), "a write ran while the store was being read");
+        drop(reading);
+        client.set("name", "Grace").await.unwrap();
+        assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Grace"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reads_are_answered_during_a_large_save() {
+        let dir = TempDir::new();
+        let db = MiniDatabase::new(dir.join("db.json").to_string_lossy().into_owned());
+        {
+            let mut store = db.store.write().await;
+            let store = store.get_mut(0);
+            for i in 0..100_000 {
+                store.set(format!("key:{}", i), "x".repeat(64));
+            }
         }
+        let client = serve_pipe(&db);
+
+        let started = Instant::now();
+        let save = tokio::spawn({
+            let db = db.clone();
+            async move { db.save_to_disk().await }
+        });
+        // Wait for the save to start, then read while it runs
+        while db.saving.try_lock().is_ok() {
+            tokio::task::yield_now().await;
+        }
+        let value = client.get("key:42").await.unwrap();
+        let read_after = started.elapsed();
+        assert!(db.saving.try_lock().is_err(), "the read waited for the whole save");
+        assert_eq!(value.map(|value| value.len()), Some(64));
+
+        save.await.unwrap().unwrap();
+        let save_took = started.elapsed();
+        assert!(read_after < save_took, "read took {:?} of a {:?} save", read_after, save_took);
+        let saved = Databases::load_from_file(dir.join("db.json")).unwrap();
+        assert_eq!(saved.get(0).len(), 100_000);
+    }
+
+
+    #[tokio::test]
+    async fn a_failed_load_closes_accepted_connections() {
+        let db = MiniDatabase::new(String::new()).with_backend(StorageBackend::Memory);
+        db.loading.store(true, Ordering::Release);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (fail_load, load_failed) = oneshot::channel::<()>();
+        let loader = tokio::spawn(async move {
+            let _ = load_failed.await;
+            Err(anyhow::anyhow!("corrupt snapshot"))
+        });
+        let (_shutdown, shutdown_rx) = oneshot::channel();
+        let serving = tokio::spawn({
+            let db = db.clone();
+            async move { db.serve_listeners(&[Listener::Tcp(listener)], Some(loader), None, shutdown_rx).await }
+        });
+
+        // Answered, even if only to say the server is loading, so the connection is open
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut stream, &serde_json::to_vec(&DatabaseCommand::Ping).unwrap()).await.unwrap();
+        assert!(crate::protocol::framing::read_frame(&mut stream).await.unwrap().is_some());
+
+        fail_load.send(()).unwrap();
+        assert!(matches!(serving.await.unwrap(), Err(ServerError::Load(_))));
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await;
+        assert!(closed.is_ok(), "the connection was left open");
     }
 }
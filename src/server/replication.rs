@@ -0,0 +1,156 @@
+//! Primary/replica replication: streaming mutations to replicas, and following a primary
+
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+use crate::client::DatabaseClient;
+use crate::protocol::framing::write_frame;
+use crate::protocol::{DatabaseCommand, DatabaseResponse, ReplicationCommand};
+use crate::store::Databases;
+
+use super::{read_owned_frame, MiniDatabase};
+
+/// How long a replica waits before syncing again after losing its primary
+const RESYNC_DELAY: Duration = Duration::from_secs(1);
+
+/// What a primary sends on to its replicas' connections
+#[derive(Debug, Clone)]
+pub(super) enum ReplicationEvent {
+    /// A mutation, sent under the write lock just before it is applied
+    Command(ReplicationCommand),
+    /// The data was replaced wholesale, e.g. by `Reload`, so replicas must sync again
+    Resync,
+}
+
+impl MiniDatabase {
+    /// Stream mutations to a replica until it disconnects.
+    ///
+    /// A replica that misses mutations, by falling more than the backlog behind or by
+    /// the data being reloaded, is sent an error and disconnected so it syncs again.
+    /// Like a subscriber, it may still send `Ping`.
+    pub(super) async fn stream_replication<S>(
+        &self,
+        stream: S,
+        mut receiver: broadcast::Receiver<ReplicationEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        // Owned so a half-read frame survives a mutation arriving in between
+        let mut next_frame = Box::pin(read_owned_frame(reader, self.max_frame_size));
+
+        loop {
+            let (response, done) = tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(ReplicationEvent::Command(command)) => (DatabaseResponse::replicated(command), false),
+                    Ok(ReplicationEvent::Resync) => {
+                        info!("Data was replaced, disconnecting replica so it syncs again");
+                        (DatabaseResponse::error("ERR the data was replaced; sync again"), true)
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Replica fell behind and missed {} mutations, disconnecting it", skipped);
+                        (DatabaseResponse::error("ERR replica fell too far behind; sync again"), true)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                (reader, frame) = &mut next_frame => match frame {
+                    Ok(Some(data)) => {
+                        next_frame = Box::pin(read_owned_frame(reader, self.max_frame_size));
+                        match serde_json::from_slice(&data) {
+                            Ok(DatabaseCommand::Ping) => (DatabaseResponse::pong(), false),
+                            _ => (DatabaseResponse::error("ERR only Ping is allowed while replicating"), false),
+                        }
+                    }
+                    Ok(None) => {
+                        info!("Replica disconnected");
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Error reading from replica: {}", e);
+                        break;
+                    }
+                },
+            };
+            write_frame(&mut writer, &serde_json::to_vec(&response)?).await?;
+            if done {
+                break;
+            }
+        }
+
+        writer.shutdown().await.ok();
+        Ok(())
+    }
+
+    /// Follow the primary on a background task when this server is a replica,
+    /// syncing again whenever the stream breaks
+    pub(super) fn spawn_replication(&self) {
+        let Some(primary) = self.replica_of.clone() else {
+            return;
+        };
+        let db = self.clone();
+        self.spawn_until_closed(async move {
+            loop {
+                match db.follow_primary(&primary).await {
+                    Ok(()) => warn!("Primary {} closed the replication stream", primary),
+                    Err(e) => warn!("Replication from {} failed: {:#}", primary, e),
+                }
+                tokio::time::sleep(RESYNC_DELAY).await;
+            }
+        });
+    }
+
+    /// Sync every database from `primary`, then apply its mutations until the stream ends
+    async fn follow_primary(&self, primary: &str) -> Result<()> {
+        let client = DatabaseClient::new(primary).await?;
+        if let Some(password) = &self.primary_password {
+            client.auth(password).await?;
+        }
+        let (snapshot, mut stream) = client.replicate().await?;
+        self.install_snapshot(primary, snapshot).await?;
+        while let Some(command) = stream.next_command().await? {
+            self.apply_replicated(command).await;
+        }
+        Ok(())
+    }
+
+    /// Replace every database with the primary's snapshot, as `Reload` does with the
+    /// storage file. The WAL is emptied, since its records were made against the old data.
+    async fn install_snapshot(&self, primary: &str, snapshot: Databases) -> Result<()> {
+        // No save may run meanwhile, or it could discard WAL records past the truncation
+        let _saving = self.saving.lock().await;
+        let mut store = self.store.write().await;
+        if let Some(wal) = self.wal.lock().unwrap().as_mut() {
+            wal.truncate()?;
+        }
+        *store = snapshot;
+        // Replicas of this replica can not follow the swap either
+        let _ = self.replicas.send(ReplicationEvent::Resync);
+        self.publish_replaced(&store);
+        self.mark_dirty();
+        info!("Synced {} keys from primary {}", store.total_len(), primary);
+        Ok(())
+    }
+
+    /// Apply one of the primary's mutations as if a client had sent it, logging it to
+    /// this server's WAL and passing it on to its own replicas
+    async fn apply_replicated(&self, replicated: ReplicationCommand) {
+        let ReplicationCommand { db, command } = replicated;
+        {
+            let mut store = self.store.write().await;
+            if db >= store.count() {
+                warn!("Ignoring replicated {} for database {}, which does not exist here", command.name(), db);
+                return;
+            }
+            // An error here is the same one the primary's client got, e.g. INCR on text
+            self.execute_write(store.get_mut(db), db, command);
+        }
+        if self.save_due() {
+            self.flush_if_dirty().await;
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Record of commands that took longer than a threshold
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+/// A command that exceeded the slow log threshold
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowLogEntry {
+    /// Increases by one for every slow command since the server started
+    pub id: u64,
+    /// Unix time in seconds when the command finished
+    pub timestamp: u64,
+    /// Command variant, e.g. "MSet"
+    pub command: String,
+    pub duration_micros: u64,
+}
+
+/// Ring buffer of the most recent slow commands
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        Self {
+            threshold,
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// Keep an entry for `command` if `elapsed` exceeds the threshold; returns whether it
+    /// was slow, even when the capacity is zero and nothing is kept
+    pub fn record(&self, command: &str, elapsed: Duration) -> bool {
+        if elapsed <= self.threshold {
+            return false;
+        }
+        if self.capacity == 0 {
+            return true;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.back().map_or(0, |last| last.id + 1);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(SlowLogEntry {
+            id,
+            timestamp,
+            command: command.to_string(),
+            duration_micros: elapsed.as_micros() as u64,
+        });
+        true
+    }
+
+    /// Recorded entries, newest first
+    pub fn entries(&self) -> Vec<SlowLogEntry> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
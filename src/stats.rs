@@ -0,0 +1,90 @@
+//! Server operation counters
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{DatabaseCommand, DatabaseResponse};
+
+/// Counters updated with relaxed atomics, outside the store lock
+#[derive(Debug, Default)]
+pub struct Stats {
+    commands: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+}
+
+/// Point-in-time copy of [`Stats`], returned by the `Stats` command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    /// Commands run against the store, including those inside EXEC
+    pub commands: u64,
+    pub reads: u64,
+    pub writes: u64,
+    /// GETs that found a value
+    pub get_hits: u64,
+    /// GETs of a missing or expired key
+    pub get_misses: u64,
+    /// Keys written by SET-style commands, counting each MSET pair
+    pub sets: u64,
+    /// Keys named by DELETE-style commands, whether or not they existed
+    pub deletes: u64,
+}
+
+impl Stats {
+    /// Count a command about to run
+    pub fn record_command(&self, command: &DatabaseCommand) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        if command.is_mutation() {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let sets = match command {
+            DatabaseCommand::Set { .. }
+            | DatabaseCommand::SetBytes { .. }
+            | DatabaseCommand::GetSet { .. }
+            | DatabaseCommand::SetNx { .. }
+            | DatabaseCommand::Cas { .. }
+            | DatabaseCommand::SetEx { .. } => 1,
+            DatabaseCommand::MSet { pairs } => pairs.len() as u64,
+            _ => 0,
+        };
+        self.sets.fetch_add(sets, Ordering::Relaxed);
+        let deletes = match command {
+            DatabaseCommand::Delete { .. } => 1,
+            DatabaseCommand::DeleteMany { keys } => keys.len() as u64,
+            _ => 0,
+        };
+        self.deletes.fetch_add(deletes, Ordering::Relaxed);
+    }
+
+    /// Count the outcome of a GET
+    pub fn record_get(&self, response: &DatabaseResponse) {
+        match response {
+            DatabaseResponse::Ok { value: Some(_) } => {
+                self.get_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            DatabaseResponse::Ok { value: None } => {
+                self.get_misses.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            commands: self.commands.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            get_hits: self.get_hits.load(Ordering::Relaxed),
+            get_misses: self.get_misses.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+        }
+    }
+}
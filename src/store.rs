@@ -1,108 +1,2764 @@
 //! Key-value store implementation
 
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
-use serde::{Deserialize, Serialize};
-use anyhow::{Result, Context};
-use crate::database::Database;
+use flate2::{Compression, Crc, CrcWriter};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use anyhow::{Result, Context, bail};
+use tracing::warn;
+use crate::clock::{Clock, SystemClock};
+use crate::database::{Database, TypedDatabase};
+use crate::error::StorageError;
+use crate::protocol::{DatabaseCommand, SetOptions, SetOutcome};
+use crate::wal::{WalEntry, WriteAheadLog};
 
-/// Key-value store with JSON persistence
+fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// Seed for a store's random number generator when none is injected
+fn random_seed() -> AtomicU64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    AtomicU64::new(nanos as u64)
+}
+
+/// Path of the temporary file used while saving `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Match `key` against a glob `pattern`.
+///
+/// `*` matches any run of characters (including none) and `?` matches exactly
+/// one character. There is no escape syntax: `*` and `?` in a pattern are always
+/// wildcards, so a literal `*` or `?` in a key is only matched by a wildcard.
+pub fn matches_pattern(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Position of the last `*` seen and the key index it is currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    k = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The Unix time `seconds` after `from`, refusing one too far out to represent
+fn expiry_after(from: u64, seconds: u64) -> Result<u64> {
+    match from.checked_add(seconds) {
+        Some(expires_at) => Ok(expires_at),
+        None => bail!("ERR invalid expire time"),
+    }
+}
+
+/// First bytes of any gzip stream
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Header written at the start of bincode-encoded files holding a single store
+const BINCODE_MAGIC: &[u8] = b"KVB3";
+
+/// Header written at the start of bincode-encoded files holding every database
+const BINCODE_DATABASES_MAGIC: &[u8] = b"KVB4";
+
+/// Headers of bincode files written before per-key modification times were saved.
+/// Bincode fields are positional, so these are decoded with [`LegacyStore`].
+const LEGACY_BINCODE_MAGIC: &[u8] = b"KVB1";
+const LEGACY_BINCODE_DATABASES_MAGIC: &[u8] = b"KVB2";
+
+/// Header of files whose payload is followed by a CRC32 of it, see [`StorageOptions::checksum`]
+const CHECKSUM_MAGIC: &[u8] = b"KVC1";
+
+/// Length of the big-endian CRC32 footer after a checksummed payload
+const CHECKSUM_LEN: usize = 4;
+
+/// Read `path`, decompressing it if it is gzipped and verifying its checksum if it
+/// has one; a missing file yields `None`
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read(path)
+        .context("Failed to read file")?;
+    if contents.starts_with(GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(contents.as_slice())
+            .read_to_end(&mut decompressed)
+            .context("Failed to decompress file")?;
+        return verify_checksum(decompressed).map(Some);
+    }
+    verify_checksum(contents).map(Some)
+}
+
+/// Strip the checksum header and footer after checking the payload against them.
+/// Files saved without a checksum are returned as they are.
+fn verify_checksum(mut contents: Vec<u8>) -> Result<Vec<u8>> {
+    if !contents.starts_with(CHECKSUM_MAGIC) {
+        return Ok(contents);
+    }
+    let Some(footer_at) = contents.len().checked_sub(CHECKSUM_LEN).filter(|&at| at >= CHECKSUM_MAGIC.len()) else {
+        return Err(StorageError::CorruptFile { reason: "file is too short to hold its checksum".to_string() }.into());
+    };
+
+    let footer = contents.split_off(footer_at);
+    contents.drain(..CHECKSUM_MAGIC.len());
+    let recorded = u32::from_be_bytes([footer[0], footer[1], footer[2], footer[3]]);
+    let mut crc = Crc::new();
+    crc.update(&contents);
+    if crc.sum() != recorded {
+        return Err(StorageError::CorruptFile {
+            reason: format!("checksum {:08x} does not match the recorded {:08x}", crc.sum(), recorded),
+        }
+        .into());
+    }
+    Ok(contents)
+}
+
+/// Save atomically by writing a sibling temp file and renaming it over `path`,
+/// creating any missing parent directories first
+fn save_file<T: Serialize>(value: &T, path: &Path, magic: &[u8], options: StorageOptions) -> Result<()> {
+    write_temp_file(value, path, magic, options)?.commit()
+}
+
+/// A save whose bytes are in the temp file but not yet synced or renamed into place
+pub(crate) struct PendingSave {
+    file: File,
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl PendingSave {
+    /// Make the save durable and replace the old file with it. Needs no access to
+    /// the value that was saved, so any lock on it can be released first.
+    pub(crate) fn commit(self) -> Result<()> {
+        self.file.sync_all()
+            .context("Failed to sync file")?;
+        fs::rename(&self.tmp_path, &self.path)
+            .context("Failed to replace file")?;
+        sync_parent_dir(&self.path)
+    }
+}
+
+/// Serialize `value` into the temp file next to `path`, leaving it to be committed
+fn write_temp_file<T: Serialize>(value: &T, path: &Path, magic: &[u8], options: StorageOptions) -> Result<PendingSave> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create directory {}: {}", dir.display(), e))?;
+    }
+    let tmp_path = temp_path(path);
+    let file = File::create(&tmp_path)
+        .context("Failed to create temporary file")?;
+
+    // Serialize straight into the file, through the encoder when compressing, so the
+    // store is never held in memory as one big string
+    let file = if options.compress {
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        write_payload(value, &mut encoder, magic, options)?;
+        encoder.finish()
+            .context("Failed to write file")?
+            .into_inner()
+            .context("Failed to write file")?
+    } else {
+        let mut writer = BufWriter::new(file);
+        write_payload(value, &mut writer, magic, options)?;
+        writer.into_inner()
+            .context("Failed to write file")?
+    };
+
+    Ok(PendingSave { file, tmp_path, path: path.to_path_buf() })
+}
+
+/// Make a rename into `path` durable by syncing the directory that holds it
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .context("Failed to sync directory")
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Quote a CSV field if needed, doubling any quotes inside it
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Split RFC 4180 CSV into records, unquoting quoted fields.
+///
+/// Quoted fields may contain commas, doubled quotes and line breaks. Lines may end
+/// in `\n` or `\r\n`, and blank lines are skipped.
+fn parse_csv(input: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = input.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => {
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => bail!("Unterminated quoted field starting on line {}", start),
+                    }
+                }
+                if !matches!(chars.peek(), None | Some(',' | '\n' | '\r')) {
+                    bail!("Unexpected character after quoted field on line {}", line);
+                }
+            }
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                line += 1;
+                if !record.is_empty() || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+            }
+            c => field.push(c),
+        }
+    }
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Serialize `value` with [`write_to`], between a checksum header and footer if
+/// `options` asks for one
+fn write_payload<T: Serialize, W: Write>(value: &T, writer: &mut W, magic: &[u8], options: StorageOptions) -> Result<()> {
+    if !options.checksum {
+        return write_to(value, writer, magic, options);
+    }
+
+    writer.write_all(CHECKSUM_MAGIC)
+        .context("Failed to write file")?;
+    let mut checked = CrcWriter::new(&mut *writer);
+    write_to(value, &mut checked, magic, options)?;
+    let sum = checked.crc().sum();
+    writer.write_all(&sum.to_be_bytes())
+        .context("Failed to write file")
+}
+
+/// Serialize `value`, prefixing bincode output with `magic`
+fn write_to<T: Serialize, W: Write>(value: &T, writer: &mut W, magic: &[u8], options: StorageOptions) -> Result<()> {
+    match options.format {
+        StorageFormat::Json if options.pretty => serde_json::to_writer_pretty(writer, value)
+            .context("Failed to serialize to JSON"),
+        StorageFormat::Json => serde_json::to_writer(writer, value)
+            .context("Failed to serialize to JSON"),
+        StorageFormat::Bincode => {
+            writer.write_all(magic)
+                .context("Failed to write file")?;
+            bincode::serialize_into(writer, value)
+                .context("Failed to serialize to bincode")
+        }
+    }
+}
+
+/// Deserialize bincode prefixed with `magic`, or JSON otherwise
+fn decode<T: DeserializeOwned>(contents: &[u8], magic: &[u8]) -> Result<T> {
+    match contents.strip_prefix(magic) {
+        Some(payload) => bincode::deserialize(payload)
+            .context("Failed to parse bincode"),
+        None => serde_json::from_slice(contents)
+            .context("Failed to parse JSON"),
+    }
+}
+
+/// On-disk encoding used when saving the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageFormat {
+    /// JSON, pretty-printed by default so it is easy to read and edit by hand
+    #[default]
+    Json,
+    /// Binary encoding prefixed with a magic header. Every string carries an 8-byte
+    /// length, so short strings take more room than in compact JSON.
+    Bincode,
+}
+
+impl fmt::Display for StorageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+impl FromStr for StorageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            other => Err(format!("unknown storage format '{}' (expected json or bincode)", other)),
+        }
+    }
+}
+
+/// Where a server keeps its data between restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageBackend {
+    /// Load from and save to the storage file
+    #[default]
+    File,
+    /// Keep everything in memory only; nothing is loaded, saved or logged to disk
+    Memory,
+}
+
+impl fmt::Display for StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File => write!(f, "file"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(Self::File),
+            "memory" => Ok(Self::Memory),
+            other => Err(format!("unknown storage backend '{}' (expected file or memory)", other)),
+        }
+    }
+}
+
+/// Position of `key` in `scan_page` order; stable for the life of the process
+fn scan_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Error message for operations on a key holding an incompatible type
+pub const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Longest string `set_range` may grow a value to, as in Redis
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// A typed value stored under a key.
+///
+/// Serialized externally tagged, e.g. `{"Str": "Alice"}` or `{"List": ["a", "b"]}`.
+/// Bare JSON strings written by older versions load as [`Value::Str`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(remote = "Self")]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    List(VecDeque<String>),
+    Hash(HashMap<String, String>),
+    /// Arbitrary bytes, set with `SetBytes`; not readable as a string
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// Name reported by the TYPE command
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Int(_) => "int",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Bytes(_) => "bytes",
+        }
+    }
+
+    /// String form of scalar values; `None` for collections and bytes
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            Value::Str(s) => Some(s.clone()),
+            Value::Int(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Value::Str(s) => Some(s),
+            other => other.as_string(),
+        }
+    }
+
+    /// Bytes of string data held: integers count as their decimal form, and
+    /// collections as the sum of their elements (fields and values for hashes)
+    pub fn byte_len(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::Int(n) => n.to_string().len(),
+            Value::List(items) => items.iter().map(String::len).sum(),
+            Value::Hash(fields) => fields.iter().map(|(field, value)| field.len() + value.len()).sum(),
+            Value::Bytes(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Value::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Value::deserialize(deserializer);
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Legacy(String),
+            Tagged(#[serde(deserialize_with = "Value::deserialize")] Value),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Legacy(s) => Value::Str(s),
+            Repr::Tagged(value) => value,
+        })
+    }
+}
+
+/// Settings controlling how the store is written to disk
+#[derive(Debug, Clone, Copy)]
+pub struct StorageOptions {
+    pub format: StorageFormat,
+    /// Gzip the serialized bytes; compressed files are detected on load
+    pub compress: bool,
+    /// Indent JSON for people to read; compact JSON is smaller and faster to write.
+    /// Both load the same way, and bincode ignores this.
+    pub pretty: bool,
+    /// Wrap the payload in a header and a CRC32 footer, so a truncated or damaged
+    /// file fails to load with [`StorageError::CorruptFile`] instead of loading partial
+    /// data. Files with and without a checksum both load.
+    pub checksum: bool,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            format: StorageFormat::default(),
+            compress: false,
+            pretty: true,
+            checksum: false,
+        }
+    }
+}
+
+/// Key-value store with JSON or bincode persistence.
+///
+/// Generic over the value type so embedders can store their own serde types;
+/// the server uses the default, [`Value`], which adds the typed commands
+/// (lists, hashes, counters) and the string view through [`Database`].
 #[derive(Debug, Serialize, Deserialize)]
-pub struct KeyValueStore {
-    data: HashMap<String, String>,
+#[serde(from = "SavedStore<V>", bound(deserialize = "V: Serialize + DeserializeOwned + Clone"))]
+pub struct KeyValueStore<V = Value> {
+    data: HashMap<String, V>,
+    /// Absolute expiry time (Unix seconds) for keys that have a TTL
+    #[serde(default)]
+    expires_at: HashMap<String, u64>,
     created_at: u64,
     updated_at: u64,
+    /// Logical time each key was last read or written, for LRU eviction. Atomic so
+    /// reads can record access under a shared lock; keys loaded from disk start at 0,
+    /// older than any access since.
+    #[serde(skip)]
+    last_access: HashMap<String, AtomicU64>,
+    #[serde(skip)]
+    access_clock: AtomicU64,
+    /// Unix time each key's value last changed. Keys loaded from files written before
+    /// this was tracked have no entry until they are next written.
+    #[serde(default)]
+    modified_at: HashMap<String, u64>,
+    /// Splitmix64 state for RANDOMKEY, reseedable with [`KeyValueStore::set_rng_seed`]
+    #[serde(skip, default = "random_seed")]
+    rng_state: AtomicU64,
+    /// Time source for TTLs and timestamps, replaceable with [`KeyValueStore::set_clock`]
+    #[serde(skip, default = "system_clock")]
+    clock: Arc<dyn Clock>,
 }
 
-impl KeyValueStore {
+impl<V: Serialize + DeserializeOwned + Clone> KeyValueStore<V> {
     pub fn new() -> Self {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
+        Self::with_clock(system_clock())
+    }
+
+    /// An empty store reading time from `clock`, e.g. a [`MockClock`](crate::clock::MockClock)
+    /// to step through TTLs in tests
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now_secs();
+
         Self {
             data: HashMap::new(),
+            expires_at: HashMap::new(),
             created_at: now,
             updated_at: now,
+            last_access: HashMap::new(),
+            access_clock: AtomicU64::new(0),
+            modified_at: HashMap::new(),
+            rng_state: random_seed(),
+            clock,
         }
     }
 
+    /// Read time from `clock` from now on, e.g. after loading a store from disk
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.clock.now_secs()
+    }
+
+    /// The whole store, expiry times included, as a compact JSON string
+    pub fn to_blob(&self) -> Result<String> {
+        serde_json::to_string(self).context("Failed to serialize store")
+    }
+
+    /// Rebuild a store from [`KeyValueStore::to_blob`] output
+    pub fn from_blob(blob: &str) -> Result<Self> {
+        serde_json::from_str(blob).context("ERR invalid dump")
+    }
+
+    /// Replace the whole store with [`KeyValueStore::to_blob`] output, keeping this
+    /// store's clock
+    pub fn restore(&mut self, blob: &str) -> Result<()> {
+        let mut restored = Self::from_blob(blob)?;
+        restored.set_clock(Arc::clone(&self.clock));
+        *self = restored;
+        Ok(())
+    }
+
+    /// Load from `path`, detecting compression and format from its header
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
-        
-        if !path.exists() {
-            return Ok(Self::new());
+        match read_file(path.as_ref())? {
+            Some(contents) if contents.starts_with(LEGACY_BINCODE_MAGIC) => {
+                decode::<LegacyStore<V>>(&contents, LEGACY_BINCODE_MAGIC).map(Self::from)
+            }
+            Some(contents) => decode(&contents, BINCODE_MAGIC),
+            None => Ok(Self::new()),
         }
-
-        let contents = fs::read_to_string(path)
-            .context("Failed to read file")?;
-        let store: Self = serde_json::from_str(&contents)
-            .context("Failed to parse JSON")?;
-        Ok(store)
     }
 
+    /// Save as uncompressed, pretty-printed JSON, see [`KeyValueStore::save_to_file_with`]
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let contents = serde_json::to_string_pretty(self)
-            .context("Failed to serialize to JSON")?;
-        fs::write(path, contents)
-            .context("Failed to write file")?;
-        Ok(())
+        self.save_to_file_with(path, StorageOptions::default())
+    }
+
+    /// Save atomically by writing a sibling temp file and renaming it over `path`,
+    /// creating missing parent directories
+    pub fn save_to_file_with<P: AsRef<Path>>(&self, path: P, options: StorageOptions) -> Result<()> {
+        save_file(self, path.as_ref(), BINCODE_MAGIC, options)
     }
 
     fn update_timestamp(&mut self) {
-        self.updated_at = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        self.updated_at = self.now_secs();
     }
 
-    pub fn created_at(&self) -> u64 {
-        self.created_at
+    fn is_expired(&self, key: &str) -> bool {
+        self.expires_at
+            .get(key)
+            .is_some_and(|&expires_at| expires_at <= self.now_secs())
     }
 
-    pub fn updated_at(&self) -> u64 {
-        self.updated_at
+    fn is_live(&self, key: &str) -> bool {
+        self.data.contains_key(key) && !self.is_expired(key)
     }
-}
 
-impl Database<String, String> for KeyValueStore {
-    fn get(&self, key: &String) -> Option<String> {
-        self.data.get(key).cloned()
+    fn next_access(&self) -> u64 {
+        self.access_clock.fetch_add(1, Ordering::Relaxed) + 1
     }
 
-    fn set(&mut self, key: String, value: String) -> Option<String> {
+    /// Record a read of an existing key
+    fn record_access(&self, key: &str) {
+        if let Some(at) = self.last_access.get(key) {
+            at.store(self.next_access(), Ordering::Relaxed);
+        }
+    }
+
+    /// Record a write, starting to track `key` if it is new
+    fn touch_mut(&mut self, key: &str) {
+        let modified_at = self.now_secs();
+        self.modified_at.insert(key.to_string(), modified_at);
+        self.mark_used(key);
+    }
+
+    /// Make `key` the most recently used, tracking it from now on if it was not yet
+    fn mark_used(&mut self, key: &str) {
+        let now = self.next_access();
+        match self.last_access.get_mut(key) {
+            Some(at) => *at.get_mut() = now,
+            None => {
+                self.last_access.insert(key.to_string(), AtomicU64::new(now));
+            }
+        }
+    }
+
+    /// Make [`KeyValueStore::random_key`] deterministic, e.g. in tests
+    pub fn set_rng_seed(&mut self, seed: u64) {
+        *self.rng_state.get_mut() = seed;
+    }
+
+    /// Next splitmix64 output; lock-free so it works under a shared lock
+    fn next_random(&self) -> u64 {
+        let mut z = self.rng_state
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Live entries in arbitrary order, without cloning keys or values.
+    ///
+    /// Expired keys are skipped and access times are not touched. The iterator
+    /// borrows the store, so it cannot be held across a mutation; collect what you
+    /// need, or use [`KeyValueStore::entries`], before writing.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> + '_ {
+        self.data.iter().filter(|(key, _)| !self.is_expired(key))
+    }
+
+    /// Owned copies of the live entries, in arbitrary order
+    pub fn entries(&self) -> Vec<(String, V)> {
+        self.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// A uniformly random live key, chosen by reservoir sampling so the key
+    /// list is never collected
+    pub fn random_key(&self) -> Option<String> {
+        let mut chosen = None;
+        let live = self.data.keys().filter(|key| !self.is_expired(key));
+        for (seen, key) in live.enumerate() {
+            if self.next_random().is_multiple_of(seen as u64 + 1) {
+                chosen = Some(key);
+            }
+        }
+        chosen.cloned()
+    }
+
+    /// Remove `key` along with its TTL, access and modification times
+    fn remove_key(&mut self, key: &str) -> Option<V> {
+        self.expires_at.remove(key);
+        self.last_access.remove(key);
+        self.modified_at.remove(key);
+        self.data.remove(key)
+    }
+
+    /// The value at `key`, ignoring expired entries
+    pub fn get_value(&self, key: &str) -> Option<&V> {
+        if self.is_expired(key) {
+            return None;
+        }
+        let value = self.data.get(key);
+        if value.is_some() {
+            self.record_access(key);
+        }
+        value
+    }
+
+    /// Replace the value at `key`, clearing any TTL, and return the live previous value
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
         self.update_timestamp();
-        self.data.insert(key, value)
+        self.touch_mut(&key);
+        let expired = self.is_expired(&key);
+        self.expires_at.remove(&key);
+        self.data.insert(key, value).filter(|_| !expired)
     }
 
-    fn delete(&mut self, key: &String) -> Option<String> {
+    /// Remove `key` and its TTL, returning the value unless it had already expired
+    pub fn remove(&mut self, key: &str) -> Option<V> {
         self.update_timestamp();
-        self.data.remove(key)
+        let expired = self.is_expired(key);
+        self.remove_key(key).filter(|_| !expired)
     }
 
-    fn exists(&self, key: &String) -> bool {
-        self.data.contains_key(key)
+    /// Mark every live key in `keys` as just used, so LRU eviction keeps it longest,
+    /// without reading or changing its value. Returns how many were live.
+    pub fn touch(&mut self, keys: &[String]) -> usize {
+        keys.iter()
+            .filter(|key| {
+                let live = self.is_live(key);
+                if live {
+                    self.mark_used(key);
+                }
+                live
+            })
+            .count()
     }
 
-    fn keys(&self) -> Vec<String> {
-        self.data.keys().cloned().collect()
+    /// Delete every live key in `keys`, returning how many were removed
+    pub fn delete_many(&mut self, keys: &[String]) -> usize {
+        let removed = keys
+            .iter()
+            .filter(|key| {
+                let live = self.is_live(key);
+                self.remove_key(key);
+                live
+            })
+            .count();
+        if removed > 0 {
+            self.update_timestamp();
+        }
+        removed
     }
 
-    fn len(&self) -> usize {
-        self.data.len()
+    /// Move the value at `from` to `to`, overwriting `to` and keeping `from`'s TTL
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.remove_expired(from);
+        let Some(value) = self.data.get(from) else {
+            bail!("ERR no such key");
+        };
+        if from == to {
+            return Ok(());
+        }
+
+        let value = value.clone();
+        let expires_at = self.expires_at.get(from).copied();
+        self.remove_key(from);
+        self.insert(to.to_string(), value);
+        if let Some(expires_at) = expires_at {
+            self.expires_at.insert(to.to_string(), expires_at);
+        }
+        Ok(())
     }
 
-    fn clear(&mut self) {
+    /// Rename only if `to` does not exist, returning whether the rename happened
+    pub fn rename_nx(&mut self, from: &str, to: &str) -> Result<bool> {
+        self.remove_expired(to);
+        if !self.is_live(from) {
+            bail!("ERR no such key");
+        }
+        if self.data.contains_key(to) {
+            return Ok(false);
+        }
+        self.rename(from, to)?;
+        Ok(true)
+    }
+
+    /// Copy the value and TTL at `from` to `to`, returning false without copying
+    /// if `to` exists and `replace` is not set
+    pub fn copy(&mut self, from: &str, to: &str, replace: bool) -> Result<bool> {
+        if from == to {
+            bail!("ERR source and destination keys are the same");
+        }
+        self.remove_expired(to);
+        let Some(value) = self.get_value(from) else {
+            bail!("ERR no such key");
+        };
+        if !replace && self.data.contains_key(to) {
+            return Ok(false);
+        }
+
+        let value = value.clone();
+        let expires_at = self.expires_at.get(from).copied();
+        self.insert(to.to_string(), value);
+        if let Some(expires_at) = expires_at {
+            self.expires_at.insert(to.to_string(), expires_at);
+        }
+        Ok(true)
+    }
+
+    /// Set a TTL on an existing key, returning false if the key is absent
+    pub fn expire(&mut self, key: &str, seconds: u64) -> Result<bool> {
+        let expires_at = expiry_after(self.now_secs(), seconds)?;
+        Ok(self.expire_at(key, expires_at))
+    }
+
+    /// Expire `key` at the Unix time `expires_at`, returning false if the key is absent
+    fn expire_at(&mut self, key: &str, expires_at: u64) -> bool {
+        if !self.is_live(key) {
+            return false;
+        }
         self.update_timestamp();
-        self.data.clear();
+        self.expires_at.insert(key.to_string(), expires_at);
+        true
     }
-}
 
-impl Default for KeyValueStore {
-    fn default() -> Self {
-        Self::new()
+    /// Remaining TTL in seconds, -1 if the key has no expiry, -2 if it is missing
+    pub fn ttl(&self, key: &str) -> i64 {
+        if !self.is_live(key) {
+            return -2;
+        }
+        match self.expires_at.get(key) {
+            Some(&expires_at) => i64::try_from(expires_at.saturating_sub(self.now_secs())).unwrap_or(i64::MAX),
+            None => -1,
+        }
+    }
+
+    /// Live keys starting with `prefix`, sorted so results are deterministic
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self.data
+            .keys()
+            .filter(|key| key.starts_with(prefix) && !self.is_expired(key))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Live keys matching the glob `pattern`, sorted
+    pub fn keys_matching(&self, pattern: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self.data
+            .keys()
+            .filter(|key| matches_pattern(pattern, key) && !self.is_expired(key))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// One page of at most `count` live keys starting at `cursor`, with the cursor for
+    /// the next page, 0 once every key has been returned. Start with cursor 0.
+    ///
+    /// Keys are ordered by a fixed hash of their name, and the cursor is the hash to
+    /// resume from, so keys present for the whole scan are returned exactly once even
+    /// when other keys are added or removed between pages. Keys whose hashes collide
+    /// always share a page, which may then exceed `count`.
+    pub fn scan_page(&self, cursor: u64, count: usize) -> (Vec<String>, u64) {
+        let mut remaining: Vec<(u64, &String)> = self.data
+            .keys()
+            .filter(|key| !self.is_expired(key))
+            .map(|key| (scan_hash(key), key))
+            .filter(|(hash, _)| *hash >= cursor)
+            .collect();
+        remaining.sort_unstable();
+
+        let mut end = count.max(1).min(remaining.len());
+        while end < remaining.len() && remaining[end].0 == remaining[end - 1].0 {
+            end += 1;
+        }
+        let next = remaining.get(end).map_or(0, |(hash, _)| *hash);
+        let keys = remaining[..end].iter().map(|(_, key)| (*key).clone()).collect();
+        (keys, next)
+    }
+
+    /// Remove `key` if its TTL has passed, returning whether it was removed
+    pub fn remove_expired(&mut self, key: &str) -> bool {
+        if !self.is_expired(key) {
+            return false;
+        }
+        self.remove_key(key);
+        true
+    }
+
+    /// Remove every key whose TTL has passed, returning how many were removed
+    pub fn purge_expired(&mut self) -> usize {
+        self.purge_expired_batch(usize::MAX).len()
+    }
+
+    /// Remove up to `limit` expired keys, returning the removed keys
+    pub fn purge_expired_batch(&mut self, limit: usize) -> Vec<String> {
+        let now = self.now_secs();
+        let expired: Vec<String> = self.expires_at
+            .iter()
+            .filter(|(_, &expires_at)| expires_at <= now)
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.remove_key(key);
+        }
+        if !expired.is_empty() {
+            self.update_timestamp();
+        }
+        expired
+    }
+
+    /// Evict least-recently-used keys until at most `max_keys` remain, after first
+    /// dropping expired keys. Returns the evicted keys, oldest first.
+    pub fn evict_lru(&mut self, max_keys: usize) -> Vec<String> {
+        if self.data.len() <= max_keys {
+            return Vec::new();
+        }
+        self.purge_expired();
+        let excess = self.data.len().saturating_sub(max_keys);
+        if excess == 0 {
+            return Vec::new();
+        }
+
+        let mut by_access: Vec<(u64, &String)> = self.data
+            .keys()
+            .map(|key| {
+                let at = self.last_access.get(key).map_or(0, |at| at.load(Ordering::Relaxed));
+                (at, key)
+            })
+            .collect();
+        by_access.sort_unstable();
+        let evicted: Vec<String> = by_access
+            .into_iter()
+            .take(excess)
+            .map(|(_, key)| key.clone())
+            .collect();
+
+        for key in &evicted {
+            self.remove_key(key);
+        }
+        self.update_timestamp();
+        evicted
+    }
+
+    /// Keys physically held, including expired keys not yet removed
+    pub fn raw_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Seconds since the value at `key` last changed
+    pub fn key_age(&self, key: &str) -> Result<u64> {
+        if !self.is_live(key) {
+            bail!("ERR no such key");
+        }
+        match self.modified_at.get(key) {
+            Some(&modified_at) => Ok(self.now_secs().saturating_sub(modified_at)),
+            None => bail!("ERR no modification time recorded for key"),
+        }
+    }
+
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    pub fn updated_at(&self) -> u64 {
+        self.updated_at
     }
-}
\ No newline at end of file
+}
+
+/// Typed commands used by the server, which need to look inside values
+impl KeyValueStore {
+    /// Write live keys as `key,value` CSV rows under a header, sorted by key.
+    ///
+    /// Fields are quoted per RFC 4180 when they contain commas, quotes or line
+    /// breaks. Lists and hashes are written as their JSON encoding.
+    pub fn export_csv(&self, mut writer: impl Write) -> Result<()> {
+        let mut keys: Vec<&String> = self.data.keys().filter(|key| !self.is_expired(key)).collect();
+        keys.sort();
+
+        writeln!(writer, "key,value").context("Failed to write CSV")?;
+        for key in keys {
+            let value = match &self.data[key] {
+                Value::List(items) => serde_json::to_string(items)?,
+                Value::Bytes(bytes) => serde_json::to_string(bytes)?,
+                Value::Hash(fields) => serde_json::to_string(&fields.iter().collect::<BTreeMap<_, _>>())?,
+                scalar => scalar.as_string().unwrap_or_default(),
+            };
+            writeln!(writer, "{},{}", csv_field(key), csv_field(&value))
+                .context("Failed to write CSV")?;
+        }
+        writer.flush().context("Failed to write CSV")?;
+        Ok(())
+    }
+
+    /// Set keys from `key,value` CSV rows, as written by [`KeyValueStore::export_csv`].
+    ///
+    /// A leading `key,value` header is skipped. When a key appears more than once the
+    /// last row wins. With `replace` the store is cleared first, otherwise existing
+    /// keys not in the input are kept. Returns the number of distinct keys imported.
+    pub fn import_csv(&mut self, mut reader: impl Read, replace: bool) -> Result<usize> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input).context("Failed to read CSV")?;
+
+        let mut rows = parse_csv(&input)?.into_iter().peekable();
+        if rows.peek().is_some_and(|row| row == &["key", "value"]) {
+            rows.next();
+        }
+        let mut pairs = HashMap::new();
+        for (index, row) in rows.enumerate() {
+            let [key, value]: [String; 2] = row.try_into().map_err(|row: Vec<String>| {
+                anyhow::anyhow!("CSV record {} has {} fields, expected 2", index + 1, row.len())
+            })?;
+            pairs.insert(key, value);
+        }
+        Ok(self.import_pairs(pairs, replace))
+    }
+
+    /// Set keys from a flat JSON object such as `{"name": "Alice", "age": 30}`.
+    ///
+    /// Values must be strings or numbers; numbers are stored as their JSON text.
+    /// Duplicate keys and `replace` behave as in [`KeyValueStore::import_csv`].
+    pub fn import_json_object(&mut self, reader: impl Read, replace: bool) -> Result<usize> {
+        let object: serde_json::Map<String, serde_json::Value> = serde_json::from_reader(reader)
+            .context("Expected a JSON object")?;
+        let mut pairs = HashMap::new();
+        for (key, value) in object {
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Number(n) => n.to_string(),
+                _ => bail!("Value for key '{}' is not a string or number", key),
+            };
+            pairs.insert(key, value);
+        }
+        Ok(self.import_pairs(pairs, replace))
+    }
+
+    fn import_pairs(&mut self, pairs: HashMap<String, String>, replace: bool) -> usize {
+        if replace {
+            self.clear();
+        }
+        let count = pairs.len();
+        for (key, value) in pairs {
+            self.set(key, value);
+        }
+        count
+    }
+
+    /// Total bytes of live keys and their values, in one pass over the entries
+    pub fn byte_size(&self) -> usize {
+        self.iter()
+            .map(|(key, value)| key.len() + value.byte_len())
+            .sum()
+    }
+
+    /// The string at `key`, or an error if it holds a collection
+    pub fn get_string(&self, key: &str) -> Result<Option<String>> {
+        match self.get_value(key) {
+            Some(value) => match value.as_string() {
+                Some(s) => Ok(Some(s)),
+                None => bail!(WRONG_TYPE),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Type name of the value at `key`, or "none" if it is missing
+    pub fn type_of(&self, key: &str) -> &'static str {
+        self.get_value(key).map_or("none", Value::type_name)
+    }
+
+    /// Set a string subject to `opts`, returning what happened and, with
+    /// `get_old`, the previous value even when the write is skipped
+    pub fn set_with_options(&mut self, key: String, value: String, opts: SetOptions) -> Result<(SetOutcome, Option<String>)> {
+        if opts.only_if_exists && opts.only_if_absent {
+            bail!("ERR only_if_exists and only_if_absent cannot both be set");
+        }
+        let old_value = if opts.get_old { self.get_string(&key)? } else { None };
+        let exists = self.is_live(&key);
+        if (opts.only_if_exists && !exists) || (opts.only_if_absent && exists) {
+            return Ok((SetOutcome::Skipped, old_value));
+        }
+        self.set(key, value);
+        let outcome = if exists { SetOutcome::Updated } else { SetOutcome::Created };
+        Ok((outcome, old_value))
+    }
+
+    /// Store raw bytes at `key`, replacing any value and clearing its TTL
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) {
+        self.insert(key, Value::Bytes(value));
+    }
+
+    /// The bytes at `key`; strings and integers read as their UTF-8 encoding,
+    /// collections are an error
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.get_value(key) {
+            Some(Value::Bytes(bytes)) => Ok(Some(bytes.clone())),
+            Some(value) => match value.as_string() {
+                Some(s) => Ok(Some(s.into_bytes())),
+                None => bail!(WRONG_TYPE),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Set a value that expires after `seconds`
+    pub fn set_with_expiry(&mut self, key: String, value: String, seconds: u64) -> Result<Option<String>> {
+        let expires_at = expiry_after(self.now_secs(), seconds)?;
+        Ok(self.set_with_expiry_at(key, value, expires_at))
+    }
+
+    /// Set a value that expires at the Unix time `expires_at`
+    fn set_with_expiry_at(&mut self, key: String, value: String, expires_at: u64) -> Option<String> {
+        let old_value = self.set(key.clone(), value);
+        self.expires_at.insert(key, expires_at);
+        old_value
+    }
+
+    /// Set a string and return the previous one, failing if the key holds a collection
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old_value = self.get_string(&key)?;
+        self.set(key, value);
+        Ok(old_value)
+    }
+
+    /// Set `key` only if it does not already exist, returning whether it was set
+    pub fn set_nx(&mut self, key: String, value: String) -> bool {
+        self.remove_expired(&key);
+        if self.data.contains_key(&key) {
+            return false;
+        }
+        self.set(key, value);
+        true
+    }
+
+    /// Set `key` to `new` only if its current value equals `expected`,
+    /// where `None` means the key must not exist. Returns whether the swap happened.
+    pub fn cas(&mut self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        if self.get_string(&key)? != expected {
+            return Ok(false);
+        }
+        self.set(key, new);
+        Ok(true)
+    }
+
+    /// Bytes `start..=end` of the string at `key`, as GETRANGE: negative offsets count
+    /// back from the end, so -1 is the last byte, and the range is clamped to the
+    /// string. A range that is empty after clamping gives `""`. Offsets are in bytes,
+    /// so a character split by either end comes back as U+FFFD.
+    pub fn get_range(&self, key: &str, start: i64, end: i64) -> Result<Option<String>> {
+        let Some(value) = self.get_string(key)? else {
+            return Ok(None);
+        };
+        let len = value.len() as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let end = if end < 0 { len + end } else { end.min(len - 1) };
+        if start > end {
+            return Ok(Some(String::new()));
+        }
+        let bytes = &value.as_bytes()[start as usize..=end as usize];
+        Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+
+    /// Length in bytes of the value at `key`, as STRLEN, so "héllo" is 6 long; 0 if it
+    /// is missing. An integer counts its decimal digits and sign.
+    pub fn strlen(&self, key: &str) -> Result<usize> {
+        match self.get_value(key) {
+            Some(Value::Str(s)) => Ok(s.len()),
+            Some(Value::Int(n)) => Ok(n.to_string().len()),
+            Some(Value::Bytes(bytes)) => Ok(bytes.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    /// Overwrite the string at `key` from byte `offset` with `value`, as SETRANGE,
+    /// creating the key if absent and padding with zero bytes up to `offset`. Returns
+    /// the new length; an empty `value` changes nothing, not even creating the key.
+    ///
+    /// Values must stay valid UTF-8, so a write that would start or end inside an
+    /// existing multi-byte character fails and leaves the value as it was.
+    pub fn set_range(&mut self, key: &str, offset: usize, value: &str) -> Result<usize> {
+        self.remove_expired(key);
+        let end = offset.saturating_add(value.len());
+        let current_len = match self.data.get(key) {
+            Some(Value::Str(s)) => {
+                let splits = |at: usize| at < s.len() && !s.is_char_boundary(at);
+                if !value.is_empty() && (splits(offset) || splits(end)) {
+                    bail!("ERR SETRANGE would split a multi-byte character");
+                }
+                s.len()
+            }
+            // Digits are single bytes, so any range is safe
+            Some(Value::Int(n)) => n.to_string().len(),
+            Some(_) => bail!(WRONG_TYPE),
+            None => 0,
+        };
+        if value.is_empty() {
+            return Ok(current_len);
+        }
+        if end > MAX_STRING_LEN {
+            bail!("ERR string exceeds maximum allowed size");
+        }
+
+        let entry = self.data
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Str(String::new()));
+        if let Value::Int(n) = entry {
+            *entry = Value::Str(n.to_string());
+        }
+        let Value::Str(s) = entry else {
+            bail!(WRONG_TYPE);
+        };
+        if s.len() < offset {
+            s.push_str(&"\0".repeat(offset - s.len()));
+        }
+        let replaced = offset..end.min(s.len());
+        s.replace_range(replaced, value);
+        let len = s.len();
+        self.touch_mut(key);
+        self.update_timestamp();
+        Ok(len)
+    }
+
+    /// Append to the string at `key`, creating it if absent, and return the new length
+    pub fn append(&mut self, key: &str, value: &str) -> Result<usize> {
+        self.remove_expired(key);
+        let entry = self.data
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Str(String::new()));
+        if let Value::Int(n) = entry {
+            *entry = Value::Str(n.to_string());
+        }
+        let Value::Str(s) = entry else {
+            bail!(WRONG_TYPE);
+        };
+        s.push_str(value);
+        let len = s.len();
+        self.touch_mut(key);
+        self.update_timestamp();
+        Ok(len)
+    }
+
+    /// The list at `key`, created empty if absent when `create` is set
+    fn list_mut(&mut self, key: &str, create: bool) -> Result<Option<&mut VecDeque<String>>> {
+        self.remove_expired(key);
+        if create {
+            self.data
+                .entry(key.to_string())
+                .or_insert_with(|| Value::List(VecDeque::new()));
+        }
+        if self.data.contains_key(key) {
+            self.touch_mut(key);
+        }
+        match self.data.get_mut(key) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(None),
+        }
+    }
+
+    /// Push values onto the head of a list, creating it if needed, and return its length
+    pub fn push_front(&mut self, key: &str, values: Vec<String>) -> Result<usize> {
+        let list = self.list_mut(key, true)?.expect("list was just created");
+        for value in values {
+            list.push_front(value);
+        }
+        let len = list.len();
+        self.update_timestamp();
+        Ok(len)
+    }
+
+    /// Push values onto the tail of a list, creating it if needed, and return its length
+    pub fn push_back(&mut self, key: &str, values: Vec<String>) -> Result<usize> {
+        let list = self.list_mut(key, true)?.expect("list was just created");
+        list.extend(values);
+        let len = list.len();
+        self.update_timestamp();
+        Ok(len)
+    }
+
+    /// Pop from the head of a list, removing the key once the list is empty
+    pub fn pop_front(&mut self, key: &str) -> Result<Option<String>> {
+        self.pop(key, VecDeque::pop_front)
+    }
+
+    /// Pop from the tail of a list, removing the key once the list is empty
+    pub fn pop_back(&mut self, key: &str) -> Result<Option<String>> {
+        self.pop(key, VecDeque::pop_back)
+    }
+
+    fn pop(&mut self, key: &str, pop: fn(&mut VecDeque<String>) -> Option<String>) -> Result<Option<String>> {
+        let Some(list) = self.list_mut(key, false)? else {
+            return Ok(None);
+        };
+        let value = pop(list);
+        if list.is_empty() {
+            self.remove_key(key);
+        }
+        self.update_timestamp();
+        Ok(value)
+    }
+
+    /// Length of the list at `key`, 0 if it is missing
+    pub fn list_len(&self, key: &str) -> Result<usize> {
+        match self.get_value(key) {
+            Some(Value::List(list)) => Ok(list.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    /// The hash at `key`, created empty if absent when `create` is set
+    fn hash_mut(&mut self, key: &str, create: bool) -> Result<Option<&mut HashMap<String, String>>> {
+        self.remove_expired(key);
+        if create {
+            self.data
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Hash(HashMap::new()));
+        }
+        if self.data.contains_key(key) {
+            self.touch_mut(key);
+        }
+        match self.data.get_mut(key) {
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(None),
+        }
+    }
+
+    fn hash(&self, key: &str) -> Result<Option<&HashMap<String, String>>> {
+        match self.get_value(key) {
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(None),
+        }
+    }
+
+    /// Set a hash field, returning true if the field is new
+    pub fn hset(&mut self, key: &str, field: String, value: String) -> Result<bool> {
+        let hash = self.hash_mut(key, true)?.expect("hash was just created");
+        let created = hash.insert(field, value).is_none();
+        self.update_timestamp();
+        Ok(created)
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        Ok(self.hash(key)?.and_then(|hash| hash.get(field).cloned()))
+    }
+
+    /// Remove a hash field, removing the key once the hash is empty
+    pub fn hdel(&mut self, key: &str, field: &str) -> Result<bool> {
+        let Some(hash) = self.hash_mut(key, false)? else {
+            return Ok(false);
+        };
+        let removed = hash.remove(field).is_some();
+        if hash.is_empty() {
+            self.remove_key(key);
+        }
+        if removed {
+            self.update_timestamp();
+        }
+        Ok(removed)
+    }
+
+    /// All field/value pairs of a hash, sorted by field
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs: Vec<(String, String)> = self.hash(key)?
+            .map(|hash| hash.iter().map(|(f, v)| (f.clone(), v.clone())).collect())
+            .unwrap_or_default();
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    /// Add `delta` to the integer stored at `key`, treating a missing key as 0
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64> {
+        self.remove_expired(key);
+        let current = match self.data.get(key) {
+            Some(Value::Int(n)) => *n,
+            Some(Value::Str(s)) => match s.parse::<i64>() {
+                Ok(n) => n,
+                Err(_) => bail!("value is not an integer"),
+            },
+            Some(_) => bail!(WRONG_TYPE),
+            None => 0,
+        };
+        let Some(new_value) = current.checked_add(delta) else {
+            bail!("increment would overflow");
+        };
+
+        self.update_timestamp();
+        self.touch_mut(key);
+        self.data.insert(key.to_string(), Value::Int(new_value));
+        Ok(new_value)
+    }
+
+    /// Apply a mutation recorded in the write-ahead log at the Unix time `logged_at`;
+    /// other commands are ignored.
+    ///
+    /// A `SetEx`/`Expire` TTL counts from `logged_at`, so a key expires when it would
+    /// have without the restart, and one whose TTL ran out meanwhile stays expired.
+    /// Records without a time, from logs written before timestamps were added, count
+    /// from replay time.
+    pub fn replay_command(&mut self, command: DatabaseCommand, logged_at: Option<u64>) -> Result<()> {
+        let from = logged_at.unwrap_or_else(|| self.now_secs());
+        match command {
+            DatabaseCommand::Set { key, value, opts: None } => {
+                self.set(key, value);
+            }
+            DatabaseCommand::Set { key, value, opts: Some(opts) } => {
+                self.set_with_options(key, value, opts)?;
+            }
+            DatabaseCommand::GetSet { key, value } => {
+                self.get_set(key, value)?;
+            }
+            DatabaseCommand::SetNx { key, value } => {
+                self.set_nx(key, value);
+            }
+            DatabaseCommand::Cas { key, expected, new } => {
+                self.cas(key, expected, new)?;
+            }
+            DatabaseCommand::SetEx { key, value, seconds } => {
+                let expires_at = expiry_after(from, seconds)?;
+                self.set_with_expiry_at(key, value, expires_at);
+            }
+            DatabaseCommand::SetBytes { key, value } => {
+                self.set_bytes(key, value);
+            }
+            DatabaseCommand::Expire { key, seconds } => {
+                let expires_at = expiry_after(from, seconds)?;
+                self.expire_at(&key, expires_at);
+            }
+            DatabaseCommand::MSet { pairs } => {
+                for (key, value) in pairs {
+                    self.set(key, value);
+                }
+            }
+            DatabaseCommand::Append { key, value } => {
+                self.append(&key, &value)?;
+            }
+            DatabaseCommand::SetRange { key, offset, value } => {
+                self.set_range(&key, offset, &value)?;
+            }
+            DatabaseCommand::Incr { key } => {
+                self.incr_by(&key, 1)?;
+            }
+            DatabaseCommand::Decr { key } => {
+                self.incr_by(&key, -1)?;
+            }
+            DatabaseCommand::IncrBy { key, delta } => {
+                self.incr_by(&key, delta)?;
+            }
+            DatabaseCommand::LPush { key, values } => {
+                self.push_front(&key, values)?;
+            }
+            DatabaseCommand::RPush { key, values } => {
+                self.push_back(&key, values)?;
+            }
+            DatabaseCommand::LPop { key } => {
+                self.pop_front(&key)?;
+            }
+            DatabaseCommand::RPop { key } => {
+                self.pop_back(&key)?;
+            }
+            DatabaseCommand::HSet { key, field, value } => {
+                self.hset(&key, field, value)?;
+            }
+            DatabaseCommand::HDel { key, field } => {
+                self.hdel(&key, &field)?;
+            }
+            DatabaseCommand::Rename { from, to } => {
+                self.rename(&from, &to)?;
+            }
+            DatabaseCommand::RenameNx { from, to } => {
+                self.rename_nx(&from, &to)?;
+            }
+            DatabaseCommand::Copy { from, to, replace } => {
+                self.copy(&from, &to, replace)?;
+            }
+            DatabaseCommand::Delete { key, .. } => {
+                self.delete(&key);
+            }
+            DatabaseCommand::DeleteMany { keys } => {
+                self.delete_many(&keys);
+            }
+            DatabaseCommand::Clear | DatabaseCommand::FlushDb { .. } => self.clear(),
+            DatabaseCommand::Restore { data } => self.restore(&data)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// String view of the store; collection values read as absent
+impl Database<String, String> for KeyValueStore {
+    fn get(&self, key: &String) -> Option<String> {
+        self.get_value(key).and_then(Value::as_string)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Option<String> {
+        self.insert(key, Value::Str(value))
+            .and_then(Value::into_string)
+    }
+
+    fn delete(&mut self, key: &String) -> Option<String> {
+        self.remove(key).and_then(Value::into_string)
+    }
+
+    fn exists(&self, key: &String) -> bool {
+        self.is_live(key)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.data
+            .keys()
+            .filter(|key| !self.is_expired(key))
+            .cloned()
+            .collect()
+    }
+
+    /// Live keys; expired keys awaiting the sweeper are not counted, see
+    /// [`KeyValueStore::raw_len`]
+    fn len(&self) -> usize {
+        let now = self.now_secs();
+        let expired = self.expires_at.values().filter(|&&expires_at| expires_at <= now).count();
+        self.data.len().saturating_sub(expired)
+    }
+
+    fn clear(&mut self) {
+        self.update_timestamp();
+        self.data.clear();
+        self.expires_at.clear();
+        self.last_access.clear();
+        self.modified_at.clear();
+    }
+}
+
+/// The server's command set, running the inherent methods above
+impl TypedDatabase for KeyValueStore {
+    fn get_string(&self, key: &str) -> Result<Option<String>> {
+        KeyValueStore::get_string(self, key)
+    }
+
+    fn get_range(&self, key: &str, start: i64, end: i64) -> Result<Option<String>> {
+        KeyValueStore::get_range(self, key, start, end)
+    }
+
+    fn strlen(&self, key: &str) -> Result<usize> {
+        KeyValueStore::strlen(self, key)
+    }
+
+    fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        KeyValueStore::get_bytes(self, key)
+    }
+
+    fn type_of(&self, key: &str) -> &'static str {
+        KeyValueStore::type_of(self, key)
+    }
+
+    fn ttl(&self, key: &str) -> i64 {
+        KeyValueStore::ttl(self, key)
+    }
+
+    fn key_age(&self, key: &str) -> Result<u64> {
+        KeyValueStore::key_age(self, key)
+    }
+
+    fn random_key(&self) -> Option<String> {
+        KeyValueStore::random_key(self)
+    }
+
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        KeyValueStore::keys_with_prefix(self, prefix)
+    }
+
+    fn keys_matching(&self, pattern: &str) -> Vec<String> {
+        KeyValueStore::keys_matching(self, pattern)
+    }
+
+    fn scan_page(&self, cursor: u64, count: usize) -> (Vec<String>, u64) {
+        KeyValueStore::scan_page(self, cursor, count)
+    }
+
+    fn list_len(&self, key: &str) -> Result<usize> {
+        KeyValueStore::list_len(self, key)
+    }
+
+    fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        KeyValueStore::hget(self, key, field)
+    }
+
+    fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>> {
+        KeyValueStore::hgetall(self, key)
+    }
+
+    fn to_blob(&self) -> Result<String> {
+        KeyValueStore::to_blob(self)
+    }
+
+    fn byte_size(&self) -> usize {
+        KeyValueStore::byte_size(self)
+    }
+
+    fn set_with_options(&mut self, key: String, value: String, opts: SetOptions) -> Result<(SetOutcome, Option<String>)> {
+        KeyValueStore::set_with_options(self, key, value, opts)
+    }
+
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) {
+        KeyValueStore::set_bytes(self, key, value)
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        KeyValueStore::get_set(self, key, value)
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> bool {
+        KeyValueStore::set_nx(self, key, value)
+    }
+
+    fn cas(&mut self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        KeyValueStore::cas(self, key, expected, new)
+    }
+
+    fn set_with_expiry(&mut self, key: String, value: String, seconds: u64) -> Result<Option<String>> {
+        KeyValueStore::set_with_expiry(self, key, value, seconds)
+    }
+
+    fn expire(&mut self, key: &str, seconds: u64) -> Result<bool> {
+        KeyValueStore::expire(self, key, seconds)
+    }
+
+    fn append(&mut self, key: &str, value: &str) -> Result<usize> {
+        KeyValueStore::append(self, key, value)
+    }
+
+    fn set_range(&mut self, key: &str, offset: usize, value: &str) -> Result<usize> {
+        KeyValueStore::set_range(self, key, offset, value)
+    }
+
+    fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64> {
+        KeyValueStore::incr_by(self, key, delta)
+    }
+
+    fn push_front(&mut self, key: &str, values: Vec<String>) -> Result<usize> {
+        KeyValueStore::push_front(self, key, values)
+    }
+
+    fn push_back(&mut self, key: &str, values: Vec<String>) -> Result<usize> {
+        KeyValueStore::push_back(self, key, values)
+    }
+
+    fn pop_front(&mut self, key: &str) -> Result<Option<String>> {
+        KeyValueStore::pop_front(self, key)
+    }
+
+    fn pop_back(&mut self, key: &str) -> Result<Option<String>> {
+        KeyValueStore::pop_back(self, key)
+    }
+
+    fn hset(&mut self, key: &str, field: String, value: String) -> Result<bool> {
+        KeyValueStore::hset(self, key, field, value)
+    }
+
+    fn hdel(&mut self, key: &str, field: &str) -> Result<bool> {
+        KeyValueStore::hdel(self, key, field)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        KeyValueStore::rename(self, from, to)
+    }
+
+    fn rename_nx(&mut self, from: &str, to: &str) -> Result<bool> {
+        KeyValueStore::rename_nx(self, from, to)
+    }
+
+    fn copy(&mut self, from: &str, to: &str, replace: bool) -> Result<bool> {
+        KeyValueStore::copy(self, from, to, replace)
+    }
+
+    fn touch(&mut self, keys: &[String]) -> usize {
+        KeyValueStore::touch(self, keys)
+    }
+
+    fn delete_many(&mut self, keys: &[String]) -> usize {
+        KeyValueStore::delete_many(self, keys)
+    }
+
+    fn restore(&mut self, blob: &str) -> Result<()> {
+        KeyValueStore::restore(self, blob)
+    }
+
+    fn evict_lru(&mut self, max_keys: usize) -> Vec<String> {
+        KeyValueStore::evict_lru(self, max_keys)
+    }
+}
+
+impl<V: Serialize + DeserializeOwned + Clone> Default for KeyValueStore<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The fields of a [`KeyValueStore`] that are saved, in their serialized order.
+/// Loading goes through this so every loaded key is tracked for LRU eviction.
+#[derive(Deserialize)]
+#[serde(bound = "V: DeserializeOwned")]
+struct SavedStore<V> {
+    data: HashMap<String, V>,
+    #[serde(default)]
+    expires_at: HashMap<String, u64>,
+    created_at: u64,
+    updated_at: u64,
+    #[serde(default)]
+    modified_at: HashMap<String, u64>,
+}
+
+impl<V: Serialize + DeserializeOwned + Clone> From<SavedStore<V>> for KeyValueStore<V> {
+    fn from(saved: SavedStore<V>) -> Self {
+        // Untracked keys could never be marked as used, so a key read since loading
+        // would still be evicted ahead of every key written since
+        let last_access = saved.data.keys().map(|key| (key.clone(), AtomicU64::new(0))).collect();
+        Self {
+            data: saved.data,
+            expires_at: saved.expires_at,
+            created_at: saved.created_at,
+            updated_at: saved.updated_at,
+            last_access,
+            modified_at: saved.modified_at,
+            ..Self::new()
+        }
+    }
+}
+
+/// A store as saved in bincode files before `modified_at` was added
+#[derive(Deserialize)]
+#[serde(bound = "V: DeserializeOwned")]
+struct LegacyStore<V = Value> {
+    data: HashMap<String, V>,
+    expires_at: HashMap<String, u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl<V: Serialize + DeserializeOwned + Clone> From<LegacyStore<V>> for KeyValueStore<V> {
+    fn from(legacy: LegacyStore<V>) -> Self {
+        Self::from(SavedStore {
+            data: legacy.data,
+            expires_at: legacy.expires_at,
+            created_at: legacy.created_at,
+            updated_at: legacy.updated_at,
+            modified_at: HashMap::new(),
+        })
+    }
+}
+
+/// Every database as saved in bincode files before `modified_at` was added
+#[derive(Deserialize)]
+struct LegacyDatabases {
+    databases: Vec<LegacyStore>,
+}
+
+/// Number of logical databases, numbered from 0 like Redis
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// Numbered logical databases, each an independent [`KeyValueStore`], persisted in one file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Databases {
+    databases: Vec<KeyValueStore>,
+}
+
+impl Databases {
+    pub fn new() -> Self {
+        let mut databases = Self { databases: Vec::new() };
+        databases.ensure_count();
+        databases
+    }
+
+    /// Load from `path`, accepting files written before multiple databases
+    /// existed as the contents of database 0
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let Some(contents) = read_file(path.as_ref())? else {
+            return Ok(Self::new());
+        };
+
+        let mut databases = if contents.starts_with(BINCODE_MAGIC) {
+            Self::from(decode::<KeyValueStore>(&contents, BINCODE_MAGIC)?)
+        } else if contents.starts_with(BINCODE_DATABASES_MAGIC) {
+            decode(&contents, BINCODE_DATABASES_MAGIC)?
+        } else if contents.starts_with(LEGACY_BINCODE_MAGIC) {
+            Self::from(KeyValueStore::from(decode::<LegacyStore>(&contents, LEGACY_BINCODE_MAGIC)?))
+        } else if contents.starts_with(LEGACY_BINCODE_DATABASES_MAGIC) {
+            let legacy: LegacyDatabases = decode(&contents, LEGACY_BINCODE_DATABASES_MAGIC)?;
+            Self { databases: legacy.databases.into_iter().map(KeyValueStore::from).collect() }
+        } else {
+            serde_json::from_slice(&contents).or_else(|e| {
+                serde_json::from_slice::<KeyValueStore>(&contents)
+                    .map(Self::from)
+                    .map_err(|_| e)
+                    .context("Failed to parse JSON")
+            })?
+        };
+        databases.ensure_count();
+        Ok(databases)
+    }
+
+    /// Save atomically by writing a sibling temp file and renaming it over `path`,
+    /// creating missing parent directories
+    pub fn save_to_file_with<P: AsRef<Path>>(&self, path: P, options: StorageOptions) -> Result<()> {
+        save_file(self, path.as_ref(), BINCODE_DATABASES_MAGIC, options)
+    }
+
+    /// Write the temp file for a save to `path`; committing it afterwards does not
+    /// need the databases, so a caller holding a lock on them can release it first
+    pub(crate) fn begin_save<P: AsRef<Path>>(&self, path: P, options: StorageOptions) -> Result<PendingSave> {
+        write_temp_file(self, path.as_ref(), BINCODE_DATABASES_MAGIC, options)
+    }
+
+    /// Apply write-ahead log records in order, each to the database chosen by the
+    /// latest `Select` (database 0 before any). Records that fail, or that follow a
+    /// `Select` of a missing database, are skipped with a warning.
+    pub fn replay(&mut self, entries: Vec<WalEntry>) {
+        let mut db = Some(0);
+        for entry in entries {
+            match (entry.command, db) {
+                (DatabaseCommand::Select { index }, _) => {
+                    db = (index < self.count()).then_some(index);
+                    if db.is_none() {
+                        warn!("Skipping WAL records for missing database {}", index);
+                    }
+                }
+                (command, Some(db)) => {
+                    if let Err(e) = self.get_mut(db).replay_command(command, entry.logged_at) {
+                        warn!("Skipping WAL record: {}", e);
+                    }
+                }
+                (_, None) => {}
+            }
+        }
+    }
+
+    /// Replay the log at `wal_path` up to and including `cutoff_secs`, a Unix time,
+    /// returning how many records were applied.
+    ///
+    /// Replay stops at the first command logged after the cutoff. Records from logs
+    /// written before timestamps were added have no time and are always applied. The
+    /// log only holds writes made since the snapshot it was written against, so these
+    /// databases should be loaded from that snapshot first.
+    pub fn replay_until<P: AsRef<Path>>(&mut self, wal_path: P, cutoff_secs: u64) -> Result<usize> {
+        let mut entries = WriteAheadLog::read_entries(wal_path)?;
+        let after_cutoff = entries.iter().position(|entry| {
+            !matches!(entry.command, DatabaseCommand::Select { .. })
+                && entry.logged_at.is_some_and(|at| at > cutoff_secs)
+        });
+        if let Some(end) = after_cutoff {
+            entries.truncate(end);
+        }
+        let count = entries.len();
+        self.replay(entries);
+        Ok(count)
+    }
+
+    fn ensure_count(&mut self) {
+        if self.databases.len() < DEFAULT_DATABASES {
+            self.databases.resize_with(DEFAULT_DATABASES, KeyValueStore::new);
+        }
+    }
+
+    /// Number of databases available to SELECT
+    pub fn count(&self) -> usize {
+        self.databases.len()
+    }
+
+    /// Database `index`; panics if it is out of range, see [`Databases::count`]
+    pub fn get(&self, index: usize) -> &KeyValueStore {
+        &self.databases[index]
+    }
+
+    /// Database `index`; panics if it is out of range, see [`Databases::count`]
+    pub fn get_mut(&mut self, index: usize) -> &mut KeyValueStore {
+        &mut self.databases[index]
+    }
+
+    /// Total number of keys across every database
+    pub fn total_len(&self) -> usize {
+        self.databases.iter().map(|store| store.len()).sum()
+    }
+}
+
+impl From<KeyValueStore> for Databases {
+    fn from(store: KeyValueStore) -> Self {
+        let mut databases = Self { databases: vec![store] };
+        databases.ensure_count();
+        databases
+    }
+}
+
+impl Default for Databases {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::testutil::TempDir;
+
+    fn store_with(pairs: &[(&str, &str)]) -> KeyValueStore {
+        let mut store = KeyValueStore::new();
+        for (key, value) in pairs {
+            store.set(key.to_string(), value.to_string());
+        }
+        store
+    }
+
+    #[test]
+    fn half_written_temp_file_leaves_previous_save_loadable() {
+        let dir = TempDir::new();
+        let path = dir.join("db.json");
+        store_with(&[("name", "old")]).save_to_file(&path).unwrap();
+
+        // A save that died partway through writing its temp file
+        let mut next = Vec::new();
+        write_to(
+            &store_with(&[("name", "new")]),
+            &mut next,
+            BINCODE_MAGIC,
+            StorageOptions::default(),
+        )
+        .unwrap();
+        fs::write(temp_path(&path), &next[..next.len() / 2]).unwrap();
+
+        let loaded = KeyValueStore::<Value>::load_from_file(&path).unwrap();
+        assert_eq!(loaded.get_string("name").unwrap().as_deref(), Some("old"));
+
+        // The next save replaces the leftover temp file
+        store_with(&[("name", "new")]).save_to_file(&path).unwrap();
+        let loaded = KeyValueStore::<Value>::load_from_file(&path).unwrap();
+        assert_eq!(loaded.get_string("name").unwrap().as_deref(), Some("new"));
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn empty_prefix_scans_every_key_in_order() {
+        let store = store_with(&[("b", "2"), ("a", "1"), ("c:1", "3")]);
+        let mut keys = store.keys();
+        keys.sort();
+        assert_eq!(store.keys_with_prefix(""), keys);
+        assert_eq!(store.keys_with_prefix(""), ["a", "b", "c:1"]);
+        assert_eq!(store.keys_with_prefix("c:"), ["c:1"]);
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("user:*", "user:1"));
+        assert!(matches_pattern("user:*", "user:"));
+        assert!(!matches_pattern("user:*", "users:1"));
+        assert!(matches_pattern("*.json", "db.json"));
+        assert!(!matches_pattern("*.json", "db.json.tmp"));
+        assert!(matches_pattern("a?c", "abc"));
+        assert!(matches_pattern("a?c", "aéc"));
+        assert!(!matches_pattern("a?c", "ac"));
+        assert!(!matches_pattern("a?c", "abbc"));
+        // Wildcards are never literal, so they also match the characters themselves
+        assert!(matches_pattern("a*", "a*"));
+        assert!(matches_pattern("a?", "a?"));
+        assert!(matches_pattern("a?", "ab"));
+    }
+
+    #[test]
+    fn keys_matching_is_sorted_and_skips_expired() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        for key in ["user:2", "user:1", "session:1"] {
+            store.set(key.to_string(), "x".to_string());
+        }
+        store.expire("user:2", 1).unwrap();
+        assert_eq!(store.keys_matching("user:*"), ["user:1", "user:2"]);
+        clock.advance(1);
+        assert_eq!(store.keys_matching("user:*"), ["user:1"]);
+        assert_eq!(store.keys_matching("*:1"), ["session:1", "user:1"]);
+    }
+
+    #[test]
+    fn storage_formats_compared_by_size() {
+        let dir = TempDir::new();
+        let mut store = KeyValueStore::new();
+        for i in 0..5_000 {
+            store.set(format!("user:{}", i), format!("value number {}", i));
+        }
+        let save = |name: &str, format: StorageFormat, pretty: bool| {
+            let path = dir.join(name);
+            store
+                .save_to_file_with(
+                    &path,
+                    StorageOptions {
+                        format,
+                        pretty,
+                        ..StorageOptions::default()
+                    },
+                )
+                .unwrap();
+            let loaded = KeyValueStore::<Value>::load_from_file(&path).unwrap();
+            assert_eq!(loaded.len(), 5_000);
+            assert_eq!(
+                loaded.get_string("user:42").unwrap().as_deref(),
+                Some("value number 42")
+            );
+            fs::metadata(&path).unwrap().len()
+        };
+
+        let pretty = save("pretty.json", StorageFormat::Json, true);
+        let compact = save("compact.json", StorageFormat::Json, false);
+        let bincode = save("db.bin", StorageFormat::Bincode, false);
+        assert!(compact < pretty);
+        // Bincode gives every string an 8-byte length, so on short strings it beats
+        // the indentation of pretty JSON but not compact JSON
+        assert!(bincode < pretty);
+        assert!(bincode > compact);
+    }
+
+    #[test]
+    fn list_push_and_pop_from_both_ends() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(
+            store
+                .push_back("queue", vec!["b".into(), "c".into()])
+                .unwrap(),
+            2
+        );
+        assert_eq!(store.push_front("queue", vec!["a".into()]).unwrap(), 3);
+        assert_eq!(store.type_of("queue"), "list");
+        assert_eq!(store.pop_front("queue").unwrap().as_deref(), Some("a"));
+        assert_eq!(store.pop_back("queue").unwrap().as_deref(), Some("c"));
+        assert_eq!(store.pop_back("queue").unwrap().as_deref(), Some("b"));
+        // The emptied list is removed
+        assert!(!store.exists(&"queue".to_string()));
+        assert_eq!(store.pop_front("queue").unwrap(), None);
+        assert_eq!(store.list_len("queue").unwrap(), 0);
+    }
+
+    #[test]
+    fn list_commands_refuse_other_types() {
+        let mut store = store_with(&[("name", "Ada")]);
+        store.push_back("queue", vec!["a".into()]).unwrap();
+
+        let wrong_type = |result: Result<_>| result.unwrap_err().to_string() == WRONG_TYPE;
+        assert!(wrong_type(
+            store.push_front("name", vec!["x".into()]).map(drop)
+        ));
+        assert!(wrong_type(
+            store.push_back("name", vec!["x".into()]).map(drop)
+        ));
+        assert!(wrong_type(store.pop_front("name").map(drop)));
+        assert!(wrong_type(store.pop_back("name").map(drop)));
+        assert!(wrong_type(store.list_len("name").map(drop)));
+        assert!(wrong_type(store.get_string("queue").map(drop)));
+        // Nothing was changed by the refused commands
+        assert_eq!(store.get_string("name").unwrap().as_deref(), Some("Ada"));
+        assert_eq!(store.list_len("queue").unwrap(), 1);
+    }
+
+    #[test]
+    fn loaded_key_that_is_read_survives_eviction() {
+        let dir = TempDir::new();
+        let path = dir.join("db.json");
+        store_with(&[("read", "1"), ("unread", "2")])
+            .save_to_file(&path)
+            .unwrap();
+        let mut databases = Databases::from(store_with(&[("read", "1"), ("unread", "2")]));
+        databases
+            .save_to_file_with(dir.join("all.json"), StorageOptions::default())
+            .unwrap();
+
+        let mut single = KeyValueStore::load_from_file(&path).unwrap();
+        databases = Databases::load_from_file(dir.join("all.json")).unwrap();
+        for store in [&mut single, databases.get_mut(0)] {
+            assert!(store.get(&"read".to_string()).is_some());
+            store.set("new".to_string(), "3".to_string());
+            assert_eq!(store.evict_lru(2), ["unread"]);
+            assert!(store.exists(&"read".to_string()));
+        }
+    }
+
+    #[test]
+    fn seeded_random_key_is_repeatable_and_live() {
+        assert_eq!(KeyValueStore::<Value>::new().random_key(), None);
+
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        for i in 0..20 {
+            store.set(format!("key:{}", i), i.to_string());
+        }
+        store.expire("key:0", 1).unwrap();
+        clock.advance(1);
+
+        let picks = |store: &mut KeyValueStore| {
+            store.set_rng_seed(42);
+            (0..50)
+                .map(|_| store.random_key().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let first = picks(&mut store);
+        assert_eq!(picks(&mut store), first);
+        assert!(!first.contains(&"key:0".to_string()));
+        assert!(first.iter().collect::<std::collections::HashSet<_>>().len() > 1);
+    }
+
+    #[test]
+    fn byte_size_sums_keys_and_values() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        assert_eq!(store.byte_size(), 0);
+        store.set("name".to_string(), "Ada".to_string()); // 4 + 3
+        store.set("héllo".to_string(), "wörld".to_string()); // 6 + 6, in UTF-8 bytes
+        store.incr_by("count", 1234).unwrap(); // 5 + 4
+        store
+            .push_back("list", vec!["ab".into(), "cde".into()])
+            .unwrap(); // 4 + 5
+        store.hset("hash", "field".into(), "value".into()).unwrap(); // 4 + 10
+        store.set_bytes("blob".to_string(), vec![0; 16]); // 4 + 16
+        assert_eq!(store.byte_size(), 7 + 12 + 9 + 9 + 14 + 20);
+
+        store
+            .set_with_expiry("temp".to_string(), "xyz".to_string(), 1)
+            .unwrap();
+        assert_eq!(store.byte_size(), 71 + 7);
+        clock.advance(1);
+        assert_eq!(store.byte_size(), 71);
+    }
+
+    #[test]
+    fn csv_quotes_commas_quotes_and_line_breaks() {
+        let store = store_with(&[
+            ("plain", "value"),
+            ("comma", "a,b"),
+            ("quote", "say \"hi\""),
+            ("lines", "first\nsecond\r\nthird"),
+            ("key,with,commas", "x"),
+        ]);
+        let mut csv = Vec::new();
+        store.export_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv.clone()).unwrap(),
+            "key,value\n\
+             comma,\"a,b\"\n\
+             \"key,with,commas\",x\n\
+             lines,\"first\nsecond\r\nthird\"\n\
+             plain,value\n\
+             quote,\"say \"\"hi\"\"\"\n"
+        );
+
+        let mut imported = KeyValueStore::new();
+        assert_eq!(imported.import_csv(csv.as_slice(), false).unwrap(), 5);
+        for key in store.keys() {
+            assert_eq!(
+                imported.get(&key),
+                store.get(&key),
+                "{} changed in the round trip",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn len_and_keys_drop_expired_keys_before_they_are_removed() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        store
+            .set_with_expiry("session".to_string(), "token".to_string(), 1)
+            .unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.keys(), ["session"]);
+
+        clock.advance(1);
+        assert_eq!(store.len(), 0);
+        assert!(store.is_empty());
+        assert!(store.keys().is_empty());
+        // Still held until something removes it
+        assert_eq!(store.raw_len(), 1);
+    }
+
+    #[test]
+    fn mock_clock_drives_lazy_expiry() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        store
+            .set_with_expiry("session".to_string(), "token".to_string(), 10)
+            .unwrap();
+        store.set("name".to_string(), "Ada".to_string());
+
+        clock.advance(9);
+        assert_eq!(store.ttl("session"), 1);
+        assert_eq!(
+            store.get_string("session").unwrap().as_deref(),
+            Some("token")
+        );
+
+        clock.advance(1);
+        assert_eq!(store.get_string("session").unwrap(), None);
+        assert_eq!(store.ttl("session"), -2);
+        assert!(!store.exists(&"session".to_string()));
+        assert_eq!(store.ttl("name"), -1);
+        // Reads only hide the key until the sweeper or a write removes it
+        assert_eq!(store.raw_len(), 2);
+        assert!(store.remove_expired("session"));
+        assert_eq!(store.raw_len(), 1);
+    }
+
+    #[test]
+    fn purge_expired_batch_removes_at_most_its_limit() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        for i in 0..5 {
+            store
+                .set_with_expiry(format!("temp:{}", i), "x".to_string(), 1)
+                .unwrap();
+        }
+        store
+            .set_with_expiry("later".to_string(), "x".to_string(), 60)
+            .unwrap();
+        store.set("kept".to_string(), "x".to_string());
+        assert!(store.purge_expired_batch(10).is_empty());
+
+        clock.advance(1);
+        let first = store.purge_expired_batch(3);
+        assert_eq!(first.len(), 3);
+        assert_eq!(store.raw_len(), 4);
+        let rest = store.purge_expired_batch(3);
+        assert_eq!(rest.len(), 2);
+        let mut removed: Vec<String> = first.into_iter().chain(rest).collect();
+        removed.sort();
+        assert_eq!(removed, ["temp:0", "temp:1", "temp:2", "temp:3", "temp:4"]);
+        assert!(store.purge_expired_batch(3).is_empty());
+
+        let mut keys = store.keys();
+        keys.sort();
+        assert_eq!(keys, ["kept", "later"]);
+    }
+
+    #[test]
+    fn is_empty_ignores_expired_keys() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        assert!(store.is_empty());
+        store
+            .set_with_expiry("session".to_string(), "token".to_string(), 1)
+            .unwrap();
+        assert!(!store.is_empty());
+        clock.advance(1);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn key_age_resets_when_the_key_is_overwritten() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        assert_eq!(
+            store.key_age("name").unwrap_err().to_string(),
+            "ERR no such key"
+        );
+
+        store.set("name".to_string(), "Ada".to_string());
+        store.set("other".to_string(), "x".to_string());
+        clock.advance(30);
+        assert_eq!(store.key_age("name").unwrap(), 30);
+        // Reads do not count as modifications
+        store.get(&"name".to_string());
+        assert_eq!(store.key_age("name").unwrap(), 30);
+
+        store.set("name".to_string(), "Grace".to_string());
+        assert_eq!(store.key_age("name").unwrap(), 0);
+        clock.advance(5);
+        assert_eq!(store.key_age("name").unwrap(), 5);
+        assert_eq!(store.key_age("other").unwrap(), 35);
+
+        store.delete(&"name".to_string());
+        assert!(store.key_age("name").is_err());
+
+        let dir = TempDir::new();
+        let path = dir.join("db.json");
+        store.save_to_file(&path).unwrap();
+        let mut loaded: KeyValueStore = KeyValueStore::load_from_file(&path).unwrap();
+        loaded.set_clock(clock.clone());
+        clock.advance(10);
+        assert_eq!(loaded.key_age("other").unwrap(), 45);
+    }
+
+    #[test]
+    fn touched_keys_are_evicted_last() {
+        let mut store = store_with(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        // Only live keys are counted
+        assert_eq!(store.touch(&["a".to_string(), "missing".to_string()]), 1);
+
+        store.set("d".to_string(), "4".to_string());
+        assert_eq!(store.evict_lru(3), ["b"]);
+        store.touch(&["c".to_string()]);
+        store.set("e".to_string(), "5".to_string());
+        assert_eq!(store.evict_lru(3), ["a"]);
+        let mut keys = store.keys();
+        keys.sort();
+        assert_eq!(keys, ["c", "d", "e"]);
+    }
+
+    #[test]
+    fn compact_and_pretty_files_load_identically() {
+        let dir = TempDir::new();
+        let mut databases = Databases::new();
+        let store = databases.get_mut(0);
+        store.set("name".to_string(), "Ada \"Lovelace\"\n".to_string());
+        store.set("empty".to_string(), String::new());
+        store.incr_by("count", 42).unwrap();
+        store
+            .push_back("queue", vec!["a".into(), "b".into()])
+            .unwrap();
+        store.hset("user:1", "name".into(), "Grace".into()).unwrap();
+        store.set_bytes("blob".to_string(), vec![0, 159, 255]);
+        store
+            .set_with_expiry("session".to_string(), "token".to_string(), 600)
+            .unwrap();
+        databases
+            .get_mut(1)
+            .set("other".to_string(), "db".to_string());
+
+        let load = |name: &str, pretty: bool| {
+            let path = dir.join(name);
+            databases
+                .save_to_file_with(
+                    &path,
+                    StorageOptions {
+                        pretty,
+                        ..StorageOptions::default()
+                    },
+                )
+                .unwrap();
+            (
+                fs::read_to_string(&path).unwrap(),
+                Databases::load_from_file(&path).unwrap(),
+            )
+        };
+        let (pretty_text, pretty) = load("pretty.json", true);
+        let (compact_text, compact) = load("compact.json", false);
+        assert!(pretty_text.contains('\n'));
+        assert!(!compact_text.contains('\n'));
+        assert!(compact_text.len() < pretty_text.len());
+
+        assert_eq!(pretty.count(), compact.count());
+        for index in 0..pretty.count() {
+            let sorted = |store: &KeyValueStore| {
+                let mut entries = store.entries();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                entries
+            };
+            assert_eq!(sorted(pretty.get(index)), sorted(compact.get(index)));
+            for key in pretty.get(index).keys() {
+                assert_eq!(pretty.get(index).ttl(&key), compact.get(index).ttl(&key));
+            }
+        }
+        assert_eq!(compact.get(0).len(), 7);
+    }
+
+    #[test]
+    fn streamed_saves_match_serializing_to_a_string() {
+        // Saves serialize straight into the file rather than building the whole
+        // string first, which keeps a second copy of a large store out of memory
+        let dir = TempDir::new();
+        let mut store = store_with(&[("name", "Ada"), ("city", "London")]);
+        store
+            .push_back("queue", vec!["a".into(), "b".into()])
+            .unwrap();
+        store.set_bytes("blob".to_string(), vec![1, 2, 3]);
+
+        let path = dir.join("pretty.json");
+        store.save_to_file(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            serde_json::to_string_pretty(&store).unwrap()
+        );
+
+        let path = dir.join("compact.json");
+        store
+            .save_to_file_with(
+                &path,
+                StorageOptions {
+                    pretty: false,
+                    ..StorageOptions::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            serde_json::to_string(&store).unwrap()
+        );
+
+        let path = dir.join("compressed.json.gz");
+        store
+            .save_to_file_with(
+                &path,
+                StorageOptions {
+                    compress: true,
+                    ..StorageOptions::default()
+                },
+            )
+            .unwrap();
+        let mut unzipped = String::new();
+        GzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut unzipped)
+            .unwrap();
+        assert_eq!(unzipped, serde_json::to_string_pretty(&store).unwrap());
+
+        let loaded: KeyValueStore = KeyValueStore::load_from_file(&path).unwrap();
+        assert_eq!(
+            loaded.get_string("city").unwrap().as_deref(),
+            Some("London")
+        );
+        assert_eq!(loaded.list_len("queue").unwrap(), 2);
+        assert_eq!(loaded.get_bytes("blob").unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn replay_until_recovers_to_a_point_in_time() {
+        let dir = TempDir::new();
+        let wal_path = dir.join("db.wal");
+        let set = |key: &str, value: &str| DatabaseCommand::Set {
+            key: key.into(),
+            value: value.into(),
+            opts: None,
+        };
+        let records = [
+            (100, set("name", "Ada")),
+            (100, set("count", "1")),
+            (200, DatabaseCommand::Select { index: 1 }),
+            (200, set("other", "db")),
+            (200, DatabaseCommand::Select { index: 0 }),
+            (200, set("count", "2")),
+            // The bad write to recover from, and everything after it
+            (
+                300,
+                DatabaseCommand::Delete {
+                    key: "name".into(),
+                    detailed: false,
+                },
+            ),
+            (400, set("count", "3")),
+        ];
+        let log: String = records
+            .iter()
+            .map(|(at, command)| {
+                format!(
+                    "{{\"version\":2,\"at\":{},\"command\":{}}}\n",
+                    at,
+                    serde_json::to_string(command).unwrap()
+                )
+            })
+            .collect();
+        fs::write(&wal_path, log).unwrap();
+
+        let mut databases = Databases::new();
+        assert_eq!(databases.replay_until(&wal_path, 250).unwrap(), 6);
+        assert_eq!(
+            databases.get(0).get(&"name".to_string()).as_deref(),
+            Some("Ada")
+        );
+        assert_eq!(
+            databases.get(0).get(&"count".to_string()).as_deref(),
+            Some("2")
+        );
+        assert_eq!(
+            databases.get(1).get(&"other".to_string()).as_deref(),
+            Some("db")
+        );
+
+        // A cutoff before the first record recovers nothing; one after the last, everything
+        assert_eq!(Databases::new().replay_until(&wal_path, 50).unwrap(), 0);
+        let mut databases = Databases::new();
+        assert_eq!(
+            databases.replay_until(&wal_path, 400).unwrap(),
+            records.len()
+        );
+        assert_eq!(databases.get(0).get(&"name".to_string()), None);
+        assert_eq!(
+            databases.get(0).get(&"count".to_string()).as_deref(),
+            Some("3")
+        );
+    }
+
+    #[test]
+    fn damaged_checksummed_files_fail_with_corrupt_file() {
+        let dir = TempDir::new();
+        let mut databases = Databases::new();
+        databases
+            .get_mut(0)
+            .set("name".to_string(), "Ada".to_string());
+        databases
+            .get_mut(0)
+            .set("city".to_string(), "London".to_string());
+        let corrupt_reason = |path: &Path| match Databases::load_from_file(path) {
+            Ok(_) => panic!("{} loaded despite the damage", path.display()),
+            Err(e) => match e.downcast_ref::<StorageError>() {
+                Some(StorageError::CorruptFile { reason }) => reason.clone(),
+                _ => panic!("expected CorruptFile, got {:#}", e),
+            },
+        };
+
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            let path = dir.join(&format!("db.{}", format));
+            databases
+                .save_to_file_with(
+                    &path,
+                    StorageOptions {
+                        format,
+                        checksum: true,
+                        ..StorageOptions::default()
+                    },
+                )
+                .unwrap();
+            let saved = fs::read(&path).unwrap();
+            assert_eq!(Databases::load_from_file(&path).unwrap().get(0).len(), 2);
+
+            let mut flipped = saved.clone();
+            flipped[saved.len() / 2] ^= 0x01;
+            fs::write(&path, &flipped).unwrap();
+            assert!(corrupt_reason(&path).contains("does not match"));
+
+            // Cut inside the footer, and down to less than a footer after the header
+            fs::write(&path, &saved[..saved.len() - 2]).unwrap();
+            assert!(corrupt_reason(&path).contains("does not match"));
+            fs::write(&path, &saved[..CHECKSUM_MAGIC.len() + CHECKSUM_LEN - 1]).unwrap();
+            assert_eq!(
+                corrupt_reason(&path),
+                "file is too short to hold its checksum"
+            );
+        }
+
+        // Files saved without a checksum still load
+        let path = dir.join("plain.json");
+        databases
+            .save_to_file_with(&path, StorageOptions::default())
+            .unwrap();
+        assert_eq!(Databases::load_from_file(&path).unwrap().get(0).len(), 2);
+    }
+
+    #[test]
+    fn get_range_clamps_negative_and_out_of_range_bounds() {
+        let store = store_with(&[("greeting", "Hello, World"), ("empty", "")]);
+        let range = |start, end| store.get_range("greeting", start, end).unwrap().unwrap();
+
+        assert_eq!(range(0, 4), "Hello");
+        assert_eq!(range(-5, -1), "World");
+        assert_eq!(range(0, -1), "Hello, World");
+        assert_eq!(range(-100, 4), "Hello");
+        assert_eq!(range(0, -100), "");
+        assert_eq!(range(7, 100), "World");
+        assert_eq!(range(5, 2), "");
+        // Starting past the end is empty rather than an error
+        assert_eq!(range(12, 20), "");
+        assert_eq!(range(20, 30), "");
+
+        assert_eq!(
+            store.get_range("empty", 0, -1).unwrap().as_deref(),
+            Some("")
+        );
+        assert_eq!(store.get_range("empty", 0, 0).unwrap().as_deref(), Some(""));
+        assert_eq!(store.get_range("missing", 0, -1).unwrap(), None);
+    }
+
+    #[test]
+    fn get_range_offsets_are_bytes() {
+        let store = store_with(&[("accent", "héllo")]);
+        assert_eq!(
+            store.get_range("accent", 1, 2).unwrap().as_deref(),
+            Some("é")
+        );
+        assert_eq!(
+            store.get_range("accent", -3, -1).unwrap().as_deref(),
+            Some("llo")
+        );
+        // Cutting through "é" leaves a replacement character
+        assert_eq!(
+            store.get_range("accent", 0, 1).unwrap().as_deref(),
+            Some("h\u{FFFD}")
+        );
+    }
+
+    #[test]
+    fn set_range_pads_with_zero_bytes() {
+        let mut store = store_with(&[("greeting", "Hello, World")]);
+        assert_eq!(store.set_range("greeting", 7, "Rusty").unwrap(), 12);
+        assert_eq!(
+            store.get_string("greeting").unwrap().as_deref(),
+            Some("Hello, Rusty")
+        );
+        assert_eq!(store.set_range("greeting", 14, "!").unwrap(), 15);
+        assert_eq!(
+            store.get_string("greeting").unwrap().as_deref(),
+            Some("Hello, Rusty\0\0!")
+        );
+
+        assert_eq!(store.set_range("padded", 3, "ab").unwrap(), 5);
+        assert_eq!(
+            store.get_string("padded").unwrap().as_deref(),
+            Some("\0\0\0ab")
+        );
+    }
+
+    #[test]
+    fn set_range_with_an_empty_value_changes_nothing() {
+        let mut store = store_with(&[("greeting", "Hello")]);
+        assert_eq!(store.set_range("greeting", 10, "").unwrap(), 5);
+        assert_eq!(
+            store.get_string("greeting").unwrap().as_deref(),
+            Some("Hello")
+        );
+        assert_eq!(store.set_range("missing", 3, "").unwrap(), 0);
+        assert_eq!(store.get_string("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn set_range_refuses_to_split_a_character() {
+        let mut store = store_with(&[("accent", "héllo")]);
+        // "é" is bytes 1 and 2, so a write may neither start nor end at byte 2
+        for (offset, value) in [(2, "x"), (0, "hh"), (1, "e")] {
+            let err = store.set_range("accent", offset, value).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "ERR SETRANGE would split a multi-byte character"
+            );
+            assert_eq!(
+                store.get_string("accent").unwrap().as_deref(),
+                Some("héllo")
+            );
+        }
+
+        assert_eq!(store.set_range("accent", 1, "ée").unwrap(), 6);
+        assert_eq!(
+            store.get_string("accent").unwrap().as_deref(),
+            Some("héelo")
+        );
+        // Writing past the end never splits anything
+        assert_eq!(store.set_range("accent", 6, "!").unwrap(), 7);
+    }
+
+    #[test]
+    fn expiry_too_far_out_is_refused() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        store.set("key".to_string(), "value".to_string());
+
+        let err = store.expire("key", u64::MAX).unwrap_err();
+        assert_eq!(err.to_string(), "ERR invalid expire time");
+        assert_eq!(store.ttl("key"), -1);
+        let err = store
+            .set_with_expiry("key".to_string(), "other".to_string(), u64::MAX)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "ERR invalid expire time");
+        assert_eq!(store.get_string("key").unwrap().as_deref(), Some("value"));
+
+        // The furthest expiry that fits is kept, and its TTL clamped to an i64
+        assert!(store.expire("key", u64::MAX - 1_000).unwrap());
+        assert_eq!(store.ttl("key"), i64::MAX);
+        clock.advance(3_600);
+        assert_eq!(store.get_string("key").unwrap().as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn replayed_ttls_count_from_when_they_were_logged() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut databases = Databases::new();
+        databases.get_mut(0).set_clock(clock.clone());
+        let logged = |command| WalEntry {
+            logged_at: Some(1_000),
+            command,
+        };
+        let set = |key: &str| DatabaseCommand::Set {
+            key: key.into(),
+            value: "v".into(),
+            opts: None,
+        };
+        let set_ex = |key: &str, seconds| DatabaseCommand::SetEx {
+            key: key.into(),
+            value: "v".into(),
+            seconds,
+        };
+
+        // Restarting 20 seconds after the records were logged
+        clock.set(1_020);
+        databases.replay(vec![
+            logged(set_ex("session", 60)),
+            logged(set_ex("short", 10)),
+            logged(set("plain")),
+            logged(DatabaseCommand::Expire {
+                key: "plain".into(),
+                seconds: 30,
+            }),
+            // Version 1 records carry no time and count from replay
+            WalEntry {
+                logged_at: None,
+                command: set_ex("old", 60),
+            },
+        ]);
+
+        let store = databases.get(0);
+        assert_eq!(store.ttl("session"), 40);
+        assert_eq!(store.ttl("short"), -2);
+        assert_eq!(store.ttl("plain"), 10);
+        assert_eq!(store.ttl("old"), 60);
+        clock.advance(41);
+        assert_eq!(databases.get(0).get_string("session").unwrap(), None);
+        assert_eq!(
+            databases.get(0).get_string("old").unwrap().as_deref(),
+            Some("v")
+        );
+    }
+
+    #[test]
+    fn restore_keeps_the_stores_clock() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut source = KeyValueStore::with_clock(clock.clone());
+        source
+            .set_with_expiry("session".to_string(), "token".to_string(), 10)
+            .unwrap();
+        let blob = source.to_blob().unwrap();
+
+        let mut store = KeyValueStore::with_clock(clock.clone());
+        TypedDatabase::restore(&mut store, &blob).unwrap();
+        assert_eq!(store.ttl("session"), 10);
+        clock.advance(10);
+        assert_eq!(store.get_string("session").unwrap(), None);
+
+        // Replaying a logged RESTORE keeps it too
+        clock.set(1_000);
+        let mut replayed = KeyValueStore::with_clock(clock.clone());
+        replayed
+            .replay_command(DatabaseCommand::Restore { data: blob }, None)
+            .unwrap();
+        clock.advance(10);
+        assert_eq!(replayed.ttl("session"), -2);
+    }
+}
@@ -0,0 +1,194 @@
+//! Append-only write-ahead log of mutating commands
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::protocol::DatabaseCommand;
+
+/// Version of the record format this build writes.
+///
+/// Version 1 logs hold one bare JSON command per line. Version 2 records wrap the
+/// command with the format version and the Unix time it was logged, e.g.
+/// `{"version":2,"at":1700000000,"command":{"Set":{"key":"a","value":"1"}}}`.
+/// Both load, so a log written before an upgrade still replays.
+pub const WAL_FORMAT_VERSION: u32 = 2;
+
+/// A record as written, borrowing its command
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    version: u32,
+    at: u64,
+    command: &'a DatabaseCommand,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    version: u32,
+    at: u64,
+    command: DatabaseCommand,
+}
+
+/// A command read back from the log
+#[derive(Debug, Clone)]
+pub struct WalEntry {
+    /// Unix time the command was logged, `None` for version 1 records
+    pub logged_at: Option<u64>,
+    pub command: DatabaseCommand,
+}
+
+impl WalEntry {
+    fn parse(line: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(line)
+            .context("Failed to parse WAL record")?;
+        if value.get("version").is_none() {
+            let command = serde_json::from_value(value)
+                .context("Failed to parse WAL record")?;
+            return Ok(Self { logged_at: None, command });
+        }
+
+        let record: Record = serde_json::from_value(value)
+            .context("Failed to parse WAL record")?;
+        if record.version > WAL_FORMAT_VERSION {
+            bail!(
+                "WAL record version {} is newer than this build supports ({})",
+                record.version,
+                WAL_FORMAT_VERSION,
+            );
+        }
+        Ok(Self { logged_at: Some(record.at), command: record.command })
+    }
+}
+
+/// Write-ahead log storing one JSON-encoded record per line, see [`WAL_FORMAT_VERSION`].
+///
+/// Commands apply to the database chosen by the most recent `Select` record,
+/// or database 0 before any.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    /// Database selected by the last record, `None` if unknown since opening
+    db: Option<usize>,
+}
+
+/// Position in a [`WriteAheadLog`], from [`WriteAheadLog::checkpoint`]
+#[derive(Debug, Clone, Copy)]
+pub struct WalCheckpoint {
+    len: u64,
+    /// Database the records after this point start out in
+    db: Option<usize>,
+}
+
+impl WriteAheadLog {
+    /// Open `path` for appending, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context("Failed to open WAL")?;
+        Ok(Self { path, file, db: None })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a command run against database `db`, preceded by a `Select`
+    /// record when the database differs from the previous record's
+    pub fn append(&mut self, db: usize, command: &DatabaseCommand) -> Result<()> {
+        let at = SystemClock.now_secs();
+        let mut lines = Vec::new();
+        if self.db != Some(db) {
+            Self::encode_record(&mut lines, &DatabaseCommand::Select { index: db }, at)?;
+        }
+        Self::encode_record(&mut lines, command, at)?;
+        self.file.write_all(&lines)
+            .context("Failed to append to WAL")?;
+        self.db = Some(db);
+        Ok(())
+    }
+
+    fn encode_record(buf: &mut Vec<u8>, command: &DatabaseCommand, at: u64) -> Result<()> {
+        let record = RecordRef { version: WAL_FORMAT_VERSION, at, command };
+        serde_json::to_writer(&mut *buf, &record)
+            .context("Failed to serialize WAL record")?;
+        buf.push(b'\n');
+        Ok(())
+    }
+
+    /// Discard every record, called once they are captured in a snapshot
+    pub fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)
+            .context("Failed to truncate WAL")?;
+        self.db = Some(0);
+        Ok(())
+    }
+
+    /// Mark the end of the log, e.g. while a snapshot of the store is taken
+    pub fn checkpoint(&self) -> Result<WalCheckpoint> {
+        let len = self.file.metadata()
+            .context("Failed to read WAL length")?
+            .len();
+        Ok(WalCheckpoint { len, db: self.db })
+    }
+
+    /// Discard the records before `checkpoint`, once a snapshot holds them, and keep
+    /// any appended since. Kept records are rewritten to a new file that replaces the
+    /// log, preceded by a `Select` for the database they start out in.
+    pub fn discard_through(&mut self, checkpoint: WalCheckpoint) -> Result<()> {
+        let mut tail = Vec::new();
+        let mut file = File::open(&self.path)
+            .context("Failed to read WAL")?;
+        file.seek(SeekFrom::Start(checkpoint.len))
+            .and_then(|_| file.read_to_end(&mut tail))
+            .context("Failed to read WAL")?;
+        if tail.is_empty() {
+            return self.truncate();
+        }
+
+        let mut records = Vec::new();
+        if let Some(db) = checkpoint.db.filter(|&db| db != 0) {
+            Self::encode_record(&mut records, &DatabaseCommand::Select { index: db }, SystemClock.now_secs())?;
+        }
+        records.append(&mut tail);
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, &records)
+            .context("Failed to write WAL")?;
+        fs::rename(&tmp_path, &self.path)
+            .context("Failed to replace WAL")?;
+        self.file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .context("Failed to open WAL")?;
+        Ok(())
+    }
+
+    /// Read all commands logged at `path`; a missing file yields no commands
+    pub fn read_commands<P: AsRef<Path>>(path: P) -> Result<Vec<DatabaseCommand>> {
+        let entries = Self::read_entries(path)?;
+        Ok(entries.into_iter().map(|entry| entry.command).collect())
+    }
+
+    /// Read every record logged at `path` with its timestamp; a missing file yields none
+    pub fn read_entries<P: AsRef<Path>>(path: P) -> Result<Vec<WalEntry>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let contents = fs::read_to_string(path)
+            .context("Failed to read WAL")?;
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(WalEntry::parse)
+            .collect()
+    }
+}
@@ -0,0 +1,105 @@
+//! Client behaviour: connection handling and how results are reported
+
+mod common;
+
+use std::time::Duration;
+
+use common::{memory_db, TempDir, TestServer};
+use kv_store::client::{DatabaseClient, Lookup};
+use kv_store::{DatabaseCommand, DatabaseResponse, MiniDatabase};
+
+#[tokio::test]
+async fn reconnecting_client_survives_a_server_restart() {
+    let dir = TempDir::new();
+    let path = dir.file("db.json");
+    let server = TestServer::start(MiniDatabase::new(path.clone())).await;
+    let addr = server.addr.clone();
+    let client = DatabaseClient::new_reconnecting(&addr).await.unwrap();
+    let plain = DatabaseClient::new(&addr).await.unwrap();
+    client.set("name", "Ada").await.unwrap();
+
+    server.stop().await;
+    // Back after a few failed redials, with the data it saved on the way down
+    let restart = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        TestServer::start_on(MiniDatabase::new(path), &addr).await
+    });
+    assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Ada"));
+    client.set("name", "Grace").await.unwrap();
+    assert!(plain.get("name").await.is_err(), "a plain client reconnected");
+
+    let server = restart.await.unwrap();
+    assert_eq!(server.client().await.get("name").await.unwrap().as_deref(), Some("Grace"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn get_detailed_tells_missing_from_empty() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.set("name", "Ada").await.unwrap();
+    client.set("empty", "").await.unwrap();
+
+    assert_eq!(client.get_detailed("name").await.unwrap(), Lookup::Found("Ada".to_string()));
+    assert_eq!(client.get_detailed("empty").await.unwrap(), Lookup::Found(String::new()));
+    assert_eq!(client.get_detailed("missing").await.unwrap(), Lookup::Missing);
+    assert!(client.get_detailed("empty").await.unwrap().is_found());
+    assert_eq!(client.get_detailed("missing").await.unwrap().into_option(), None);
+
+    client.delete("empty").await.unwrap();
+    assert_eq!(client.get_detailed("empty").await.unwrap(), Lookup::Missing);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn empty_string_round_trips_distinct_from_absence() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.set("empty", "").await.unwrap();
+
+    assert!(client.exists("empty").await.unwrap());
+    assert_eq!(client.get("empty").await.unwrap(), Some(String::new()));
+    assert_eq!(client.get("missing").await.unwrap(), None);
+    assert_eq!(client.strlen("empty").await.unwrap(), 0);
+    assert_eq!(client.len().await.unwrap(), 1);
+    // On the wire too: an empty value is `Some("")`, a missing key `None`
+    let responses = client
+        .pipeline(vec![
+            DatabaseCommand::Get { key: "empty".to_string() },
+            DatabaseCommand::Get { key: "missing".to_string() },
+        ])
+        .await
+        .unwrap();
+    assert!(matches!(&responses[0], DatabaseResponse::Ok { value: Some(value) } if value.is_empty()));
+    assert!(matches!(&responses[1], DatabaseResponse::Ok { value: None }));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn many_tasks_share_one_cloned_client() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+
+    let tasks: Vec<_> = (0..32)
+        .map(|task| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                for i in 0..25 {
+                    let key = format!("task{}:{}", task, i);
+                    let value = format!("{}-{}", task, i);
+                    client.set(&key, &value).await.unwrap();
+                    // Each caller gets its own reply, not a neighbour's
+                    assert_eq!(client.get(&key).await.unwrap(), Some(value));
+                    client.incr("counter").await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.expect("task panicked");
+    }
+
+    assert_eq!(client.len().await.unwrap(), 32 * 25 + 1);
+    assert_eq!(client.get("counter").await.unwrap().as_deref(), Some("800"));
+    server.stop().await;
+}
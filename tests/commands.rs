@@ -0,0 +1,347 @@
+//! Commands run against a live server, including their behavior under concurrency
+
+mod common;
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use common::{memory_db, server_error, TestServer};
+use kv_store::client::DatabaseClient;
+use kv_store::{MutationEvent, StatsSnapshot};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn racing_getsets_never_see_the_same_previous_value() {
+    let server = TestServer::start(memory_db()).await;
+    let mut tasks = Vec::new();
+    for worker in 0..8 {
+        let client = server.client().await;
+        tasks.push(tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for i in 0..50 {
+                seen.push(client.get_set("slot", &format!("{}-{}", worker, i)).await.unwrap());
+            }
+            seen
+        }));
+    }
+
+    let mut previous = Vec::new();
+    for task in tasks {
+        previous.extend(task.await.unwrap());
+    }
+    // Each value written is handed back to exactly one later GETSET, bar the last
+    let replaced: Vec<String> = previous.iter().flatten().cloned().collect();
+    assert_eq!(previous.iter().filter(|value| value.is_none()).count(), 1);
+    assert_eq!(replaced.len(), 8 * 50 - 1);
+    assert_eq!(replaced.iter().collect::<HashSet<_>>().len(), replaced.len());
+
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn exactly_one_of_two_racing_cas_calls_wins() {
+    let server = TestServer::start(memory_db()).await;
+    let (first, second) = (server.client().await, server.client().await);
+    for round in 0..50 {
+        let current = round.to_string();
+        first.set("version", &current).await.unwrap();
+        let (a, b) = tokio::join!(
+            first.cas("version", Some(&current), "first"),
+            second.cas("version", Some(&current), "second"),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert!(a != b, "round {}: both or neither swap succeeded", round);
+        let winner = if a { "first" } else { "second" };
+        assert_eq!(first.get("version").await.unwrap().as_deref(), Some(winner));
+    }
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cas_retry_loops_lose_no_increments() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    assert!(client.cas("counter", None, "0").await.unwrap());
+    assert!(!client.cas("counter", None, "0").await.unwrap(), "created a key that exists");
+
+    let mut tasks = Vec::new();
+    for _ in 0..2 {
+        let client = server.client().await;
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..50 {
+                loop {
+                    let current = client.get("counter").await.unwrap().unwrap();
+                    let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                    if client.cas("counter", Some(&current), &next).await.unwrap() {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(client.get("counter").await.unwrap().as_deref(), Some("100"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn stats_count_a_known_mix_of_commands() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.set("a", "1").await.unwrap();
+    client.set("b", "2").await.unwrap();
+    client.mset(&[("c", "3"), ("d", "4")]).await.unwrap();
+    client.get("a").await.unwrap();
+    client.get("missing").await.unwrap();
+    client.get("gone").await.unwrap();
+    client.delete("a").await.unwrap();
+    client.delete_many(&["b", "never-set"]).await.unwrap();
+    client.exists("c").await.unwrap();
+
+    // The Stats command counts itself as a read
+    let expected = StatsSnapshot { commands: 10, reads: 5, writes: 5, get_hits: 1, get_misses: 2, sets: 4, deletes: 3 };
+    assert_eq!(client.stats().await.unwrap(), expected);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn rename_overwrites_and_renamenx_refuses() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.mset(&[("from", "1"), ("to", "2"), ("other", "3")]).await.unwrap();
+
+    assert!(!client.rename_nx("from", "to").await.unwrap());
+    assert_eq!(client.mget(&["from", "to"]).await.unwrap(), [Some("1".to_string()), Some("2".to_string())]);
+
+    client.rename("from", "to").await.unwrap();
+    assert_eq!(client.mget(&["from", "to"]).await.unwrap(), [None, Some("1".to_string())]);
+
+    assert!(client.rename_nx("other", "fresh").await.unwrap());
+    assert_eq!(client.mget(&["other", "fresh"]).await.unwrap(), [None, Some("3".to_string())]);
+
+    assert_eq!(server_error(client.rename("missing", "to").await), "ERR no such key");
+    assert_eq!(server_error(client.rename_nx("missing", "to").await), "ERR no such key");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn full_database_refuses_new_keys_but_takes_updates() {
+    let server = TestServer::start(memory_db().with_max_keys(2).with_reject_over_capacity(true)).await;
+    let client = server.client().await;
+    client.mset(&[("a", "1"), ("b", "2")]).await.unwrap();
+
+    assert_eq!(server_error(client.set("c", "3").await), "OOM: max keys reached");
+    assert_eq!(server_error(client.mset(&[("a", "9"), ("c", "3")]).await), "OOM: max keys reached");
+    assert!(!client.exists("c").await.unwrap());
+
+    client.set("a", "10").await.unwrap();
+    assert_eq!(client.incr("b").await.unwrap(), 3);
+    assert_eq!(client.mget(&["a", "b"]).await.unwrap(), [Some("10".to_string()), Some("3".to_string())]);
+
+    // Room made by a delete can be used again
+    client.delete("a").await.unwrap();
+    client.set("c", "3").await.unwrap();
+    assert_eq!(client.len().await.unwrap(), 2);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn exists_many_counts_repeats_and_skips_absent_keys() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.mset(&[("a", "1"), ("b", "2")]).await.unwrap();
+
+    assert_eq!(client.exists_many(&["a", "b", "missing"]).await.unwrap(), 2);
+    // A key named twice counts twice, as in Redis
+    assert_eq!(client.exists_many(&["a", "a", "missing", "b"]).await.unwrap(), 3);
+    assert_eq!(client.exists_many(&["missing", "also-missing"]).await.unwrap(), 0);
+    assert_eq!(client.exists_many(&[]).await.unwrap(), 0);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn dump_and_restore_round_trip_the_keyspace() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.set("name", "Ada").await.unwrap();
+    client.set("empty", "").await.unwrap();
+    client.set_ex("session", "token", Duration::from_secs(600)).await.unwrap();
+    client.rpush("queue", &["a", "b"]).await.unwrap();
+    client.hset("user:1", "name", "Grace").await.unwrap();
+    client.incr_by("count", 7).await.unwrap();
+
+    let dump = client.dump().await.unwrap();
+    client.clear().await.unwrap();
+    assert!(client.is_empty().await.unwrap());
+    client.restore(&dump).await.unwrap();
+
+    let mut keys = client.keys().await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["count", "empty", "name", "queue", "session", "user:1"]);
+    assert_eq!(client.mget(&["name", "empty", "count"]).await.unwrap(), [Some("Ada".into()), Some(String::new()), Some("7".into())]);
+    assert_eq!(client.lpop("queue").await.unwrap().as_deref(), Some("a"));
+    assert_eq!(client.hget("user:1", "name").await.unwrap().as_deref(), Some("Grace"));
+    let ttl = client.ttl("session").await.unwrap().expect("the TTL was lost");
+    assert!(ttl > Duration::from_secs(590));
+
+    assert!(client.restore("not a dump").await.is_err());
+    assert_eq!(client.len().await.unwrap(), 6);
+    server.stop().await;
+
+}
+
+#[tokio::test]
+async fn is_empty_follows_len() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    assert!(client.is_empty().await.unwrap());
+
+    client.set("name", "Ada").await.unwrap();
+    assert!(!client.is_empty().await.unwrap());
+    assert_eq!(client.len().await.unwrap(), 1);
+
+    client.delete("name").await.unwrap();
+    assert!(client.is_empty().await.unwrap());
+    client.rpush("queue", &["a"]).await.unwrap();
+    assert!(!client.is_empty().await.unwrap());
+    client.clear().await.unwrap();
+    assert!(client.is_empty().await.unwrap());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn size_limits_allow_exactly_the_limit_and_refuse_one_more() {
+    let db = memory_db().with_max_key_size(8).with_max_value_size(16);
+    let server = TestServer::start(db).await;
+    let client = server.client().await;
+
+    client.set(&"k".repeat(8), &"v".repeat(16)).await.unwrap();
+    assert_eq!(client.get(&"k".repeat(8)).await.unwrap().map(|v| v.len()), Some(16));
+
+    let error = server_error(client.set("k", &"v".repeat(17)).await);
+    assert_eq!(error, "ERR value too large");
+    let error = server_error(client.set(&"k".repeat(9), "v").await);
+    assert_eq!(error, "ERR key too large");
+    // Limits apply to each list element and to the result of SETRANGE
+    client.rpush("list", &[&"v".repeat(16)]).await.unwrap();
+    assert_eq!(server_error(client.rpush("list", &["v", &"v".repeat(17)]).await), "ERR value too large");
+    assert_eq!(client.set_range("range", 8, &"v".repeat(8)).await.unwrap(), 16);
+    assert_eq!(server_error(client.set_range("range", 9, &"v".repeat(8)).await), "ERR value too large");
+
+    assert!(!client.exists("k").await.unwrap());
+    assert!(!client.exists(&"k".repeat(9)).await.unwrap());
+    assert_eq!(client.llen("list").await.unwrap(), 1);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn delete_detailed_tells_present_from_absent() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.set("name", "Ada").await.unwrap();
+    client.set("empty", "").await.unwrap();
+    client.rpush("queue", &["a"]).await.unwrap();
+
+    assert_eq!(client.delete_detailed("name").await.unwrap(), (Some("Ada".to_string()), true));
+    assert_eq!(client.delete_detailed("name").await.unwrap(), (None, false));
+    assert_eq!(client.delete_detailed("empty").await.unwrap(), (Some(String::new()), true));
+    // A list has no string value, but was still there to delete
+    assert_eq!(client.delete_detailed("queue").await.unwrap(), (None, true));
+    assert_eq!(client.delete_detailed("missing").await.unwrap(), (None, false));
+    assert!(client.is_empty().await.unwrap());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn touched_keys_outlive_untouched_ones_under_max_keys() {
+    let server = TestServer::start(memory_db().with_max_keys(3)).await;
+    let client = server.client().await;
+    client.mset(&[("a", "1"), ("b", "2"), ("c", "3")]).await.unwrap();
+
+    assert_eq!(client.touch(&["a", "missing"]).await.unwrap(), 1);
+    client.set("d", "4").await.unwrap();
+    assert!(client.exists("a").await.unwrap());
+    assert!(!client.exists("b").await.unwrap());
+    assert_eq!(client.len().await.unwrap(), 3);
+    server.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn mutation_events_cover_concurrent_writes_in_order() {
+    let db = memory_db();
+    let mut events = db.subscribe_mutations();
+    let server = TestServer::start(db).await;
+
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let addr = server.addr.clone();
+            tokio::spawn(async move {
+                let client = DatabaseClient::new(&addr).await.unwrap();
+                let key = format!("writer:{}", writer);
+                for i in 0..20 {
+                    client.set(&key, &i.to_string()).await.unwrap();
+                }
+                client.delete(&key).await.unwrap();
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.expect("writer panicked");
+    }
+    let client = server.client().await;
+    client.set("last", "1").await.unwrap();
+    client.clear().await.unwrap();
+
+    let mut seen: HashMap<String, Vec<Option<String>>> = HashMap::new();
+    loop {
+        match events.recv().await.unwrap() {
+            MutationEvent::Set { db: 0, key, value } if key == "last" => assert_eq!(value, "1"),
+            MutationEvent::Set { db: 0, key, value } => seen.entry(key).or_default().push(Some(value)),
+            MutationEvent::Delete { db: 0, key } => seen.entry(key).or_default().push(None),
+            MutationEvent::Clear { db: 0 } => break,
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+    // However the writers interleaved, each one's events arrive in the order it wrote
+    assert_eq!(seen.len(), 4);
+    for values in seen.values() {
+        let expected: Vec<Option<String>> = (0..20).map(|i| Some(i.to_string())).chain([None]).collect();
+        assert_eq!(values, &expected);
+    }
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn strlen_counts_bytes_not_characters() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.set("ascii", "hello").await.unwrap();
+    client.set("accent", "héllo").await.unwrap();
+    client.set("emoji", "🦀").await.unwrap();
+
+    assert_eq!(client.strlen("ascii").await.unwrap(), 5);
+    assert_eq!(client.strlen("accent").await.unwrap(), 6);
+    assert_eq!(client.strlen("emoji").await.unwrap(), 4);
+    assert_eq!(client.strlen("missing").await.unwrap(), 0);
+    // A counter is measured as its decimal digits
+    client.incr_by("counter", -120).await.unwrap();
+    assert_eq!(client.strlen("counter").await.unwrap(), 4);
+    client.rpush("list", &["a"]).await.unwrap();
+    assert!(server_error(client.strlen("list").await).starts_with("WRONGTYPE"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn an_expiry_past_the_end_of_time_is_an_error() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    client.set("key", "value").await.unwrap();
+
+    let max = Duration::from_secs(u64::MAX);
+    assert_eq!(server_error(client.expire("key", max).await), "ERR invalid expire time");
+    assert_eq!(server_error(client.set_ex("key", "other", max).await), "ERR invalid expire time");
+    assert_eq!(client.get("key").await.unwrap().as_deref(), Some("value"));
+    assert_eq!(client.ttl("key").await.unwrap(), None);
+    server.stop().await;
+}
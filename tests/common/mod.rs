@@ -0,0 +1,87 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use kv_store::client::DatabaseClient;
+use kv_store::{ClientError, MiniDatabase, ServerError, StorageBackend};
+
+/// A server bound to a free port and serving on a background task
+pub struct TestServer {
+    pub addr: String,
+    shutdown: oneshot::Sender<()>,
+    serving: JoinHandle<Result<(), ServerError>>,
+}
+
+impl TestServer {
+    pub async fn start(db: MiniDatabase) -> Self {
+        Self::start_on(db, "127.0.0.1:0").await
+    }
+
+    /// Start on a given address, e.g. to restart a server where clients expect it
+    pub async fn start_on(db: MiniDatabase, addr: &str) -> Self {
+        let server = db.bind(addr).await.expect("failed to bind the test server");
+        let addr = server.local_addr().to_string();
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let serving = tokio::spawn(server.serve_with_shutdown(shutdown_rx));
+        Self { addr, shutdown, serving }
+    }
+
+    pub async fn client(&self) -> DatabaseClient {
+        DatabaseClient::new(&self.addr).await.expect("failed to connect to the test server")
+    }
+
+    /// Shut the server down, returning once its final save is done
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(());
+        self.serving.await.expect("server task panicked").expect("server failed");
+    }
+}
+
+/// A server that keeps nothing on disk
+pub fn memory_db() -> MiniDatabase {
+    MiniDatabase::new(String::new()).with_backend(StorageBackend::Memory)
+}
+
+/// The message of the error the server answered with, failing on success or any
+/// other error
+pub fn server_error<T: std::fmt::Debug>(result: Result<T, ClientError>) -> String {
+    match result {
+        Err(ClientError::Server { message }) => message,
+        other => panic!("expected a server error, got {:?}", other),
+    }
+}
+
+/// A fresh directory under the system temp dir, removed with its contents on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("kv-store-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("failed to create a temp dir");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// `name` inside the directory, as a storage path
+    pub fn file(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
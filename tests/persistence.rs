@@ -0,0 +1,108 @@
+//! Saving to and loading from the storage file
+
+mod common;
+
+use std::time::Duration;
+
+use common::{server_error, TempDir, TestServer};
+use kv_store::{Database, Databases, MiniDatabase, StorageBackend, StorageOptions};
+
+#[tokio::test]
+async fn saving_creates_missing_directories() {
+    let dir = TempDir::new();
+    let path = dir.file("nested/dir/db.json");
+    let server = TestServer::start(MiniDatabase::new(path.clone())).await;
+    server.client().await.set("name", "Ada").await.unwrap();
+    server.stop().await;
+    assert!(dir.path().join("nested/dir/db.json").is_file());
+
+    let server = TestServer::start(MiniDatabase::new(path)).await;
+    assert_eq!(server.client().await.get("name").await.unwrap().as_deref(), Some("Ada"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn memory_backend_never_creates_the_storage_file() {
+    let dir = TempDir::new();
+    let db = MiniDatabase::new(dir.file("db.json"))
+        .with_backend(StorageBackend::Memory)
+        .with_save_after_writes(1);
+    let server = TestServer::start(db).await;
+    let client = server.client().await;
+    for i in 0..10 {
+        client.set(&format!("key{}", i), "value").await.unwrap();
+    }
+    assert_eq!(client.len().await.unwrap(), 10);
+    drop(client);
+    server.stop().await;
+
+    assert!(!dir.path().join("db.json").exists());
+    assert!(!dir.path().join("db.json.lock").exists());
+}
+
+#[tokio::test]
+async fn enough_writes_save_before_the_flush_interval() {
+    let dir = TempDir::new();
+    let db = MiniDatabase::new(dir.file("db.json"))
+        .with_flush_interval(Duration::from_secs(3600))
+        .with_save_after_writes(5);
+    let server = TestServer::start(db).await;
+    let client = server.client().await;
+    for i in 0..4 {
+        client.set(&format!("key{}", i), "value").await.unwrap();
+    }
+    let status = client.persistence().await.unwrap();
+    assert!(status.pending);
+    assert_eq!(status.last_save_at, None);
+    assert!(!dir.path().join("db.json").exists());
+
+    // The fifth write saves before it is answered
+    client.set("key4", "value").await.unwrap();
+    let status = client.persistence().await.unwrap();
+    assert!(!status.pending);
+    assert!(status.last_save_at.is_some());
+    let saved = Databases::load_from_file(dir.path().join("db.json")).unwrap();
+    assert_eq!(saved.get(0).len(), 5);
+
+    // The count starts over after each save
+    client.set("key5", "value").await.unwrap();
+    assert!(client.persistence().await.unwrap().pending);
+    drop(client);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn reload_picks_up_a_file_changed_out_of_band() {
+    let dir = TempDir::new();
+    let path = dir.file("db.json");
+    let mut databases = Databases::new();
+    databases.get_mut(0).set("name".to_string(), "Ada".to_string());
+    databases.save_to_file_with(&path, StorageOptions::default()).unwrap();
+
+    let db = MiniDatabase::new(path.clone())
+        .with_flush_interval(Duration::from_secs(3600))
+        .with_password("secret");
+    let server = TestServer::start(db).await;
+    let client = server.client().await;
+    assert!(server_error(client.reload(false).await).starts_with("NOAUTH"));
+    client.auth("secret").await.unwrap();
+    assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Ada"));
+
+    let mut edited = Databases::new();
+    edited.get_mut(0).set("name".to_string(), "Grace".to_string());
+    edited.get_mut(0).set("city".to_string(), "London".to_string());
+    edited.save_to_file_with(&path, StorageOptions::default()).unwrap();
+    assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Ada"));
+    assert_eq!(client.reload(false).await.unwrap(), 2);
+    assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Grace"));
+
+    // Unsaved writes make a plain reload fail; forcing it discards them
+    client.set("draft", "x").await.unwrap();
+    let error = server_error(client.reload(false).await);
+    assert!(error.contains("1 writes are not saved yet"), "{}", error);
+    assert!(client.exists("draft").await.unwrap());
+    assert_eq!(client.reload(true).await.unwrap(), 2);
+    assert!(!client.exists("draft").await.unwrap());
+    drop(client);
+    server.stop().await;
+}
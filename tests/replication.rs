@@ -0,0 +1,58 @@
+//! A replica following a primary
+
+mod common;
+
+use std::future::Future;
+use std::time::Duration;
+
+use common::{memory_db, server_error, TestServer};
+
+/// Poll `check` until it holds, failing after a few seconds
+async fn eventually<F, Fut>(what: &str, mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    for _ in 0..500 {
+        if check().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the replica never caught up: {}", what);
+}
+
+#[tokio::test]
+async fn replica_syncs_and_follows_its_primary() {
+    let primary = TestServer::start(memory_db()).await;
+    let writer = primary.client().await;
+    writer.set("name", "Ada").await.unwrap();
+    writer.rpush("queue", &["a", "b"]).await.unwrap();
+
+    let replica = TestServer::start(memory_db().with_replica_of(primary.addr.clone())).await;
+    let reader = replica.client().await;
+    // Data written before the replica connected arrives with the initial sync
+    eventually("initial sync", || async { reader.get("name").await.unwrap().as_deref() == Some("Ada") }).await;
+    assert_eq!(reader.llen("queue").await.unwrap(), 2);
+
+    // Later writes stream across, including deletes and writes to other databases
+    writer.set("name", "Grace").await.unwrap();
+    writer.lpop("queue").await.unwrap();
+    writer.select(1).await.unwrap();
+    writer.set("other", "db").await.unwrap();
+    writer.select(0).await.unwrap();
+    writer.delete("queue").await.unwrap();
+    writer.set("done", "1").await.unwrap();
+    eventually("streamed writes", || async { reader.exists("done").await.unwrap() }).await;
+    assert_eq!(reader.get("name").await.unwrap().as_deref(), Some("Grace"));
+    assert!(!reader.exists("queue").await.unwrap());
+    reader.select(1).await.unwrap();
+    assert_eq!(reader.get("other").await.unwrap().as_deref(), Some("db"));
+
+    // The replica takes no writes of its own
+    assert!(server_error(reader.set("name", "Eve").await).starts_with("READONLY"));
+
+    drop((reader, writer));
+    replica.stop().await;
+    primary.stop().await;
+}
@@ -0,0 +1,345 @@
+//! Serving clients over TCP: framing, limits and connection handling
+
+mod common;
+
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+use common::{memory_db, server_error, TempDir, TestServer};
+use kv_store::client::DatabaseClient;
+use kv_store::protocol::framing::{read_frame, write_frame};
+use kv_store::protocol::PROTOCOL_VERSION;
+use kv_store::{ClientError, Database, DatabaseCommand, DatabaseResponse, Databases, MiniDatabase, ServerError};
+
+/// Read an error reply off a raw connection
+async fn read_error(stream: &mut TcpStream) -> String {
+    let reply = read_frame(stream).await.unwrap().expect("the connection closed without a reply");
+    match serde_json::from_slice(&reply).unwrap() {
+        DatabaseResponse::Error { message } => message,
+        other => panic!("expected an error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn large_value_round_trips() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+
+    let value: String = (0..1024 * 1024).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    client.set("big", &value).await.unwrap();
+    let read = client.get("big").await.unwrap().expect("the value is missing");
+    assert_eq!(read.len(), value.len());
+    assert!(read == value, "the value came back changed");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn shutdown_handle_stops_the_server_after_a_final_save() {
+    let dir = TempDir::new();
+    let path = dir.file("db.json");
+    let server = MiniDatabase::new(path.clone()).bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr();
+    assert_ne!(addr.port(), 0);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let serving = tokio::spawn(server.serve_with_shutdown(shutdown_rx));
+
+    let client = DatabaseClient::new(&addr.to_string()).await.unwrap();
+    client.set("name", "Ada").await.unwrap();
+
+    shutdown_tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), serving)
+        .await
+        .expect("the server did not stop")
+        .unwrap()
+        .unwrap();
+    // Open connections are closed too, so nothing is written after the final save
+    assert!(client.get("name").await.is_err());
+    let saved = Databases::load_from_file(&path).unwrap();
+    assert_eq!(saved.get(0).get_string("name").unwrap().as_deref(), Some("Ada"));
+}
+
+#[tokio::test]
+async fn read_only_server_serves_reads_and_refuses_writes() {
+    let dir = TempDir::new();
+    let path = dir.file("db.json");
+    let mut data = Databases::new();
+    data.get_mut(0).set("name".to_string(), "Ada".to_string());
+    data.save_to_file_with(&path, Default::default()).unwrap();
+
+    let server = TestServer::start(MiniDatabase::new_readonly(path)).await;
+    let client = server.client().await;
+    assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Ada"));
+    assert_eq!(client.keys().await.unwrap(), ["name"]);
+
+    let refused = [
+        server_error(client.set("name", "Grace").await),
+        server_error(client.delete("name").await),
+        server_error(client.incr("visits").await),
+        server_error(client.clear().await),
+    ];
+    for message in refused {
+        assert!(message.starts_with("READONLY"), "unexpected error: {}", message);
+    }
+    assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Ada"));
+    assert!(!client.exists("visits").await.unwrap());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn oversized_frame_gets_an_error_and_the_connection_closes() {
+    let server = TestServer::start(memory_db().with_max_frame_size(1024)).await;
+    let mut stream = TcpStream::connect(&server.addr).await.unwrap();
+    // Announces a gigabyte that never follows
+    stream.write_all(&(1u32 << 30).to_be_bytes()).await.unwrap();
+
+    let message = read_error(&mut stream).await;
+    assert!(message.contains("exceeds the maximum of 1024 bytes"), "{}", message);
+    assert!(read_frame(&mut stream).await.unwrap().is_none(), "the connection stayed open");
+
+    // The server is unharmed
+    let client = server.client().await;
+    client.set("name", "Ada").await.unwrap();
+    assert_eq!(client.get("name").await.unwrap().as_deref(), Some("Ada"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn command_split_across_writes_is_reassembled() {
+    let server = TestServer::start(memory_db()).await;
+    let mut stream = TcpStream::connect(&server.addr).await.unwrap();
+    let command = serde_json::to_vec(&DatabaseCommand::Set { key: "name".into(), value: "Ada".into(), opts: None }).unwrap();
+
+    // Header alone, then the payload in two pieces, each with a pause for the
+    // server to read what arrived so far
+    stream.write_all(&(command.len() as u32).to_be_bytes()).await.unwrap();
+    let (head, tail) = command.split_at(command.len() / 2);
+    for part in [head, tail] {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream.write_all(part).await.unwrap();
+    }
+
+    let reply = read_frame(&mut stream).await.unwrap().expect("no reply");
+    assert!(matches!(serde_json::from_slice(&reply).unwrap(), DatabaseResponse::Ok { value: None }));
+    assert_eq!(server.client().await.get("name").await.unwrap().as_deref(), Some("Ada"));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn connections_beyond_the_limit_wait_for_a_free_slot() {
+    let server = TestServer::start(memory_db().with_max_connections(2)).await;
+    let first = server.client().await;
+    let second = server.client().await;
+    first.ping().await.unwrap();
+    second.ping().await.unwrap();
+
+    // Accepted by the kernel, but not served while both slots are taken
+    let addr = server.addr.clone();
+    let third = tokio::spawn(async move { DatabaseClient::new(&addr).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!third.is_finished(), "a third connection was served");
+
+    drop(first);
+    let third = tokio::time::timeout(Duration::from_secs(5), third)
+        .await
+        .expect("the waiting connection was not served once a slot freed")
+        .unwrap()
+        .unwrap();
+    third.ping().await.unwrap();
+    second.ping().await.unwrap();
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn connections_beyond_the_limit_can_be_rejected() {
+    let server = TestServer::start(memory_db().with_max_connections(1).with_reject_over_connections(true)).await;
+    let first = server.client().await;
+
+    let mut refused = TcpStream::connect(&server.addr).await.unwrap();
+    write_frame(&mut refused, &serde_json::to_vec(&DatabaseCommand::Ping).unwrap()).await.unwrap();
+    assert_eq!(read_error(&mut refused).await, "ERR max number of clients reached");
+    assert!(read_frame(&mut refused).await.unwrap().is_none(), "the refused connection stayed open");
+    first.ping().await.unwrap();
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn idle_connections_are_dropped_after_the_timeout() {
+    let server = TestServer::start(memory_db().with_idle_timeout(Duration::from_millis(200))).await;
+    let busy = server.client().await;
+    let mut silent = TcpStream::connect(&server.addr).await.unwrap();
+
+    let started = std::time::Instant::now();
+    let closed = tokio::spawn(async move { (read_frame(&mut silent).await.unwrap(), started.elapsed()) });
+    // A connection sending commands more often than the timeout stays open
+    for _ in 0..6 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        busy.ping().await.unwrap();
+    }
+    let (frame, idle_for) = tokio::time::timeout(Duration::from_secs(5), closed)
+        .await
+        .expect("the idle connection was never closed")
+        .unwrap();
+    assert!(frame.is_none());
+    assert!(idle_for >= Duration::from_millis(200), "closed after only {:?}", idle_for);
+    busy.ping().await.unwrap();
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn protocol_versions_are_negotiated_down() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+
+    // A newer client is answered with the server's own version
+    assert_eq!(client.hello(PROTOCOL_VERSION + 5).await.unwrap(), PROTOCOL_VERSION);
+    // Sent raw: `hello` takes any error as a server from before the handshake
+    match &client.pipeline(vec![DatabaseCommand::Hello { protocol: 0 }]).await.unwrap()[..] {
+        [DatabaseResponse::Error { message }] => assert_eq!(message, "ERR unsupported protocol version 0"),
+        other => panic!("unexpected replies: {:?}", other),
+    }
+
+    // An older client is held to what its version had
+    assert_eq!(client.hello(1).await.unwrap(), 1);
+    assert_eq!(client.protocol_version(), 1);
+    let replies = client.pipeline(vec![DatabaseCommand::IsEmpty, DatabaseCommand::Len]).await.unwrap();
+    match &replies[..] {
+        [DatabaseResponse::Error { message }, DatabaseResponse::Len { count: 0 }] => {
+            assert_eq!(message, "ERR IsEmpty needs protocol version 2, this connection negotiated 1");
+        }
+        other => panic!("unexpected replies: {:?}", other),
+    }
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn unknown_commands_are_named_in_the_error() {
+    let server = TestServer::start(memory_db()).await;
+    let mut stream = TcpStream::connect(&server.addr).await.unwrap();
+
+    write_frame(&mut stream, br#"{"Frobnicate":{}}"#).await.unwrap();
+    assert_eq!(read_error(&mut stream).await, "ERR unknown command: Frobnicate");
+    write_frame(&mut stream, br#""Frobnicate""#).await.unwrap();
+    assert_eq!(read_error(&mut stream).await, "ERR unknown command: Frobnicate");
+    // A known command with bad fields keeps serde's explanation
+    write_frame(&mut stream, br#"{"Get":{"name":"x"}}"#).await.unwrap();
+    assert!(read_error(&mut stream).await.starts_with("Invalid command: missing field `key`"));
+
+    // The connection is still usable afterwards
+    let get = serde_json::to_vec(&DatabaseCommand::Get { key: "name".into() }).unwrap();
+    write_frame(&mut stream, &get).await.unwrap();
+    let reply = read_frame(&mut stream).await.unwrap().expect("no reply");
+    assert!(matches!(serde_json::from_slice(&reply).unwrap(), DatabaseResponse::Ok { value: None }));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn second_server_on_the_same_storage_file_is_refused() {
+    let dir = TempDir::new();
+    let path = dir.file("db.json");
+    let first = TestServer::start(MiniDatabase::new(path.clone())).await;
+    first.client().await.set("name", "Ada").await.unwrap();
+
+    match MiniDatabase::new(path.clone()).bind("127.0.0.1:0").await {
+        Err(ServerError::Locked { pid, .. }) => assert_eq!(pid, std::process::id()),
+        Err(e) => panic!("expected Locked, got {}", e),
+        Ok(_) => panic!("a second server took the lock"),
+    }
+    // The refused server left the first one's lock in place
+    assert!(dir.path().join("db.json.lock").exists());
+
+    first.stop().await;
+    assert!(!dir.path().join("db.json.lock").exists());
+    let second = TestServer::start(MiniDatabase::new(path)).await;
+    assert_eq!(second.client().await.get("name").await.unwrap().as_deref(), Some("Ada"));
+    second.stop().await;
+}
+
+#[tokio::test]
+async fn a_burst_beyond_the_rate_limit_is_throttled() {
+    let server = TestServer::start(memory_db().with_rate_limit(10)).await;
+    let client = server.client().await;
+
+    let mut throttled = 0;
+    for i in 0..50 {
+        if let Err(e) = client.set("counter", &i.to_string()).await {
+            assert!(matches!(&e, ClientError::Server { message } if message == "ERR rate limited"), "{:?}", e);
+            throttled += 1;
+        }
+    }
+    // The bucket starts with a second's worth, less the HELLO sent on connect
+    assert!(throttled >= 35, "only {} of 50 commands were throttled", throttled);
+    assert!(throttled < 50, "every command was throttled");
+
+    // Other connections have buckets of their own
+    let other = server.client().await;
+    assert!(other.get("counter").await.unwrap().is_some());
+    server.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_key_written_over_tcp_is_read_over_the_unix_socket() {
+    use kv_store::ListenAddr;
+
+    let dir = TempDir::new();
+    let socket = dir.path().join("db.sock");
+    // A free port; the multi-address server does not report the ports it bound
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let tcp = format!("127.0.0.1:{}", port);
+    let addrs = [ListenAddr::Tcp(tcp.clone()), ListenAddr::Unix(socket.clone())];
+    let (shutdown, shutdown_rx) = oneshot::channel();
+    let serving = tokio::spawn(async move { memory_db().start_server_multi_with_shutdown(&addrs, shutdown_rx).await });
+
+    let connect = async {
+        loop {
+            match DatabaseClient::new(&tcp).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    };
+    let tcp_client = tokio::time::timeout(Duration::from_secs(5), connect).await.expect("the server never started");
+    tcp_client.set("name", "Ada").await.unwrap();
+    let unix_client = DatabaseClient::new_unix(&socket).await.unwrap();
+    assert_eq!(unix_client.get("name").await.unwrap().as_deref(), Some("Ada"));
+    unix_client.set("city", "London").await.unwrap();
+    assert_eq!(tcp_client.get("city").await.unwrap().as_deref(), Some("London"));
+
+    drop((tcp_client, unix_client));
+    shutdown.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+    assert!(!socket.exists(), "the socket file was left behind");
+}
+
+#[tokio::test]
+async fn string_range_commands_need_protocol_4() {
+    let server = TestServer::start(memory_db()).await;
+    let client = server.client().await;
+    assert_eq!(client.protocol_version(), 4);
+    client.set("greeting", "Hello").await.unwrap();
+    assert_eq!(client.get_range("greeting", 0, 1).await.unwrap().as_deref(), Some("He"));
+
+    assert_eq!(client.hello(3).await.unwrap(), 3);
+    let replies = client
+        .pipeline(vec![
+            DatabaseCommand::GetRange { key: "greeting".to_string(), start: 0, end: 1 },
+            DatabaseCommand::SetRange { key: "greeting".to_string(), offset: 0, value: "J".to_string() },
+            DatabaseCommand::StrLen { key: "greeting".to_string() },
+        ])
+        .await
+        .unwrap();
+    match &replies[..] {
+        [DatabaseResponse::Error { message: get }, DatabaseResponse::Error { message: set }, DatabaseResponse::Error { message: len }] => {
+            assert_eq!(get, "ERR GetRange needs protocol version 4, this connection negotiated 3");
+            assert_eq!(set, "ERR SetRange needs protocol version 4, this connection negotiated 3");
+            assert_eq!(len, "ERR StrLen needs protocol version 4, this connection negotiated 3");
+        }
+        other => panic!("unexpected replies: {:?}", other),
+    }
+    assert_eq!(client.get("greeting").await.unwrap().as_deref(), Some("Hello"));
+    server.stop().await;
+}